@@ -1,4 +1,4 @@
-use spacydo::{Return, Task, VM};
+use spacydo::{Return, SavePolicy, Task, VM};
 use std::env;
 
 const BITS: &str = "PUSH_STRING %BIT% PUSH_MAX_STATES 2 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
@@ -128,7 +128,11 @@ fn set_state(state: &str, id: &str) {
 
 fn show() {
     let bytecode = VM::dot2bin(SHOW).unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    // every gate ends with S_SAVE, they are written once when evaluation ends
+    let mut vm = VM::builder()
+        .save_policy(SavePolicy::EndOfRun)
+        .build(bytecode)
+        .unwrap();
     let stack = vm.run().unwrap();
     let vec_u32 = vm
         .unbox(&stack)
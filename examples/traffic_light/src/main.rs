@@ -60,13 +60,13 @@ fn display_color(state: u8) {
 fn main() {
     // in this example dot is replaced with native binary format
     let bytecode_create = vec![
-        0x1B, 0x00, 0x0D, 0x04, 0x01, 0x54, 0x52, 0x41, 0x46, 0x46, 0x49, 0x43, 0x5F, 0x4C, 0x49,
-        0x47, 0x48, 0x54, 0x06, 0x03, 0x04, 0x00, 0x52, 0x01, 0x00, 0x00, 0x00, 0x00, 0x05, 0x01,
-        0x08, 0x03, 0x00, 0x16, 0x1A, 0x00, 0x00, 0x00, 0x1B, 0x03, 0x01, 0x01, 0x00, 0x00, 0x00,
+        0x1B, 0x0D, 0x00, 0x04, 0x01, 0x54, 0x52, 0x41, 0x46, 0x46, 0x49, 0x43, 0x5F, 0x4C, 0x49,
+        0x47, 0x48, 0x54, 0x06, 0x03, 0x04, 0x52, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x05, 0x01,
+        0x08, 0x03, 0x00, 0x16, 0x1A, 0x1B, 0x00, 0x00, 0x00, 0x03, 0x01, 0x01, 0x00, 0x00, 0x00,
         0x00, 0x05, 0x01, 0x09, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x05, 0x01, 0x08, 0x03, 0x01,
-        0x16, 0x1A, 0x00, 0x00, 0x00, 0x36, 0x03, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x05, 0x01,
-        0x09, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x05, 0x01, 0x08, 0x03, 0x02, 0x16, 0x1A, 0x00,
-        0x00, 0x00, 0x51, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x05, 0x01, 0x09, 0x12, 0x12,
+        0x16, 0x1A, 0x36, 0x00, 0x00, 0x00, 0x03, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x05, 0x01,
+        0x09, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x05, 0x01, 0x08, 0x03, 0x02, 0x16, 0x1A, 0x51,
+        0x00, 0x00, 0x00, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x05, 0x01, 0x09, 0x12, 0x12,
         0x07, 0x0B,
     ];
 
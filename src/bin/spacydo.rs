@@ -0,0 +1,138 @@
+/*
+ * spacydo - storage maintenance tool
+ *
+ * spacydo fsck <path> [--repair]
+ *   checks storage file (and its journal), prints report.
+ *   --repair saves salvaged tasks in place, original file is kept as "<path>.bak"
+ * spacydo diff <from> <to>
+ *   prints tasks created, deleted and changed (field by field, instructions disassembled)
+ *   and globals changed between two storage files
+ * spacydo merge <base> <ours> <theirs>
+ *   applies changes of <theirs> since <base> to <ours> in place, original <ours> is kept as
+ *   "<ours>.bak" (with its journal). Fields changed differently by both sides keep ours
+ *   and are printed as conflicts
+ * exit code: 0 - store is clean or was repaired, stores are the same, merge has no conflicts,
+ * 1 - issues found, stores differ, merge has conflicts, 2 - usage or io error
+ */
+
+use spacydo::{FileBackend, StorageData, StorageDiff, StorageMerge, VM};
+use std::path::Path;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: spacydo fsck <path> [--repair]
+       spacydo diff <from> <to>
+       spacydo merge <base> <ours> <theirs>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["fsck", path] => fsck(path, false),
+        ["fsck", path, "--repair"] | ["fsck", "--repair", path] => fsck(path, true),
+        ["diff", from, to] => diff(from, to),
+        ["merge", base, ours, theirs] => merge(base, ours, theirs),
+        _ => {
+            eprintln!("{USAGE}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn fsck(path: &str, repair: bool) -> ExitCode {
+    let mut backend = FileBackend::new(path);
+    let report = match VM::fsck(&mut backend) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{path}: {e:?}");
+            return ExitCode::from(2);
+        }
+    };
+    print!("{path}: {report}");
+    if report.is_clean() {
+        return ExitCode::SUCCESS;
+    }
+    if !repair {
+        return ExitCode::from(1);
+    }
+    if !backup(path) {
+        return ExitCode::from(2);
+    }
+    match report.repair(&mut backend) {
+        Ok(()) => {
+            println!("{path}: repaired, {} tasks saved", report.tasks);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{path}: repair failed: {e:?}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn diff(from: &str, to: &str) -> ExitCode {
+    let (Some((_, from_data)), Some((_, to_data))) = (load(from), load(to)) else {
+        return ExitCode::from(2);
+    };
+    let diff = StorageDiff::between(&from_data, &to_data);
+    if diff.is_empty() {
+        return ExitCode::SUCCESS;
+    }
+    println!("--- {from}\n+++ {to}");
+    print!("{diff}");
+    ExitCode::from(1)
+}
+
+fn merge(base: &str, ours: &str, theirs: &str) -> ExitCode {
+    let (Some((_, base_data)), Some((mut backend, our_data)), Some((_, their_data))) =
+        (load(base), load(ours), load(theirs))
+    else {
+        return ExitCode::from(2);
+    };
+    let merge = StorageMerge::three_way(&base_data, &our_data, &their_data);
+    if merge.merged != our_data {
+        if !backup(ours) {
+            return ExitCode::from(2);
+        }
+        // same backend which loaded ours: store written meanwhile fails with StorageConflict
+        if let Err(e) = VM::save_store(&mut backend, &merge.merged) {
+            eprintln!("{ours}: merge failed: {e:?}");
+            return ExitCode::from(2);
+        }
+    }
+    print!("{}", StorageDiff::between(&our_data, &merge.merged));
+    for conflict in &merge.conflicts {
+        println!("conflict: {conflict}");
+    }
+    if merge.is_clean() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(1)
+    }
+}
+
+// store must exist, FileBackend would load missing file as empty store
+fn load(path: &str) -> Option<(FileBackend, StorageData)> {
+    if !Path::new(path).exists() {
+        eprintln!("{path}: no such file");
+        return None;
+    }
+    let mut backend = FileBackend::new(path);
+    let data = VM::load_store(&mut backend)
+        .inspect_err(|e| eprintln!("{path}: {e:?}"))
+        .ok()?;
+    Some((backend, data))
+}
+
+// copies file and its journal to "<path>.bak" ("<path>.bak.journal") before it is rewritten
+fn backup(path: &str) -> bool {
+    for suffix in ["", ".journal"] {
+        let (from, to) = (format!("{path}{suffix}"), format!("{path}.bak{suffix}"));
+        if Path::new(&from).exists()
+            && let Err(e) = std::fs::copy(&from, &to)
+        {
+            eprintln!("{to}: {e}");
+            return false;
+        }
+    }
+    true
+}
@@ -0,0 +1,105 @@
+use crate::errors::VMResult;
+use crate::storage::backend::{FileBackend, StorageBackend};
+use crate::storage::shared::SharedStorage;
+use crate::storage::storage::StorageSource;
+use crate::values::U25_MAX;
+use crate::vm::VM;
+use std::path::Path;
+
+// VM configuration, collected by VMBuilder
+// VM::init(bytecode) is the same as VM::builder().build(bytecode) with defaults
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct VMConfig {
+    // strict mode: task opcodes (T_GET_FIELD, T_SET_FIELD, T_DELETE, CALL) accept only TaskRef values
+    // and T_CREATE pushes TaskRef of the created task
+    pub(crate) strict: bool,
+    // linear memory cap in bytes, 25 bit offset space (32 MiB) is max
+    pub(crate) memory_limit: u32,
+    pub(crate) save_policy: SavePolicy,
+    // named store snapshots kept by backend, oldest are deleted beyond it. None -> all are kept
+    pub(crate) snapshot_retention: Option<usize>,
+}
+
+impl Default for VMConfig {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            memory_limit: U25_MAX,
+            save_policy: SavePolicy::Immediate,
+            snapshot_retention: None,
+        }
+    }
+}
+
+// when S_SAVE writes storage, only records changed since last write are written (see journal.rs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SavePolicy {
+    // every S_SAVE writes (after outermost commit inside transaction)
+    #[default]
+    Immediate,
+    // S_SAVEs of a run are coalesced into one write when run succeeds
+    EndOfRun,
+    // S_SAVE only marks storage to be saved, written by VM::save()
+    Manual,
+}
+
+#[derive(Debug, Default)]
+pub struct VMBuilder {
+    config: VMConfig,
+    // FileBackend ("tasks.bin") if not set
+    storage: Option<StorageSource>,
+}
+
+impl VMBuilder {
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.config.strict = strict;
+        self
+    }
+
+    // memory cap in bytes, values bigger than 2^25 are clamped to 2^25
+    pub fn memory_limit(mut self, bytes: u32) -> Self {
+        self.config.memory_limit = bytes;
+        self
+    }
+
+    pub fn save_policy(mut self, policy: SavePolicy) -> Self {
+        self.config.save_policy = policy;
+        self
+    }
+
+    // count of named store snapshots (S_SNAPSHOT, vm.snapshot_store) kept, oldest are deleted.
+    // 0 is raised to 1, the snapshot just taken is always kept
+    pub fn snapshot_retention(mut self, keep: usize) -> Self {
+        self.config.snapshot_retention = Some(keep.max(1));
+        self
+    }
+
+    // where S_SAVE writes and VM loads tasks from
+    pub fn storage<B: StorageBackend + 'static>(mut self, backend: B) -> Self {
+        self.storage = Some(StorageSource::Backend(Box::new(backend)));
+        self
+    }
+
+    // attaches vm to storage shared with other VMs, replaces storage backend (see shared.rs)
+    pub fn shared(mut self, shared: SharedStorage) -> Self {
+        self.storage = Some(StorageSource::Shared(shared));
+        self
+    }
+
+    pub fn build(self, instructions: Vec<u8>) -> VMResult<VM> {
+        let config = self.config;
+        VM::with_config(instructions, config, self.source())
+    }
+
+    // restores vm image, strict and memory limit are taken from the image,
+    // storage backend, save policy and snapshot retention from builder
+    pub fn restore<P: AsRef<Path>>(self, path: P) -> VMResult<VM> {
+        let config = self.config;
+        VM::restore_image(path, self.source(), config)
+    }
+
+    fn source(self) -> StorageSource {
+        self.storage
+            .unwrap_or_else(|| StorageSource::Backend(Box::new(FileBackend::default())))
+    }
+}
@@ -1,17 +1,21 @@
 use crate::errors::{VMError, VMResult};
-pub fn prepare_u32_from_be_checked(inst_slice: &[u8], pc: usize) -> VMResult<u32> {
-    Ok(u32::from_be_bytes(
-        inst_slice[pc..pc + 4]
-            .try_into()
-            .map_err(|_| VMError::UnexpectedEOB)?,
+
+// bytecode operands are little-endian, same as linear memory and storage
+pub fn prepare_u32_from_le_checked(inst_slice: &[u8], pc: usize) -> VMResult<u32> {
+    Ok(u32::from_le_bytes(
+        inst_slice
+            .get(pc..pc + 4)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(VMError::UnexpectedEOB)?,
     ))
 }
 
-pub fn prepare_u16_from_be_checked(inst_slice: &[u8], pc: usize) -> VMResult<u16> {
-    Ok(u16::from_be_bytes(
-        inst_slice[pc..pc + 2]
-            .try_into()
-            .map_err(|_| VMError::UnexpectedEOB)?,
+pub fn prepare_u16_from_le_checked(inst_slice: &[u8], pc: usize) -> VMResult<u16> {
+    Ok(u16::from_le_bytes(
+        inst_slice
+            .get(pc..pc + 2)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(VMError::UnexpectedEOB)?,
     ))
 }
 
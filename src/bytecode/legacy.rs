@@ -0,0 +1,107 @@
+/*
+ * legacy - instructions written before operands became little-endian
+ *
+ * baseline dot2bin wrote multi-byte operands big-endian: PUSH_U32, MULI and JUMP_IF_FALSE u32,
+ * PUSH_CALLDATA and M_STI u16 sizes. Storage files of that time (version 0, no header)
+ * hold such instructions, they are rewritten to little-endian when the file is migrated.
+ * Only opcodes of that instruction set (up to MUL) can occur, layout and length are unchanged,
+ * so jump destinations stay valid.
+ */
+use crate::bytecode::{helpers::*, opcodes::*};
+use crate::{VMError, VMResult};
+
+const W_PAYLOAD: u8 = 1;
+
+pub(crate) fn to_le(bytecode: &[u8]) -> VMResult<Vec<u8>> {
+    let mut le = bytecode.to_vec();
+    swap_operands(&mut le)?;
+    Ok(le)
+}
+
+fn swap_operands(bytecode: &mut [u8]) -> VMResult<()> {
+    let mut pc: usize = 0;
+    while pc < bytecode.len() {
+        let op = bytecode[pc];
+        pc += 1;
+        match op {
+            PUSH_U32 | MULI | JUMP_IF_FALSE => {
+                operand(bytecode, pc, 4)?.reverse();
+                pc += 4;
+            }
+            PUSH_STATE | PUSH_MAX_STATES | PUSH_TASK_FIELD | M_ST => {
+                prepare_u8(bytecode, pc)?;
+                pc += 1;
+            }
+            PUSH_CALLDATA => {
+                let size = be_size(bytecode, pc)?;
+                pc += 2;
+                let inner = bytecode
+                    .get_mut(pc..pc + size)
+                    .ok_or(VMError::UnexpectedEOB)?;
+                swap_operands(inner)?;
+                pc += size;
+            }
+            // [size][tag][sign byte][payload if sign byte is W_PAYLOAD]
+            M_STI => {
+                let size = be_size(bytecode, pc)?;
+                pc += 2;
+                prepare_u8(bytecode, pc)?;
+                let sign_byte = *prepare_u8(bytecode, pc + 1)?;
+                pc += 2;
+                if sign_byte == W_PAYLOAD {
+                    operand(bytecode, pc, size)?;
+                    pc += size;
+                }
+            }
+            T_CREATE | T_GET_FIELD | T_SET_FIELD | T_DELETE | S_SAVE | S_LOAD | S_LEN | DO
+            | LOOP | LOOP_INDEX | CALL | END_CALL | DROP | DUP | SWAP | EQ | NEQ | LT | GT
+            | M_MUTA | MUL => {}
+            _ => {
+                return Err(VMError::UnknownOpcode {
+                    opcode: format!("{op:#04x}"),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// reads big-endian u16 size and swaps it in place
+fn be_size(bytecode: &mut [u8], pc: usize) -> VMResult<usize> {
+    let size = operand(bytecode, pc, 2)?;
+    size.reverse();
+    Ok(u16::from_le_bytes([size[0], size[1]]) as usize)
+}
+
+fn operand(bytecode: &mut [u8], pc: usize, len: usize) -> VMResult<&mut [u8]> {
+    bytecode.get_mut(pc..pc + len).ok_or(VMError::UnexpectedEOB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_le() {
+        // baseline "PUSH_U32 258 IF PUSH_CALLDATA [ PUSH_U32 1 ] THEN PUSH_STRING hi"
+        let mut be = vec![PUSH_U32, 0, 0, 1, 2, JUMP_IF_FALSE, 0, 0, 0, 18];
+        be.extend_from_slice(&[PUSH_CALLDATA, 0, 5, PUSH_U32, 0, 0, 0, 1]);
+        be.extend_from_slice(&[M_STI, 0, 2, 6, W_PAYLOAD, b'h', b'i']);
+        let mut le = vec![PUSH_U32, 2, 1, 0, 0, JUMP_IF_FALSE, 18, 0, 0, 0];
+        le.extend_from_slice(&[PUSH_CALLDATA, 5, 0, PUSH_U32, 1, 0, 0, 0]);
+        le.extend_from_slice(&[M_STI, 2, 0, 6, W_PAYLOAD, b'h', b'i']);
+        assert_eq!(to_le(&be).unwrap(), le);
+        assert!(to_le(&[]).unwrap().is_empty());
+
+        assert!(matches!(
+            to_le(&[PUSH_CALLDATA, 0, 9, END_CALL]),
+            Err(VMError::UnexpectedEOB)
+        ));
+        assert!(matches!(to_le(&[MULI, 0, 0]), Err(VMError::UnexpectedEOB)));
+        // opcodes added after baseline can't occur in legacy instructions
+        assert!(matches!(
+            to_le(&[G_GET]),
+            Err(VMError::UnknownOpcode { .. })
+        ));
+    }
+}
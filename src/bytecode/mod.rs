@@ -1,2 +1,4 @@
 pub mod helpers;
+pub(crate) mod legacy;
 pub mod opcodes;
+pub(crate) mod verify;
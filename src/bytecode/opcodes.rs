@@ -1,6 +1,10 @@
 /*
 ## Instruction Set
 
+Multi-byte operands (u16 sizes, u32 values and jump destinations) are little-endian,
+same as linear memory and storage.
+example: PUSH_U32 1 -> [0x01, 0x01, 0x00, 0x00, 0x00]
+
 ### STACK
 
 PUSH_U32 <u32> - Push u32 value
@@ -10,6 +14,7 @@ example: PUSH_MAX_STATES 5 - means possible states are [0,1,2,3,4]
 PUSH_TASK_FIELD <u32> - Push task field value: 0 - title 1 -status 2- instructions
 PUSH_CALLDATA <String with format> - Push task own instructions. Should follow format : [ ] - for empty instructions,
 for non empty should end with END_CALL - PUSH_CALLDATA [ PUSH_U64 42 END_CALL ]
+binary: [size:16bits][calldata], PUSH_CALLDATA_W [size:32bits][calldata] is emitted by assembler for calldata bigger than 65_535 bytes
 DUP - duplicates last() value on stack
 SWAP - Exchange the top two stack items. [1,2] -> [2,1]
 
@@ -27,6 +32,14 @@ T_DELETE - pop task id -> delete task by id from storage
 comment: there is [task0,task1,task2..] - PUSH_U32 1 T_DELETE -> tasks_vm in storage are Vec<Option<TaskVM>> =>
 => [task0,none,task2] while persistant storage stores only [task0,task1] and next id
 
+T_REF - pop u32 -> push TaskRef with the same id (explicit cast, TaskRef is left as it is)
+example: PUSH_U32 0 T_REF T_DELETE
+
+Strict mode (VM::builder().strict(true)):
+task id popped by T_GET_FIELD, T_SET_FIELD, T_DELETE and CALL must be TaskRef, otherwise TypeMismatch.
+T_CREATE pushes TaskRef of the created task:
+example: PUSH_STRING TestTask PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE PUSH_TASK_FIELD 0 T_GET_FIELD
+
 ### LOGICAL
 EQ - pop right -> pop left -> push True if equal or False if not
 NEQ - pop right -> pop left -> push True if not equal or False if equal
@@ -35,9 +48,19 @@ GT - pop right -> pop left -> push True if left is greater that right
 DROP - pop from stack
 
 ### STORAGE
-S_SAVE - Save all tasks to disk
+S_SAVE - Save tasks and globals changed since last save to disk. With SavePolicy::EndOfRun S_SAVEs of a run are one write when run succeeds, with SavePolicy::Manual storage is written by vm.save()
 S_LEN - Push total task count to stack
 S_LOAD - (!ignore relic code) now verifies if task exist
+TX_BEGIN - opens storage transaction, transactions may be nested
+TX_COMMIT - closes innermost transaction, changes are kept. S_SAVE inside transaction is deferred until outermost commit
+TX_ROLLBACK - closes innermost transaction, tasks and globals changed inside it are restored
+Any error during run rolls back all open transactions, run ending with open transaction is rolled back with TransactionNotCommitted.
+example: TX_BEGIN PUSH_U32 0 T_DELETE PUSH_U32 1 T_DELETE S_SAVE TX_COMMIT (both tasks are deleted and saved or none)
+S_SNAPSHOT - pop name (string) -> save named snapshot of tasks and globals (unsaved changes included), snapshot of the same name is replaced.
+Oldest snapshots beyond retention (VM::builder().snapshot_retention(n)) are deleted. Names are 1-64 chars of [A-Za-z0-9_-]
+S_RESTORE - pop name (string) -> replace tasks and globals with named snapshot and save them as S_SAVE does, unsaved changes are dropped, SnapshotNotFound if there is none
+Inside transaction (run_atomic included) S_SNAPSHOT is written on outermost commit, both are undone by rollback.
+example: PUSH_STRING before-migration S_SNAPSHOT
 
 ### CONTROL
 DO-LOOP
@@ -50,6 +73,10 @@ LOOP_INDEX - push current loop index to stack (! rn is not safe for nested loops
 JUMP_IF_FALSE - forth like if..then - pop true or false value, if false, jump to jump destinnation (after then)
 
 CALL (executes tasks instructions) - pop task id -> Save the current pc into the current call frame -> pushes new instructions frame from task -> switches contexts to tasks instructions => vm.run is on new pc and matches different set of instructions (see vm.rs).
+YIELD - suspends run: vm.run() returns current stack, stacks, memory and pc are kept.
+Next vm.run() resumes after YIELD. Suspended vm can be saved with vm.snapshot(path) and continued after VM::restore(path).
+example: PUSH_U32 1 YIELD PUSH_U32 2 (first run returns [1], second [1, 2])
+
 END_CALL (returns to main context) - pop current frame from CALL_STACK (task instructions) -> switches context back to main call frame (see vmr.rs).
 example: PUSH_U32 0 DUP CALL (execute task 0 instructions)
 
@@ -57,11 +84,45 @@ example: PUSH_U32 0 DUP CALL (execute task 0 instructions)
 VM Memory is linear bytes array Vec<u8>, grows dynamically , but technically length is restricted by offset 25 bits -> max addressable offset is 2^25-1= 33_554_431
 each memory slice is represented by nan-boxed vec [offset:25 bits][size:16bits][tag:3]
 where *offset* is starting byte address in linear memory, *size* is number of bytes , *tag* - vector element type (u32,byte)
+slices bigger than 65_535 bytes (up to 25 bits) are represented by nan-boxed handle [handle:25 bits][tag:3] into memory heap table
 M_STI - Memory Store Immediate (following bytes afte opcode: [size:16bits][TAG:8bits][SIGN:8bits][PAYLOAD])- allocates bytes to memory and returns nan-boxed vec_val on stack [offset:25 bits][size:16bits][tag:3]
+M_STI_W - same as M_STI with wide size [size:32bits], emitted by assembler for slices bigger than 65_535 bytes
 M_ST - Memory Store (followed only by : [tag:8bits])- allocates empty vec to memory and returns nan-boxed vec_val on stack [offset:25 bits][size:16bits][tag:3]
 M_MUT -Memory Mutate At - mutates memory at address in existing memory slice - takes 3 parameters:  vec, index, value  -> pop value, pop index, peek vec -> writes value at index to memory slice.
 Important, vec[offset:25 bits][size:16bits][tag:3] remains on stack!
 
+### MAP
+Map is an associative array allocated in linear memory, keys are u32 or String, values are u32 or String.
+Map value is a reference: DUP'ed map values observe all MAP_SET changes.
+MAP_NEW - push new empty map
+MAP_SET - pop value -> pop key -> peek map -> inserts or replaces value by key. Map remains on stack (same as M_MUTA)
+example: MAP_NEW PUSH_U32 0 PUSH_STRING red MAP_SET PUSH_U32 1 PUSH_STRING green MAP_SET
+MAP_GET - pop key -> pop map -> push value by key, MapKeyNotFound if there is no such key
+example: DUP PUSH_U32 1 MAP_GET (map is kept by DUP)
+MAP_HAS - pop key -> pop map -> push True if map contains key or False if not
+MAP_KEYS - pop map -> push all keys in insertion order -> push amount of keys [map] -> [k0 k1 .. kn n]
+
+### LIST
+List is a growable array allocated in linear memory, items are any values: u32, bool, strings, vectors, maps, nested lists.
+List value is a reference: DUP'ed list values observe all LIST_PUSH/LIST_SET changes.
+LIST_NEW - push new empty list
+LIST_PUSH - pop value -> peek list -> appends value. List remains on stack
+example: LIST_NEW PUSH_U32 0 LIST_PUSH PUSH_STRING title LIST_PUSH
+LIST_GET - pop index -> pop list -> push item at index
+LIST_SET - pop value -> pop index -> peek list -> replaces item at index. List remains on stack (same as M_MUTA)
+LIST_LEN - pop list -> push amount of items
+example (list of [id, title, state] lists for every task):
+LIST_NEW S_LEN PUSH_U32 0 DO LIST_NEW LOOP_INDEX LIST_PUSH LOOP_INDEX PUSH_TASK_FIELD 0 T_GET_FIELD LIST_PUSH
+LOOP_INDEX PUSH_TASK_FIELD 1 T_GET_FIELD LIST_PUSH LIST_PUSH LOOP
+
+### GLOBALS
+Global slots (0..1024) live in storage next to tasks and are persisted by S_SAVE.
+Values are u32, bool, TaskRef, strings and u32 vectors (copied to/from linear memory), unset slot is Null.
+G_SET - pop global index -> pop value -> sets global slot (value comes first, same as T_SET_FIELD)
+example: PUSH_U32 42 PUSH_U32 0 G_SET S_SAVE
+G_GET - pop global index -> push global value
+example: PUSH_U32 0 G_GET MULI 2 PUSH_U32 0 G_SET (doubles global 0)
+
 ### ARITHMETIC
 MUL - multiplication - pops 2 values from stack, multiplies them and pushes result to stack [2,3, MUL] -> [6]
 MULI - multiplication immediate value bytes following opcode - pops 1 value multiplicates to specified value and pushes result to stack [2, MULI 3] -> [6]
@@ -108,3 +169,32 @@ pub const M_MUTA: u8 = 0x1d;
 //
 pub const MULI: u8 = 0x1e;
 pub const MUL: u8 = 0x1f;
+//
+pub const T_REF: u8 = 0x20;
+//
+pub const MAP_NEW: u8 = 0x21;
+pub const MAP_SET: u8 = 0x22;
+pub const MAP_GET: u8 = 0x23;
+pub const MAP_HAS: u8 = 0x24;
+pub const MAP_KEYS: u8 = 0x25;
+//
+pub const LIST_NEW: u8 = 0x26;
+pub const LIST_PUSH: u8 = 0x27;
+pub const LIST_GET: u8 = 0x28;
+pub const LIST_SET: u8 = 0x29;
+pub const LIST_LEN: u8 = 0x2a;
+// wide variants (u32 size) of M_STI and PUSH_CALLDATA
+pub const M_STI_W: u8 = 0x2b;
+pub const PUSH_CALLDATA_W: u8 = 0x2c;
+//
+pub const G_GET: u8 = 0x2d;
+pub const G_SET: u8 = 0x2e;
+//
+pub const YIELD: u8 = 0x2f;
+//
+pub const TX_BEGIN: u8 = 0x30;
+pub const TX_COMMIT: u8 = 0x31;
+pub const TX_ROLLBACK: u8 = 0x32;
+
+pub const S_SNAPSHOT: u8 = 0x33;
+pub const S_RESTORE: u8 = 0x34;
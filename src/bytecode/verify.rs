@@ -0,0 +1,136 @@
+/*
+ * verify - static check of instructions without running them
+ *
+ * every opcode is known, operands and immediate payloads are within instructions,
+ * jump destinations are inside them, calldata is verified as instructions of its own.
+ * VM trusts stored instructions (operands are indexed directly), so storage check (fsck) rejects
+ * instructions failing verification before they are called.
+ */
+use crate::bytecode::{helpers::*, opcodes::*};
+use crate::{VMError, VMResult};
+
+const W_PAYLOAD: u8 = 1;
+
+// returns ids of tasks referenced by constants (PUSH_U32 <id> CALL, PUSH_U32 <id> T_REF)
+pub(crate) fn verify(bytecode: &[u8]) -> VMResult<Vec<u32>> {
+    let mut refs = Vec::new();
+    verify_into(bytecode, &mut refs)?;
+    Ok(refs)
+}
+
+fn verify_into(bytecode: &[u8], refs: &mut Vec<u32>) -> VMResult<()> {
+    let mut pc: usize = 0;
+    // value of PUSH_U32 right before current opcode
+    let mut constant: Option<u32> = None;
+    while pc < bytecode.len() {
+        let op = bytecode[pc];
+        pc += 1;
+        let pushed = constant.take();
+
+        match op {
+            PUSH_U32 => {
+                constant = Some(prepare_u32_from_le_checked(bytecode, pc)?);
+                pc += 4;
+            }
+            MULI => {
+                prepare_u32_from_le_checked(bytecode, pc)?;
+                pc += 4;
+            }
+            PUSH_STATE | PUSH_MAX_STATES | PUSH_TASK_FIELD | M_ST => {
+                prepare_u8(bytecode, pc)?;
+                pc += 1;
+            }
+            JUMP_IF_FALSE => {
+                let dest = prepare_u32_from_le_checked(bytecode, pc)? as usize;
+                if dest > bytecode.len() {
+                    return Err(VMError::MalformedIfThen {
+                        context: "jump destination out of instructions",
+                    });
+                }
+                pc += 4;
+            }
+            PUSH_CALLDATA | PUSH_CALLDATA_W => {
+                let size = wide_size(bytecode, &mut pc, op == PUSH_CALLDATA_W)?;
+                let inner = bytecode.get(pc..pc + size).ok_or(VMError::UnexpectedEOB)?;
+                verify_into(inner, refs)?;
+                pc += size;
+            }
+            // [size][tag][sign byte][payload if sign byte is W_PAYLOAD]
+            M_STI | M_STI_W => {
+                let size = wide_size(bytecode, &mut pc, op == M_STI_W)?;
+                prepare_u8(bytecode, pc)?;
+                let sign_byte = *prepare_u8(bytecode, pc + 1)?;
+                pc += 2;
+                if sign_byte == W_PAYLOAD {
+                    bytecode.get(pc..pc + size).ok_or(VMError::UnexpectedEOB)?;
+                    pc += size;
+                }
+            }
+            CALL | T_REF => refs.extend(pushed),
+            T_CREATE | T_GET_FIELD | T_SET_FIELD | T_DELETE | S_SAVE | S_LOAD | S_LEN | DO
+            | LOOP | LOOP_INDEX | END_CALL | DROP | DUP | SWAP | EQ | NEQ | LT | GT | MUL
+            | M_MUTA | MAP_NEW | MAP_SET | MAP_GET | MAP_HAS | MAP_KEYS | LIST_NEW | LIST_PUSH
+            | LIST_GET | LIST_SET | LIST_LEN | G_GET | G_SET | YIELD | TX_BEGIN | TX_COMMIT
+            | TX_ROLLBACK | S_SNAPSHOT | S_RESTORE => {}
+            _ => {
+                return Err(VMError::UnknownOpcode {
+                    opcode: format!("{op:#04x}"),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// u16 size operand, u32 for wide variant
+fn wide_size(bytecode: &[u8], pc: &mut usize, wide: bool) -> VMResult<usize> {
+    if wide {
+        let size = prepare_u32_from_le_checked(bytecode, *pc)? as usize;
+        *pc += 4;
+        Ok(size)
+    } else {
+        let size = prepare_u16_from_le_checked(bytecode, *pc)? as usize;
+        *pc += 2;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        // PUSH_U32 3 CALL PUSH_CALLDATA [ PUSH_U32 4 T_REF ]
+        let mut bytecode = vec![PUSH_U32, 3, 0, 0, 0, CALL, PUSH_CALLDATA, 6, 0];
+        bytecode.extend_from_slice(&[PUSH_U32, 4, 0, 0, 0, T_REF]);
+        assert_eq!(verify(&bytecode).unwrap(), [3, 4]);
+        // constant not followed by CALL is not a reference
+        assert!(
+            verify(&[PUSH_U32, 3, 0, 0, 0, DROP, CALL])
+                .unwrap()
+                .is_empty()
+        );
+
+        assert!(matches!(
+            verify(&[PUSH_U32, 3, 0]),
+            Err(VMError::UnexpectedEOB)
+        ));
+        assert!(matches!(
+            verify(&[PUSH_CALLDATA, 9, 0, END_CALL]),
+            Err(VMError::UnexpectedEOB)
+        ));
+        assert!(matches!(
+            verify(&[M_STI, 4, 0, 4, W_PAYLOAD, b'a']),
+            Err(VMError::UnexpectedEOB)
+        ));
+        assert!(matches!(
+            verify(&[JUMP_IF_FALSE, 9, 0, 0, 0]),
+            Err(VMError::MalformedIfThen { .. })
+        ));
+        assert!(matches!(
+            verify(&[PUSH_CALLDATA, 1, 0, 0xff]),
+            Err(VMError::UnknownOpcode { .. })
+        ));
+    }
+}
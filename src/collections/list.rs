@@ -0,0 +1,64 @@
+// List allocated in vm's linear memory
+//
+// list value is a fat pointer to a header (see collections/mod.rs): [offset:25 bits][size:16bits][tag:3] with tag LIST
+// item (8 bytes): [Value]
+//
+// unlike vectors (homogeneous u8 or u32 elements), list items are full nan-boxed values:
+// u32, bool, strings, vectors, maps and nested lists.
+
+use crate::collections::HEADER_SIZE;
+use crate::errors::{VMError, VMResult};
+use crate::memory::LinearMemory;
+use crate::values::*;
+
+pub(crate) const ITEM_SIZE: u32 = 8;
+
+impl LinearMemory {
+    pub(crate) fn list_new(&mut self) -> VMResult<Value> {
+        let header = self.header_new(ITEM_SIZE)?;
+        to_list_val(header, HEADER_SIZE)
+    }
+
+    fn list_header(&self, list: Value) -> VMResult<u32> {
+        if !is_list(list) {
+            return Err(VMError::InvalidType);
+        }
+        Ok(to_fat_pointer(list)?.0)
+    }
+
+    // returns offset of the item at index
+    fn list_item(&self, list: Value, index: u32) -> VMResult<u32> {
+        let (items, len, _) = self.header_read(self.list_header(list)?);
+        if index >= len {
+            return Err(VMError::MSliceOutOfBounds { index, size: len });
+        }
+        Ok(items + index * ITEM_SIZE)
+    }
+
+    pub(crate) fn list_push(&mut self, list: Value, value: Value) -> VMResult<()> {
+        let item = self.header_push_slot(self.list_header(list)?, ITEM_SIZE)?;
+        self.write_value(item, value);
+        Ok(())
+    }
+
+    pub(crate) fn list_get(&self, list: Value, index: u32) -> VMResult<Value> {
+        Ok(self.read_value(self.list_item(list, index)?))
+    }
+
+    pub(crate) fn list_set(&mut self, list: Value, index: u32, value: Value) -> VMResult<()> {
+        let item = self.list_item(list, index)?;
+        self.write_value(item, value);
+        Ok(())
+    }
+
+    pub(crate) fn list_len(&self, list: Value) -> VMResult<u32> {
+        Ok(self.header_read(self.list_header(list)?).1)
+    }
+
+    pub(crate) fn list_items(&self, list: Value) -> VMResult<Vec<Value>> {
+        let (items, len, _) = self.header_read(self.list_header(list)?);
+        Ok((0..len)
+            .map(|i| self.read_value(items + i * ITEM_SIZE))
+            .collect())
+    }
+}
@@ -0,0 +1,95 @@
+// Map (associative array) allocated in vm's linear memory
+//
+// map value is a fat pointer to a header (see collections/mod.rs): [offset:25 bits][size:16bits][tag:3] with tag MAP
+// entry (16 bytes): [key: Value][value: Value]
+//
+// keys are u32 or strings, values are u32 or strings. Lookup is linear - maps are meant for small lookup tables.
+
+use crate::collections::HEADER_SIZE;
+use crate::errors::{VMError, VMResult};
+use crate::memory::LinearMemory;
+use crate::values::*;
+
+pub(crate) const ENTRY_SIZE: u32 = 16;
+
+#[inline]
+fn is_map_key(v: Value) -> bool {
+    is_u32_val(v) || is_string_vec(v)
+}
+
+#[inline]
+fn is_map_value(v: Value) -> bool {
+    is_u32_val(v) || is_string_vec(v)
+}
+
+impl LinearMemory {
+    pub(crate) fn map_new(&mut self) -> VMResult<Value> {
+        let header = self.header_new(ENTRY_SIZE)?;
+        to_map_val(header, HEADER_SIZE)
+    }
+
+    fn map_header(&self, map: Value) -> VMResult<u32> {
+        if !is_map(map) {
+            return Err(VMError::InvalidType);
+        }
+        Ok(to_fat_pointer(map)?.0)
+    }
+
+    fn map_key_eq(&self, left: Value, right: Value) -> VMResult<bool> {
+        if is_u32_val(left) && is_u32_val(right) {
+            return Ok(to_u32(left) == to_u32(right));
+        }
+        if is_string_vec(left) && is_string_vec(right) {
+            return Ok(
+                self.is_m_slice_eq(self.fat_pointer(left)?, self.fat_pointer(right)?) == TRUE_VAL,
+            );
+        }
+        Ok(false)
+    }
+
+    // returns offset of the entry with key
+    fn map_find(&self, map: Value, key: Value) -> VMResult<Option<u32>> {
+        if !is_map_key(key) {
+            return Err(VMError::InvalidType);
+        }
+        let (entries, len, _) = self.header_read(self.map_header(map)?);
+        for i in 0..len {
+            let entry = entries + i * ENTRY_SIZE;
+            if self.map_key_eq(self.read_value(entry), key)? {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn map_get(&self, map: Value, key: Value) -> VMResult<Option<Value>> {
+        Ok(self
+            .map_find(map, key)?
+            .map(|entry| self.read_value(entry + 8)))
+    }
+
+    pub(crate) fn map_set(&mut self, map: Value, key: Value, value: Value) -> VMResult<()> {
+        if !is_map_value(value) {
+            return Err(VMError::InvalidType);
+        }
+        if let Some(entry) = self.map_find(map, key)? {
+            self.write_value(entry + 8, value);
+            return Ok(());
+        }
+        let entry = self.header_push_slot(self.map_header(map)?, ENTRY_SIZE)?;
+        self.write_value(entry, key);
+        self.write_value(entry + 8, value);
+        Ok(())
+    }
+
+    // entries in insertion order
+    pub(crate) fn map_entries(&self, map: Value) -> VMResult<Vec<(Value, Value)>> {
+        let (entries, len, _) = self.header_read(self.map_header(map)?);
+        Ok((0..len)
+            .map(|i| {
+                let entry = entries + i * ENTRY_SIZE;
+                (self.read_value(entry), self.read_value(entry + 8))
+            })
+            .collect())
+    }
+}
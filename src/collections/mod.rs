@@ -0,0 +1,54 @@
+/*
+ * Vm owned structures allocated in linear memory
+ *
+ * map and list share growable header:
+ * header (12 bytes): [items offset: u32][len: u32][capacity: u32]
+ * header never moves, items are reallocated on growth and header is updated in place,
+ * so all copies of the value (ie after DUP) observe changes.
+ */
+
+pub mod list;
+pub mod map;
+
+use crate::errors::VMResult;
+use crate::memory::LinearMemory;
+
+pub(crate) const HEADER_SIZE: u16 = 12;
+const INITIAL_CAPACITY: u32 = 4;
+const ALIGN: u32 = 4;
+
+impl LinearMemory {
+    // allocates header with initial capacity, returns header offset
+    pub(crate) fn header_new(&mut self, item_size: u32) -> VMResult<u32> {
+        let header = self.alloc_raw(HEADER_SIZE as u32, ALIGN)?;
+        let items = self.alloc_raw(INITIAL_CAPACITY * item_size, ALIGN)?;
+        self.write_u32(header, items);
+        self.write_u32(header + 4, 0);
+        self.write_u32(header + 8, INITIAL_CAPACITY);
+        Ok(header)
+    }
+
+    // returns (items offset, len, capacity)
+    pub(crate) fn header_read(&self, header: u32) -> (u32, u32, u32) {
+        (
+            self.read_u32(header),
+            self.read_u32(header + 4),
+            self.read_u32(header + 8),
+        )
+    }
+
+    // appends empty slot (grows items if needed), returns offset of the slot
+    pub(crate) fn header_push_slot(&mut self, header: u32, item_size: u32) -> VMResult<u32> {
+        let (mut items, len, mut capacity) = self.header_read(header);
+        if len == capacity {
+            capacity *= 2;
+            let grown = self.alloc_raw(capacity * item_size, ALIGN)?;
+            self.copy_within(items, len * item_size, grown);
+            items = grown;
+            self.write_u32(header, items);
+            self.write_u32(header + 8, capacity);
+        }
+        self.write_u32(header + 4, len + 1);
+        Ok(items + len * item_size)
+    }
+}
@@ -24,7 +24,7 @@ pub fn bin2dot(bytecode: &[u8]) -> VMResult<String> {
         match op {
             PUSH_U32 => {
                 result.push_str("PUSH_U32 ");
-                let v = prepare_u32_from_be_checked(bytecode, pc)?; // change?
+                let v = prepare_u32_from_le_checked(bytecode, pc)?; // change?
                 write!(&mut result, "{} ", v).map_err(|_| VMError::WriteError)?;
                 pc += 4;
             }
@@ -54,21 +54,29 @@ pub fn bin2dot(bytecode: &[u8]) -> VMResult<String> {
 
             JUMP_IF_FALSE => {
                 result.push_str("IF ");
-                jump_dest_stack.push(prepare_u32_from_be_checked(bytecode, pc)?)?;
+                jump_dest_stack.push(prepare_u32_from_le_checked(bytecode, pc)?)?;
                 pc += 4;
             }
 
-            PUSH_CALLDATA => {
+            PUSH_CALLDATA | PUSH_CALLDATA_W => {
                 result.push_str("PUSH_CALLDATA [ ");
-                let size = prepare_u16_from_be_checked(bytecode, pc)? as usize;
-                pc += 2;
-                //check logic
-                let inner = &bytecode[pc..pc + size];
+                let size = if op == PUSH_CALLDATA_W {
+                    let size = prepare_u32_from_le_checked(bytecode, pc)? as usize;
+                    pc += 4;
+                    size
+                } else {
+                    let size = prepare_u16_from_le_checked(bytecode, pc)? as usize;
+                    pc += 2;
+                    size
+                };
+                let inner = bytecode.get(pc..pc + size).ok_or(VMError::UnexpectedEOB)?;
+                pc += size;
 
                 if !inner.is_empty() {
                     result.push_str(&bin2dot(inner)?);
+                    result.push(' ');
                 }
-                result.push_str(" ] ");
+                result.push_str("] ");
             }
             //[opcode][tag byte]
             M_ST => {
@@ -76,11 +84,18 @@ pub fn bin2dot(bytecode: &[u8]) -> VMResult<String> {
                 // we need skip tag byte
                 pc += 1;
             }
-            //[opcode][size: 2 bytes][tag: 1 byte][sign_byte: 1 byte][?payload]
+            //[opcode][size: 2 bytes (4 bytes for M_STI_W)][tag: 1 byte][sign_byte: 1 byte][?payload]
             // rn it is either PUSH_STRING or NEW_VEC_U32_I
-            M_STI => {
-                let size = prepare_u16_from_be_checked(bytecode, pc)? as usize;
-                pc += 2;
+            M_STI | M_STI_W => {
+                let size = if op == M_STI_W {
+                    let size = prepare_u32_from_le_checked(bytecode, pc)? as usize;
+                    pc += 4;
+                    size
+                } else {
+                    let size = prepare_u16_from_le_checked(bytecode, pc)? as usize;
+                    pc += 2;
+                    size
+                };
                 let tag = prepare_u8(bytecode, pc)?;
                 pc += 1;
                 let sign_byte = prepare_u8(bytecode, pc)?;
@@ -99,7 +114,7 @@ pub fn bin2dot(bytecode: &[u8]) -> VMResult<String> {
 
             MULI => {
                 result.push_str("MULI ");
-                let v = prepare_u32_from_be_checked(bytecode, pc)?;
+                let v = prepare_u32_from_le_checked(bytecode, pc)?;
                 write!(&mut result, "{} ", v).map_err(|_| VMError::WriteError)?;
                 pc += 4;
             }
@@ -108,6 +123,7 @@ pub fn bin2dot(bytecode: &[u8]) -> VMResult<String> {
             T_GET_FIELD => result.push_str("T_GET_FIELD "),
             T_SET_FIELD => result.push_str("T_SET_FIELD "),
             T_DELETE => result.push_str("T_DELETE "),
+            T_REF => result.push_str("T_REF "),
             S_SAVE => result.push_str("S_SAVE "),
             S_LOAD => result.push_str("S_LOAD "),
             S_LEN => result.push_str("S_LEN "),
@@ -125,6 +141,24 @@ pub fn bin2dot(bytecode: &[u8]) -> VMResult<String> {
             GT => result.push_str("GT "),
             MUL => result.push_str("MUL "),
             M_MUTA => result.push_str("M_MUTA "),
+            MAP_NEW => result.push_str("MAP_NEW "),
+            MAP_SET => result.push_str("MAP_SET "),
+            MAP_GET => result.push_str("MAP_GET "),
+            MAP_HAS => result.push_str("MAP_HAS "),
+            MAP_KEYS => result.push_str("MAP_KEYS "),
+            LIST_NEW => result.push_str("LIST_NEW "),
+            LIST_PUSH => result.push_str("LIST_PUSH "),
+            LIST_GET => result.push_str("LIST_GET "),
+            LIST_SET => result.push_str("LIST_SET "),
+            LIST_LEN => result.push_str("LIST_LEN "),
+            G_GET => result.push_str("G_GET "),
+            G_SET => result.push_str("G_SET "),
+            YIELD => result.push_str("YIELD "),
+            TX_BEGIN => result.push_str("TX_BEGIN "),
+            TX_COMMIT => result.push_str("TX_COMMIT "),
+            TX_ROLLBACK => result.push_str("TX_ROLLBACK "),
+            S_SNAPSHOT => result.push_str("S_SNAPSHOT "),
+            S_RESTORE => result.push_str("S_RESTORE "),
 
             _ => {}
         }
@@ -5,6 +5,7 @@
 use crate::bytecode::opcodes::*;
 use crate::errors::{VMError, VMResult};
 use crate::inlinevec::InlineVec;
+use crate::values::U25_MAX;
 
 fn next_token<'a>(
     it: &mut std::iter::Enumerate<impl Iterator<Item = &'a str>>,
@@ -27,6 +28,30 @@ const TAG_U32: u8 = 6;
 const WO_PAYLOAD: u8 = 0;
 const W_PAYLOAD: u8 = 1;
 
+// pushes opcode with size operand:
+// sizes up to u16::MAX keep short form [op][size:16bits], bigger ones (up to 25 bits) use wide form [wide_op][size:32bits]
+fn push_sized_op(
+    bytecode: &mut Vec<u8>,
+    op: u8,
+    wide_op: u8,
+    size: usize,
+    context: &'static str,
+) -> VMResult<()> {
+    if let Ok(size) = u16::try_from(size) {
+        bytecode.push(op);
+        bytecode.extend_from_slice(&size.to_le_bytes());
+    } else if size < U25_MAX as usize {
+        bytecode.push(wide_op);
+        bytecode.extend_from_slice(&(size as u32).to_le_bytes());
+    } else {
+        return Err(VMError::InstructionSizeError {
+            context,
+            max: U25_MAX - 1,
+        });
+    }
+    Ok(())
+}
+
 pub fn dot2bin(src: &str) -> VMResult<Vec<u8>> {
     let mut tokens = src.split_whitespace().enumerate();
     let mut bytecode: Vec<u8> = Vec::new();
@@ -41,24 +66,24 @@ pub fn dot2bin(src: &str) -> VMResult<Vec<u8>> {
                     command: pos,
                     value: text.into(),
                 })?;
-                bytecode.extend_from_slice(&value.to_be_bytes());
+                bytecode.extend_from_slice(&value.to_le_bytes());
             }
 
             // dot is not aware of memory, so it requests alloc, not specifying offset (at least for now)
             // [size:16bits][TAG:8bits][SIGN:8bits][PAYLOAD]
-            // it will restrinct size of string to 65535 bytes
+            // strings bigger than 65535 bytes use M_STI_W with [size:32bits]
             // for "hello"  [00 05] [06] [01] [68 65 6C 6C 6F]
             "PUSH_STRING" => {
-                bytecode.push(M_STI);
                 let (_pos, text) = next_token(&mut tokens, i, "missing String")?;
                 let text_bytes = text.as_bytes();
                 //len of bytes bytes
-                let size =
-                    u16::try_from(text_bytes.len()).map_err(|_| VMError::InstructionSizeError {
-                        context: "String size exceeded limit",
-                        max: u16::MAX as u32,
-                    })?;
-                bytecode.extend_from_slice(&size.to_be_bytes());
+                push_sized_op(
+                    &mut bytecode,
+                    M_STI,
+                    M_STI_W,
+                    text_bytes.len(),
+                    "String size exceeded limit",
+                )?;
                 bytecode.push(TAG_STRING);
                 //signaling byte 1 == with payload
                 bytecode.push(W_PAYLOAD);
@@ -69,14 +94,19 @@ pub fn dot2bin(src: &str) -> VMResult<Vec<u8>> {
             // to avoid confusion size should be in bytes
             //
             "NEW_VEC_U32_I" => {
-                bytecode.push(M_STI);
                 let (pos, text) = next_token(&mut tokens, i, "missing size")?;
                 //size should be in bytes! so vm work with bytes sizes
-                let size = text.parse::<u16>().map_err(|_| VMError::InvalidUINT {
+                let size = text.parse::<u32>().map_err(|_| VMError::InvalidUINT {
                     command: pos,
                     value: text.into(),
                 })?;
-                bytecode.extend_from_slice(&size.to_be_bytes());
+                push_sized_op(
+                    &mut bytecode,
+                    M_STI,
+                    M_STI_W,
+                    size as usize,
+                    "Vector size exceeded limit",
+                )?;
                 bytecode.push(TAG_U32);
                 //signaling byte 0 == without payload
                 bytecode.push(WO_PAYLOAD);
@@ -125,24 +155,22 @@ pub fn dot2bin(src: &str) -> VMResult<Vec<u8>> {
                     command: pos,
                     value: text.into(),
                 })?;
-                bytecode.extend_from_slice(&value.to_be_bytes());
+                bytecode.extend_from_slice(&value.to_le_bytes());
             }
 
             "IF" => {
                 bytecode.push(JUMP_IF_FALSE);
                 jump_dest_stack.push(bytecode.len() as u32)?;
-                bytecode.extend_from_slice(&0u32.to_be_bytes());
+                bytecode.extend_from_slice(&0u32.to_le_bytes());
             }
             "THEN" => {
                 let jump_dest = jump_dest_stack.pop()?;
                 let upd_dest = bytecode.len() as u32;
                 bytecode[jump_dest as usize..jump_dest as usize + 4]
-                    .copy_from_slice(&upd_dest.to_be_bytes());
+                    .copy_from_slice(&upd_dest.to_le_bytes());
             }
 
             "PUSH_CALLDATA" => {
-                bytecode.push(PUSH_CALLDATA);
-
                 let (_bracket_pos, bracket) = next_token(&mut tokens, i, "empty calldata")?;
 
                 if bracket != "[" {
@@ -178,15 +206,14 @@ pub fn dot2bin(src: &str) -> VMResult<Vec<u8>> {
                     dot2bin(&inner_instructions)?
                 };
 
-                // u16 - up to 65_535 . still a lot but better than u32
-                let calldata_bytecode_len =
-                    u16::try_from(calldata_bytecode.len()).map_err(|_| {
-                        VMError::InstructionSizeError {
-                            context: "Calldata size exceeded limit",
-                            max: u16::MAX as u32,
-                        }
-                    })?;
-                bytecode.extend_from_slice(&calldata_bytecode_len.to_be_bytes());
+                // u16 - up to 65_535 , bigger calldata uses PUSH_CALLDATA_W with u32 size
+                push_sized_op(
+                    &mut bytecode,
+                    PUSH_CALLDATA,
+                    PUSH_CALLDATA_W,
+                    calldata_bytecode.len(),
+                    "Calldata size exceeded limit",
+                )?;
                 bytecode.extend_from_slice(calldata_bytecode.as_slice());
             }
 
@@ -202,6 +229,9 @@ pub fn dot2bin(src: &str) -> VMResult<Vec<u8>> {
             "T_DELETE" => {
                 bytecode.push(T_DELETE);
             }
+            "T_REF" => {
+                bytecode.push(T_REF);
+            }
             "S_SAVE" => {
                 bytecode.push(S_SAVE);
             }
@@ -253,9 +283,66 @@ pub fn dot2bin(src: &str) -> VMResult<Vec<u8>> {
             "M_STI" => {
                 bytecode.push(M_STI);
             }
+            "M_STI_W" => {
+                bytecode.push(M_STI_W);
+            }
             "M_ST" => {
                 bytecode.push(M_ST);
             }
+            "MAP_NEW" => {
+                bytecode.push(MAP_NEW);
+            }
+            "MAP_SET" => {
+                bytecode.push(MAP_SET);
+            }
+            "MAP_GET" => {
+                bytecode.push(MAP_GET);
+            }
+            "MAP_HAS" => {
+                bytecode.push(MAP_HAS);
+            }
+            "MAP_KEYS" => {
+                bytecode.push(MAP_KEYS);
+            }
+            "LIST_NEW" => {
+                bytecode.push(LIST_NEW);
+            }
+            "LIST_PUSH" => {
+                bytecode.push(LIST_PUSH);
+            }
+            "LIST_GET" => {
+                bytecode.push(LIST_GET);
+            }
+            "LIST_SET" => {
+                bytecode.push(LIST_SET);
+            }
+            "LIST_LEN" => {
+                bytecode.push(LIST_LEN);
+            }
+            "G_GET" => {
+                bytecode.push(G_GET);
+            }
+            "G_SET" => {
+                bytecode.push(G_SET);
+            }
+            "YIELD" => {
+                bytecode.push(YIELD);
+            }
+            "TX_BEGIN" => {
+                bytecode.push(TX_BEGIN);
+            }
+            "TX_COMMIT" => {
+                bytecode.push(TX_COMMIT);
+            }
+            "TX_ROLLBACK" => {
+                bytecode.push(TX_ROLLBACK);
+            }
+            "S_SNAPSHOT" => {
+                bytecode.push(S_SNAPSHOT);
+            }
+            "S_RESTORE" => {
+                bytecode.push(S_RESTORE);
+            }
             _ => {
                 return Err(VMError::UnknownOpcode {
                     opcode: token.to_string(),
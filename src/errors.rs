@@ -6,6 +6,27 @@ pub enum VMError {
     StorageReadError,
     StorageSizeTooBig,
     StorageUTF8ConversionFailed,
+    StorageChecksumMismatch,
+    UnsupportedStorageVersion(u16),
+    UnsupportedStorageFlags(u16),
+    GlobalIndexOutOfBounds(u32),
+    NoTransaction,
+    StorageConflict,
+    TransactionNotCommitted,
+    SnapshotNotFound(String),
+    InvalidSnapshotName(String),
+    SnapshotsNotSupported,
+    NotForked,
+    // fsck repair of version 0 store with big-endian instructions that can't be converted
+    UnconvertibleInstructions(u32),
+    // saved store data holds task id twice, or id at TASK_ID_LIMIT which store can't load
+    InvalidTaskId(u32),
+    // loaded snapshot or journal has task id at TASK_ID_LIMIT, at next_id or indexed twice
+    StorageCorrupted,
+
+    // VM image errors
+    InvalidImage,
+    UnsupportedImageVersion(u16),
 
     // Pool errors
     InvalidStringIndex(usize),
@@ -24,9 +45,17 @@ pub enum VMError {
         index: u32,
         size: u32,
     },
+    MemoryLimitExceeded {
+        size: u32,
+        limit: u32,
+    },
     //tagged values errors
     TypeMismatch,
     InvalidType,
+    NestingTooDeep,
+
+    // Map errors
+    MapKeyNotFound,
 
     // Bytecode errors
     EmptyInstructions,
@@ -59,6 +88,8 @@ pub enum VMError {
     BytesToStringConversionError,
     U8toU32ReinterpetationError,
     AlignmentMismatch,
+    // vec u32 is borrowed from little-endian memory, not possible on big-endian host
+    UnsupportedEndianness,
     // arithmetic errors
     MultiplicationOverflowed,
     SliceSizeMismatch,
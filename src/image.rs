@@ -0,0 +1,51 @@
+/*
+ * VM image - whole machine in a single file (VM::snapshot / VM::restore)
+ *
+ * [magic "SPDI"][version: u16 le]
+ * [strict: u8][memory limit: u32][suspended: u8]
+ * [stack][control stack][call stack (instructions ref, pc)]
+ * [linear memory][instructions pool][storage]
+ *
+ * u32 are compact (see bincodec), values on stacks are raw nan-boxed u64.
+ * memory offsets, handles and pool indices are kept as is, so restored values point to the same data,
+ * restore rejects image whose values point outside of its memory or pool (InvalidImage).
+ * storage is saved with unsaved changes and deleted slots, tasks.bin is not touched.
+ */
+
+use crate::errors::{VMError, VMResult};
+use std::io::{Read, Write};
+
+const MAGIC: [u8; 4] = *b"SPDI";
+const VERSION: u16 = 1;
+
+pub(crate) fn write_header<W: Write>(w: &mut W) -> VMResult<()> {
+    w.write_all(&MAGIC)
+        .map_err(|_| VMError::StorageWriteError)?;
+    w.write_all(&VERSION.to_le_bytes())
+        .map_err(|_| VMError::StorageWriteError)
+}
+
+pub(crate) fn read_header<R: Read>(r: &mut R) -> VMResult<()> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)
+        .map_err(|_| VMError::InvalidImage)?;
+    if magic != MAGIC {
+        return Err(VMError::InvalidImage);
+    }
+    let mut version = [0u8; 2];
+    r.read_exact(&mut version)
+        .map_err(|_| VMError::InvalidImage)?;
+    match u16::from_le_bytes(version) {
+        VERSION => Ok(()),
+        v => Err(VMError::UnsupportedImageVersion(v)),
+    }
+}
+
+// image ends right after storage
+pub(crate) fn read_end<R: Read>(r: &mut R) -> VMResult<()> {
+    let mut rest = [0u8; 1];
+    match r.read(&mut rest) {
+        Ok(0) => Ok(()),
+        _ => Err(VMError::InvalidImage),
+    }
+}
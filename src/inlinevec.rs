@@ -2,7 +2,7 @@
 // Parameters of `InlineVec<T, C>` : `T` stands for the element type and `C` for the maximum capacity, ie array size.
 // InlineVec is fixed size - ie not growable.
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct InlineVec<T, const C: usize> {
     len: u32,
     array: [T; C],
@@ -73,6 +73,11 @@ impl<T: Default + Copy, const C: usize> InlineVec<T, C> {
     pub fn as_slice(&self) -> &[T] {
         &self.array[..self.len()]
     }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [T] {
+        let len = self.len();
+        &mut self.array[..len]
+    }
 }
 
 impl<T: Default + Copy, const C: usize> Default for InlineVec<T, C> {
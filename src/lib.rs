@@ -1,7 +1,10 @@
+mod builder;
 mod bytecode;
+mod collections;
 #[cfg(feature = "dot")]
 mod dot;
 mod errors;
+mod image;
 mod inlinevec;
 mod memory;
 mod pools;
@@ -9,8 +12,17 @@ mod storage;
 mod values;
 mod vm;
 
+pub use builder::{SavePolicy, VMBuilder};
 pub use errors::{VMError, VMResult};
-pub use storage::task_types::{Task, TaskField, TaskState};
+pub use memory::MemoryStats;
+pub use storage::backend::{FileBackend, MemoryBackend, StorageBackend};
+pub use storage::diff::{
+    FieldChange, GlobalChange, MergeConflict, StorageDiff, StorageMerge, TaskChange,
+};
+pub use storage::fsck::{FsckIssue, FsckReport};
+pub use storage::globals::Global;
+pub use storage::shared::SharedStorage;
+pub use storage::task_types::{StorageData, Task, TaskField, TaskState};
 pub use values::*;
 pub use vm::VM;
 pub mod prelude {
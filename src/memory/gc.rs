@@ -0,0 +1,261 @@
+/*
+ * Mark/compact collector for linear memory
+ *
+ * roots are values on the vm stack (control stack and call stack hold no pointers).
+ * mark: live slices are found from roots, maps and lists are traced through their items
+ * (lists may contain themselves, header offset is the visited marker).
+ * compact: live slices are moved to the start of memory keeping order and alignment,
+ * handle table is rebuilt, roots, headers and items are rewritten to the new offsets.
+ *
+ * collection runs only between instructions, when every live value is on the stack.
+ * restored vm image is traced the same way before use, see check_values.
+ */
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::LinearMemory;
+use crate::collections::{HEADER_SIZE, list, map};
+use crate::errors::{VMError, VMResult};
+use crate::values::{
+    TAG_U32, Value, ValueType, get_value_type, is_list, is_map, is_vec, tag, to_fat_pointer,
+    to_handle, to_handle_val, to_u32, to_vec_val,
+};
+
+// memory growth between collections when there is enough headroom
+const MIN_GROWTH: u32 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Bytes,
+    Header,
+    // items region of map or list, only first `values` slots are initialized
+    Items { values: u32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Object {
+    size: u32,
+    align: u32,
+    kind: Kind,
+}
+
+// old offset -> new offset, old handle -> new handle
+#[derive(Default)]
+struct Relocations {
+    offsets: BTreeMap<u32, u32>,
+    handles: HashMap<u32, u32>,
+    new_handles: Vec<(u32, u32)>,
+}
+
+impl LinearMemory {
+    // memory grew past scheduled threshold since last collection (or reset)
+    #[inline]
+    pub(crate) fn needs_collect(&self) -> bool {
+        self.len() > self.next_collect
+    }
+
+    // next collection after live size doubles, but not later than half of the remaining headroom
+    pub(crate) fn schedule_collect(&mut self) {
+        let live = self.len();
+        let headroom = self.limit.saturating_sub(live) / 2;
+        self.next_collect = live + live.max(MIN_GROWTH).min(headroom);
+    }
+
+    pub(crate) fn collect(&mut self, roots: &mut [Value]) -> VMResult<()> {
+        let mut objects = BTreeMap::new();
+        let mut pending = roots.to_vec();
+        while let Some(v) = pending.pop() {
+            self.mark(v, &mut objects, &mut pending)?;
+        }
+
+        let mut relocations = self.compact(&objects);
+
+        for (old, object) in &objects {
+            let at = relocations.offsets[old];
+            match object.kind {
+                Kind::Bytes => {}
+                Kind::Header => {
+                    let items = self.read_u32(at);
+                    let moved = *relocations
+                        .offsets
+                        .get(&items)
+                        .ok_or(VMError::InvalidType)?;
+                    self.write_u32(at, moved);
+                }
+                Kind::Items { values } => {
+                    for i in 0..values {
+                        let v = self.read_value(at + i * 8);
+                        let moved = self.relocate(v, &mut relocations)?;
+                        self.write_value(at + i * 8, moved);
+                    }
+                }
+            }
+        }
+        for root in roots.iter_mut() {
+            *root = self.relocate(*root, &mut relocations)?;
+        }
+
+        self.handles = relocations.new_handles;
+        self.stats.size = self.len();
+        self.stats.collections = self.stats.collections.saturating_add(1);
+        self.schedule_collect();
+        Ok(())
+    }
+
+    fn mark(
+        &self,
+        v: Value,
+        objects: &mut BTreeMap<u32, Object>,
+        pending: &mut Vec<Value>,
+    ) -> VMResult<()> {
+        if !is_vec(v) {
+            return Ok(());
+        }
+        let (offset, size) = self.fat_pointer(v)?;
+        if is_map(v) || is_list(v) {
+            if objects.contains_key(&offset) {
+                return Ok(());
+            }
+            let item_size = if is_map(v) {
+                map::ENTRY_SIZE
+            } else {
+                list::ITEM_SIZE
+            };
+            let (items, len, capacity) = self.header_read(offset);
+            let values = len * item_size / 8;
+            objects.insert(
+                offset,
+                Object {
+                    size,
+                    align: 4,
+                    kind: Kind::Header,
+                },
+            );
+            objects.insert(
+                items,
+                Object {
+                    size: capacity * item_size,
+                    align: 4,
+                    kind: Kind::Items { values },
+                },
+            );
+            for i in 0..values {
+                pending.push(self.read_value(items + i * 8));
+            }
+            return Ok(());
+        }
+        // empty slices own no bytes, they are relocated to offset 0
+        if size == 0 {
+            return Ok(());
+        }
+        let align = if tag(v)? == TAG_U32 { 4 } else { 1 };
+        objects.entry(offset).or_insert(Object {
+            size,
+            align,
+            kind: Kind::Bytes,
+        });
+        Ok(())
+    }
+
+    // values of restored image: every slice reachable from roots must lie inside memory,
+    // calldata refs are checked by is_calldata. InvalidImage otherwise
+    pub(crate) fn check_values(
+        &self,
+        roots: &[Value],
+        is_calldata: impl Fn(u32) -> bool,
+    ) -> VMResult<()> {
+        let mut visited = HashSet::new();
+        let mut pending = roots.to_vec();
+        while let Some(v) = pending.pop() {
+            match get_value_type(v).map_err(|_| VMError::InvalidImage)? {
+                ValueType::CallData if !is_calldata(to_u32(v)) => {
+                    return Err(VMError::InvalidImage);
+                }
+                _ if !is_vec(v) => continue,
+                _ => {}
+            }
+            let (offset, size) = self.fat_pointer(v).map_err(|_| VMError::InvalidImage)?;
+            self.check_range(offset, size as u64)?;
+            if !(is_map(v) || is_list(v)) || !visited.insert(offset) {
+                continue;
+            }
+            let item_size = if is_map(v) {
+                map::ENTRY_SIZE
+            } else {
+                list::ITEM_SIZE
+            };
+            if size < HEADER_SIZE as u32 {
+                return Err(VMError::InvalidImage);
+            }
+            let (items, len, capacity) = self.header_read(offset);
+            if len > capacity {
+                return Err(VMError::InvalidImage);
+            }
+            self.check_range(items, capacity as u64 * item_size as u64)?;
+            for i in 0..len * item_size / 8 {
+                pending.push(self.read_value(items + i * 8));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, offset: u32, size: u64) -> VMResult<()> {
+        if offset as u64 + size > self.len() as u64 {
+            return Err(VMError::InvalidImage);
+        }
+        Ok(())
+    }
+
+    // slides live objects down in offset order, new offset never exceeds the old one
+    fn compact(&mut self, objects: &BTreeMap<u32, Object>) -> Relocations {
+        let mut relocations = Relocations::default();
+        let mut cursor = 0u32;
+        for (&old, object) in objects {
+            let new = (cursor + object.align - 1) & !(object.align - 1);
+            if new != old {
+                self.copy_within(old, object.size, new);
+            }
+            relocations.offsets.insert(old, new);
+            cursor = new + object.size;
+        }
+        self.bytes.truncate(cursor as usize);
+        relocations
+    }
+
+    fn relocate(&self, v: Value, relocations: &mut Relocations) -> VMResult<Value> {
+        if !is_vec(v) {
+            return Ok(v);
+        }
+        let type_tag = tag(v)?;
+        match to_handle(v) {
+            Some(handle) => {
+                let moved = match relocations.handles.get(&handle) {
+                    Some(&moved) => moved,
+                    None => {
+                        let (offset, size) = self.fat_pointer(v)?;
+                        let new_offset = *relocations
+                            .offsets
+                            .get(&offset)
+                            .ok_or(VMError::InvalidType)?;
+                        let moved = relocations.new_handles.len() as u32;
+                        relocations.new_handles.push((new_offset, size));
+                        relocations.handles.insert(handle, moved);
+                        moved
+                    }
+                };
+                to_handle_val(moved, type_tag)
+            }
+            None => {
+                let (offset, size) = to_fat_pointer(v)?;
+                if size == 0 {
+                    return to_vec_val(0, 0, type_tag);
+                }
+                let new_offset = *relocations
+                    .offsets
+                    .get(&offset)
+                    .ok_or(VMError::InvalidType)?;
+                to_vec_val(new_offset, size, type_tag)
+            }
+        }
+    }
+}
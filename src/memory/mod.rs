@@ -0,0 +1,373 @@
+use std::io::{Read, Write};
+
+use crate::errors::{VMError, VMResult};
+use crate::storage::bincodec::{Decode, Encode};
+use crate::values::{
+    TAG_STRING, TAG_U32, U25_MAX, Value, to_fat_pointer, to_handle, to_handle_val, to_vec_val,
+};
+use crate::{FALSE_VAL, TRUE_VAL, to_bool_val};
+
+mod gc;
+
+// bytes - linear memory itself, every multi-byte value is stored little-endian
+// handles - heap table for slices bigger than u16::MAX bytes: handle value -> (offset, size)
+// small slices keep (offset, size) inline in the fat pointer
+// limit - memory cap in bytes (VMBuilder::memory_limit), can't exceed 25 bit offset space
+// next_collect - memory size that triggers next garbage collection (see gc.rs)
+#[derive(Debug)]
+pub struct LinearMemory {
+    bytes: Vec<u8>,
+    handles: Vec<(u32, u32)>,
+    limit: u32,
+    next_collect: u32,
+    stats: MemoryStats,
+}
+
+/// Linear memory accounting since the start of the last run.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemoryStats {
+    /// current memory size in bytes
+    pub size: u32,
+    /// max memory size in bytes
+    pub peak: u32,
+    /// amount of allocations, stays at u32::MAX once reached
+    pub allocations: u32,
+    /// amount of garbage collections, stays at u32::MAX once reached
+    pub collections: u32,
+}
+
+#[inline]
+pub(crate) const fn element_size_bytes(tag: u64) -> VMResult<usize> {
+    match tag {
+        TAG_STRING => Ok(1),
+        TAG_U32 => Ok(4),
+        _ => Err(VMError::InvalidType),
+    }
+}
+
+impl LinearMemory {
+    pub(crate) fn new(limit: u32) -> Self {
+        let mut memory = Self {
+            bytes: Vec::new(),
+            handles: Vec::new(),
+            limit: limit.min(U25_MAX),
+            next_collect: 0,
+            stats: MemoryStats::default(),
+        };
+        memory.schedule_collect();
+        memory
+    }
+
+    // memory lives only between runs, limit is kept
+    pub(crate) fn reset(&mut self) {
+        *self = Self::new(self.limit);
+    }
+
+    pub(crate) fn stats(&self) -> MemoryStats {
+        self.stats
+    }
+
+    // every allocation goes through reserve: checks offset space and memory limit, grows memory
+    fn reserve(&mut self, offset: u32, size: u32) -> VMResult<usize> {
+        let end = offset as usize + size as usize;
+        if end > U25_MAX as usize {
+            return Err(VMError::MSliceParamOverflow);
+        }
+        if end > self.limit as usize {
+            return Err(VMError::MemoryLimitExceeded {
+                size: end as u32,
+                limit: self.limit,
+            });
+        }
+        if end > self.bytes.len() {
+            self.bytes.resize(end, 0u8);
+        }
+        self.stats.allocations = self.stats.allocations.saturating_add(1);
+        self.stats.size = self.len();
+        self.stats.peak = self.stats.peak.max(self.stats.size);
+        Ok(end)
+    }
+
+    //the purpose is to align string and especially vec u32 acorrectly.
+    // it may be string with len 2 before, that will lead to incorrect allignment of vec
+    // works: 1-4 => 4 , 5-8 => 8 etc
+    // also important to check proposed offset by assembler and not allow to overlay
+    fn offset_aligned(&self, tag: u8) -> u32 {
+        match tag as u64 {
+            TAG_U32 => (self.len() + 3) & !3,
+            _ => self.len(),
+        }
+    }
+
+    // accepts size in bytes
+    // gets aligned offset
+    // since we have 2 sourses -> load and bytecode (dot) without coordination, auto alloc is safer option
+    pub(crate) fn alloc(&mut self, size: u32, tag: u8, payload: &[u8]) -> VMResult<Value> {
+        let tag = tag as u64;
+        if !matches!(tag, TAG_STRING | TAG_U32) {
+            return Err(VMError::InvalidType);
+        }
+        let offset = self.offset_aligned(tag as u8);
+        let end = self.reserve(offset, size)?;
+
+        if !payload.is_empty() {
+            self.bytes[offset as usize..end].copy_from_slice(payload);
+        }
+        self.slice_val(offset, size, tag)
+    }
+
+    // fast path: size fits u16 -> inline fat pointer, otherwise handle into heap table
+    fn slice_val(&mut self, offset: u32, size: u32, tag: u64) -> VMResult<Value> {
+        match u16::try_from(size) {
+            Ok(size) => to_vec_val(offset, size, tag),
+            Err(_) => {
+                let handle = self.handles.len() as u32;
+                self.handles.push((offset, size));
+                to_handle_val(handle, tag)
+            }
+        }
+    }
+
+    // resolves vec value (inline or handle) to (offset, size in bytes)
+    pub(crate) fn fat_pointer(&self, v: Value) -> VMResult<(u32, u32)> {
+        match to_handle(v) {
+            Some(handle) => self
+                .handles
+                .get(handle as usize)
+                .copied()
+                .ok_or(VMError::InvalidType),
+            None => {
+                let (offset, size) = to_fat_pointer(v)?;
+                Ok((offset, size as u32))
+            }
+        }
+    }
+    pub(crate) fn mut_vec(
+        &mut self,
+        offset: u32,
+        size: u32,
+        index: u32,
+        payload: u32,
+        tag: u64,
+    ) -> VMResult<()> {
+        let element_size_bytes = element_size_bytes(tag)?;
+        if index >= size / element_size_bytes as u32 {
+            return Err(VMError::MSliceOutOfBounds {
+                index: index * element_size_bytes as u32,
+                size,
+            });
+        }
+        let abs_index = offset as usize + index as usize * element_size_bytes;
+        let payload_bytes = payload.to_le_bytes();
+        // shortcut as we use u32 payload -> little-endian payload_bytes gives us [low,..,high]
+        // if 4 bytes -> [.. element_size_bytes] (all 4 bytes) - for vec u32 vals
+        // if 1 byte -> [.. element_size_bytes ] ([..1] low byte) - for string vals
+        self.bytes[abs_index..abs_index + element_size_bytes]
+            .copy_from_slice(&payload_bytes[..element_size_bytes]);
+        Ok(())
+    }
+
+    // raw allocation for vm owned structures (ie map header and entries), returns offset
+    pub(crate) fn alloc_raw(&mut self, size: u32, align: u32) -> VMResult<u32> {
+        let offset = (self.len() + align - 1) & !(align - 1);
+        self.reserve(offset, size)?;
+        Ok(offset)
+    }
+
+    pub(crate) fn read_u32(&self, offset: u32) -> u32 {
+        let at = offset as usize;
+        u32::from_le_bytes(self.bytes[at..at + 4].try_into().unwrap_or_default())
+    }
+
+    pub(crate) fn write_u32(&mut self, offset: u32, val: u32) {
+        let at = offset as usize;
+        self.bytes[at..at + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    pub(crate) fn read_value(&self, offset: u32) -> Value {
+        let at = offset as usize;
+        u64::from_le_bytes(self.bytes[at..at + 8].try_into().unwrap_or_default())
+    }
+
+    pub(crate) fn write_value(&mut self, offset: u32, val: Value) {
+        let at = offset as usize;
+        self.bytes[at..at + 8].copy_from_slice(&val.to_le_bytes());
+    }
+
+    // copies bytes inside memory, used to move vm owned structures on growth
+    pub(crate) fn copy_within(&mut self, from: u32, size: u32, to: u32) {
+        let from = from as usize;
+        self.bytes
+            .copy_within(from..from + size as usize, to as usize);
+    }
+
+    pub(crate) fn get_slice_bytes(&self, offset: u32, size: u32) -> &[u8] {
+        &self.bytes[offset as usize..offset as usize + size as usize]
+    }
+    pub(crate) fn get_slice_as_str(&self, offset: u32, size: u32) -> VMResult<&str> {
+        let bytes = &self.bytes[offset as usize..offset as usize + size as usize];
+        std::str::from_utf8(bytes).map_err(|_| VMError::BytesToStringConversionError)
+    }
+
+    //reinterpreting &[u8] as &[u32]
+    // memory is little-endian, so slice is borrowed as it is only on little-endian hosts,
+    // big-endian host gets UnsupportedEndianness instead of misleading AlignmentMismatch
+    // https://doc.rust-lang.org/std/primitive.slice.html#method.align_to
+    pub(crate) fn get_slice_as_u32(&self, offset: u32, size: u32) -> VMResult<&[u32]> {
+        let bytes = self.get_slice_bytes(offset, size);
+        if !bytes.len().is_multiple_of(size_of::<u32>()) {
+            return Err(VMError::SliceSizeMismatch);
+        }
+        if cfg!(target_endian = "big") {
+            return Err(VMError::UnsupportedEndianness);
+        }
+        let (prefix, aligned_u32, suffix) = unsafe { bytes.align_to::<u32>() };
+        if !prefix.is_empty() || !suffix.is_empty() {
+            return Err(VMError::AlignmentMismatch);
+        }
+        Ok(aligned_u32)
+    }
+
+    pub(crate) fn is_m_slice_eq(&self, left: (u32, u32), right: (u32, u32)) -> Value {
+        let (lo, ls) = left;
+        let (ro, rs) = right;
+        if ls != rs {
+            return FALSE_VAL;
+        }
+        if lo == ro {
+            return TRUE_VAL;
+        }
+        to_bool_val(self.get_slice_bytes(lo, ls) == self.get_slice_bytes(ro, rs))
+    }
+
+    pub(crate) fn len(&self) -> u32 {
+        self.bytes.len() as u32
+    }
+}
+
+// vm image: memory is saved as is, so values on stacks stay valid after restore
+// [limit][next_collect][bytes][handles len][(offset, size)..][size][peak][allocations][collections]
+impl Encode for LinearMemory {
+    fn encode<W: Write>(&self, w: &mut W) -> VMResult<()> {
+        self.limit.encode(w)?;
+        self.next_collect.encode(w)?;
+        self.bytes.encode(w)?;
+        (self.handles.len() as u32).encode(w)?;
+        for (offset, size) in &self.handles {
+            offset.encode(w)?;
+            size.encode(w)?;
+        }
+        self.stats.size.encode(w)?;
+        self.stats.peak.encode(w)?;
+        self.stats.allocations.encode(w)?;
+        self.stats.collections.encode(w)
+    }
+}
+
+impl Decode for LinearMemory {
+    fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
+        let limit = u32::decode(r)?.min(U25_MAX);
+        let next_collect = u32::decode(r)?;
+        let bytes = Vec::<u8>::decode(r)?;
+        if bytes.len() > limit as usize {
+            return Err(VMError::StorageSizeTooBig);
+        }
+        let len = u32::decode(r)?;
+        if len >= U25_MAX {
+            return Err(VMError::StorageSizeTooBig);
+        }
+        let mut handles = Vec::new();
+        for _ in 0..len {
+            let (offset, size) = (u32::decode(r)?, u32::decode(r)?);
+            if offset as usize + size as usize > bytes.len() {
+                return Err(VMError::StorageReadError);
+            }
+            handles.push((offset, size));
+        }
+        let stats = MemoryStats {
+            size: u32::decode(r)?,
+            peak: u32::decode(r)?,
+            allocations: u32::decode(r)?,
+            collections: u32::decode(r)?,
+        };
+        Ok(Self {
+            bytes,
+            handles,
+            limit,
+            next_collect,
+            stats,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::values::to_calldata_val;
+
+    #[test]
+    fn test_vec_u32_little_endian() {
+        let mut memory = LinearMemory::new(U25_MAX);
+        let v = memory.alloc(8, TAG_U32 as u8, &[]).unwrap();
+        let (offset, size) = memory.fat_pointer(v).unwrap();
+        memory
+            .mut_vec(offset, size, 1, 0x0403_0201, TAG_U32)
+            .unwrap();
+        assert_eq!(
+            memory.get_slice_bytes(offset, size),
+            &[0, 0, 0, 0, 0x01, 0x02, 0x03, 0x04]
+        );
+        assert_eq!(
+            memory.get_slice_as_u32(offset, size).unwrap(),
+            &[0, 0x0403_0201]
+        );
+    }
+
+    #[test]
+    fn test_unaligned_vec_u32_rejected() {
+        let mut memory = LinearMemory::new(U25_MAX);
+        memory.alloc(1, TAG_STRING as u8, b"x").unwrap();
+        let offset = memory.alloc_raw(4, 1).unwrap();
+        memory.write_u32(offset, 0x0403_0201);
+        assert_eq!(memory.get_slice_bytes(offset, 4), &[0x01, 0x02, 0x03, 0x04]);
+        assert!(matches!(
+            memory.get_slice_as_u32(offset, 4),
+            Err(VMError::AlignmentMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_allocations_saturate() {
+        let mut memory = LinearMemory::new(U25_MAX);
+        memory.stats.allocations = u32::MAX;
+        memory.alloc(1, TAG_STRING as u8, b"x").unwrap();
+        assert_eq!(memory.stats().allocations, u32::MAX);
+    }
+
+    #[test]
+    fn test_check_values() {
+        let mut memory = LinearMemory::new(U25_MAX);
+        let list = memory.list_new().unwrap();
+        let string = memory.alloc(2, TAG_STRING as u8, b"hi").unwrap();
+        memory.list_push(list, string).unwrap();
+        memory.list_push(list, to_calldata_val(1)).unwrap();
+        memory.list_push(list, list).unwrap();
+        assert!(memory.check_values(&[list], |index| index == 1).is_ok());
+
+        let invalid = |memory: &LinearMemory, roots: &[Value]| {
+            matches!(
+                memory.check_values(roots, |index| index == 1),
+                Err(VMError::InvalidImage)
+            )
+        };
+        // calldata ref in list item is checked too
+        assert!(invalid(&memory, &[list, to_calldata_val(2)]));
+        let out_of_memory = to_vec_val(memory.len() - 1, 2, TAG_STRING).unwrap();
+        assert!(invalid(&memory, &[out_of_memory]));
+        assert!(invalid(&memory, &[to_handle_val(0, TAG_STRING).unwrap()]));
+        // list header with len above capacity
+        let (header, _) = memory.fat_pointer(list).unwrap();
+        memory.write_u32(header + 4, 9);
+        assert!(invalid(&memory, &[list]));
+    }
+}
@@ -1,25 +1,120 @@
 use crate::errors::{VMError, VMResult};
+use crate::storage::bincodec::{Decode, Encode};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{Read, Write};
 
-#[derive(Debug, Default)]
+// identical instructions are interned once: executed PUSH_CALLDATA and tasks sharing code reuse the index
+// unreachable instructions are freed by collect between runs, freed indices are reused
+#[derive(Debug, Default, Clone)]
 pub struct InstructionsPool {
-    instructions: Vec<Vec<u8>>,
+    // None -> freed slot
+    instructions: Vec<Option<Vec<u8>>>,
+    // content hash -> indices of instructions with that hash
+    interned: HashMap<u64, Vec<u32>>,
+    free: Vec<u32>,
+}
+
+pub(crate) fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
 }
 
 impl InstructionsPool {
     pub(crate) fn intern_instructions(&mut self, calldata: Vec<u8>) -> u32 {
-        let idx = self.instructions.len();
-        self.instructions.push(calldata);
-        idx as u32
+        let hash = content_hash(&calldata);
+        let candidates = self.interned.entry(hash).or_default();
+        if let Some(&idx) = candidates
+            .iter()
+            .find(|&&idx| self.instructions[idx as usize].as_ref() == Some(&calldata))
+        {
+            return idx;
+        }
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.instructions[idx as usize] = Some(calldata);
+                idx
+            }
+            None => {
+                self.instructions.push(Some(calldata));
+                self.instructions.len() as u32 - 1
+            }
+        };
+        candidates.push(idx);
+        idx
+    }
+
+    // number of interned instructions
+    pub(crate) fn len(&self) -> usize {
+        self.instructions.len() - self.free.len()
     }
-    // pub(crate) fn len(&self) -> usize {
-    //     self.instructions.len()
-    // }
+
     //err if no instruction in pool
     pub(crate) fn get(&self, index: usize) -> VMResult<&[u8]> {
         let instructions = self
             .instructions
             .get(index)
+            .and_then(Option::as_deref)
             .ok_or(VMError::InvalidInstructionsIndex(index))?;
         Ok(instructions)
     }
+
+    // frees instructions not referenced by roots
+    pub(crate) fn collect(&mut self, roots: impl IntoIterator<Item = u32>) {
+        let mut live = vec![false; self.instructions.len()];
+        for idx in roots {
+            if let Some(live) = live.get_mut(idx as usize) {
+                *live = true;
+            }
+        }
+        for (idx, slot) in self.instructions.iter_mut().enumerate() {
+            if live[idx] {
+                continue;
+            }
+            let Some(instructions) = slot.take() else {
+                continue;
+            };
+            let hash = content_hash(&instructions);
+            if let Some(candidates) = self.interned.get_mut(&hash) {
+                candidates.retain(|&i| i as usize != idx);
+                if candidates.is_empty() {
+                    self.interned.remove(&hash);
+                }
+            }
+            self.free.push(idx as u32);
+        }
+    }
+}
+
+// vm image: pool is saved with indices, calldata values and task instructions refs stay valid
+impl Encode for InstructionsPool {
+    fn encode<W: Write>(&self, w: &mut W) -> VMResult<()> {
+        (self.instructions.len() as u32).encode(w)?;
+        // freed slot is saved empty, it is unreachable and freed again by next collect
+        for instructions in &self.instructions {
+            match instructions {
+                Some(instructions) => instructions.encode(w)?,
+                None => Vec::<u8>::new().encode(w)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Decode for InstructionsPool {
+    fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
+        let len = u32::decode(r)?;
+        let mut pool = Self::default();
+        // indices are kept as they are, duplicates of older images stay in place
+        for idx in 0..len {
+            let instructions = Vec::decode(r)?;
+            pool.interned
+                .entry(content_hash(&instructions))
+                .or_default()
+                .push(idx);
+            pool.instructions.push(Some(instructions));
+        }
+        Ok(pool)
+    }
 }
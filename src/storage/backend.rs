@@ -0,0 +1,390 @@
+use crate::errors::{VMError, VMResult};
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// default FileBackend path, relative to current directory
+const DEFAULT_PATH: &str = "tasks.bin";
+
+/// Persistent place for encoded storage (tasks, next id, globals).
+/// Storage encodes and decodes data itself, backend only keeps bytes.
+pub trait StorageBackend: Debug + Send {
+    /// Returns bytes of last save, None if nothing was saved yet.
+    /// Whole store is returned, storage only decodes its tasks lazily.
+    fn load(&mut self) -> VMResult<Option<Vec<u8>>>;
+    /// Replaces saved bytes and drops journal.
+    fn save(&mut self, bytes: &[u8]) -> VMResult<()>;
+    /// Returns journal appended since last save, None if there is none.
+    fn load_journal(&mut self) -> VMResult<Option<Vec<u8>>> {
+        Ok(None)
+    }
+    /// Appends bytes to journal. Returns false if backend has no journal,
+    /// storage then falls back to full save.
+    fn append_journal(&mut self, _bytes: &[u8]) -> VMResult<bool> {
+        Ok(false)
+    }
+    /// Saves named snapshot of the store, replacing snapshot of the same name.
+    fn save_named(&mut self, _name: &str, _bytes: &[u8]) -> VMResult<()> {
+        Err(VMError::SnapshotsNotSupported)
+    }
+    /// Returns bytes of named snapshot, None if there is none.
+    fn load_named(&mut self, _name: &str) -> VMResult<Option<Vec<u8>>> {
+        Err(VMError::SnapshotsNotSupported)
+    }
+    /// Names of snapshots, oldest first.
+    fn list_named(&mut self) -> VMResult<Vec<String>> {
+        Ok(Vec::new())
+    }
+    /// Deletes named snapshot, returns false if there was none.
+    fn delete_named(&mut self, _name: &str) -> VMResult<bool> {
+        Ok(false)
+    }
+}
+
+/// Storage file at given path ("tasks.bin" in current directory by default).
+/// Processes sharing the file are synchronized by advisory lock on "<path>.lock",
+/// save fails with StorageConflict if other process wrote the file after it was loaded
+/// (VM::reload() loads it again).
+/// Named snapshots are files "<seq>-<name>" in "<path>.snapshots" directory.
+#[derive(Debug, Clone)]
+pub struct FileBackend {
+    path: PathBuf,
+    // store generation at load, None if nothing was loaded (save is not checked)
+    generation: Option<u64>,
+    // journal read together with snapshot under the same lock
+    journal: Option<Vec<u8>>,
+}
+
+impl FileBackend {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            generation: None,
+            journal: None,
+        }
+    }
+
+    // "<path>.journal" next to storage file
+    fn journal_path(&self) -> PathBuf {
+        with_suffix(&self.path, ".journal")
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        with_suffix(&self.path, ".lock")
+    }
+
+    fn snapshots_path(&self) -> PathBuf {
+        with_suffix(&self.path, ".snapshots")
+    }
+
+    // (seq, name, file) of named snapshots, oldest first
+    fn named(&self) -> VMResult<Vec<(u64, String, PathBuf)>> {
+        let entries = match fs::read_dir(self.snapshots_path()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(_) => return Err(VMError::StorageReadError),
+        };
+        let mut named = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|_| VMError::StorageReadError)?.path();
+            // other files (temporary ones of interrupted save) are skipped
+            let parsed = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split_once('-'))
+                .and_then(|(seq, name)| Some((seq.parse().ok()?, name.to_string())));
+            if let Some((seq, name)) = parsed
+                && !name.ends_with(".tmp")
+            {
+                named.push((seq, name, path));
+            }
+        }
+        named.sort();
+        Ok(named)
+    }
+
+    // exclusive lock for write, store must be of loaded generation
+    fn lock_for_write(&mut self) -> VMResult<StoreLock> {
+        let mut lock = StoreLock::exclusive(&self.lock_path())?;
+        let generation = lock.generation().map_err(|_| VMError::StorageWriteError)?;
+        if self.generation.is_some_and(|loaded| loaded != generation) {
+            return Err(VMError::StorageConflict);
+        }
+        // bumped before write: crash in between makes other processes conflict, never lose a write
+        lock.set_generation(generation + 1)?;
+        self.generation = Some(generation + 1);
+        Ok(lock)
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path: OsString = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn read_optional(path: &Path) -> VMResult<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(_) => Err(VMError::StorageReadError),
+    }
+}
+
+// advisory lock on "<path>.lock", file keeps store generation (u64 LE) bumped by every write.
+// lock is released when file is closed
+struct StoreLock {
+    file: File,
+}
+
+impl StoreLock {
+    // None if there is no lock file: store was never written with locking (generation 0)
+    fn shared(path: &Path) -> VMResult<Option<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(_) => return Err(VMError::StorageReadError),
+        };
+        file.lock_shared().map_err(|_| VMError::StorageReadError)?;
+        Ok(Some(Self { file }))
+    }
+
+    fn exclusive(path: &Path) -> VMResult<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|_| VMError::StorageWriteError)?;
+        file.lock().map_err(|_| VMError::StorageWriteError)?;
+        Ok(Self { file })
+    }
+
+    // empty lock file is generation 0
+    fn generation(&mut self) -> VMResult<u64> {
+        let mut bytes = Vec::new();
+        self.file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.file.read_to_end(&mut bytes))
+            .map_err(|_| VMError::StorageReadError)?;
+        Ok(bytes
+            .get(..8)
+            .map_or(0, |b| u64::from_le_bytes(b.try_into().unwrap_or_default())))
+    }
+
+    fn set_generation(&mut self, generation: u64) -> VMResult<()> {
+        self.file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.file.write_all(&generation.to_le_bytes()))
+            .map_err(|_| VMError::StorageWriteError)
+    }
+}
+
+impl Default for FileBackend {
+    fn default() -> Self {
+        Self::new(DEFAULT_PATH)
+    }
+}
+
+impl StorageBackend for FileBackend {
+    // snapshot and journal are read under one shared lock, so they are of the same generation
+    fn load(&mut self) -> VMResult<Option<Vec<u8>>> {
+        let mut lock = StoreLock::shared(&self.lock_path())?;
+        self.generation = Some(match &mut lock {
+            Some(lock) => lock.generation()?,
+            None => 0,
+        });
+        self.journal = read_optional(&self.journal_path())?;
+        read_optional(&self.path)
+    }
+
+    fn save(&mut self, bytes: &[u8]) -> VMResult<()> {
+        let _lock = self.lock_for_write()?;
+        write_atomic(&self.path, bytes)?;
+        // stale journal would be ignored on load, but appending to it would lose new records
+        match fs::remove_file(self.journal_path()) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(_) => Err(VMError::StorageWriteError),
+        }
+    }
+
+    fn load_journal(&mut self) -> VMResult<Option<Vec<u8>>> {
+        Ok(self.journal.take())
+    }
+
+    fn append_journal(&mut self, bytes: &[u8]) -> VMResult<bool> {
+        let _lock = self.lock_for_write()?;
+        let path = self.journal_path();
+        let created = !path.exists();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| {
+                f.write_all(bytes)?;
+                f.sync_data()
+            })
+            .map_err(|_| VMError::StorageWriteError)?;
+        if created {
+            sync_dir(&path)?;
+        }
+        Ok(true)
+    }
+
+    // snapshots are not part of the store, lock only serializes them without bumping generation
+    fn save_named(&mut self, name: &str, bytes: &[u8]) -> VMResult<()> {
+        let _lock = StoreLock::exclusive(&self.lock_path())?;
+        let named = self.named()?;
+        let seq = named.last().map_or(0, |(seq, _, _)| seq + 1);
+        fs::create_dir_all(self.snapshots_path()).map_err(|_| VMError::StorageWriteError)?;
+        write_atomic(&self.snapshots_path().join(format!("{seq}-{name}")), bytes)?;
+        for (_, _, path) in named.iter().filter(|(_, other, _)| other == name) {
+            fs::remove_file(path).map_err(|_| VMError::StorageWriteError)?;
+        }
+        Ok(())
+    }
+
+    fn load_named(&mut self, name: &str) -> VMResult<Option<Vec<u8>>> {
+        let _lock = StoreLock::shared(&self.lock_path())?;
+        match self
+            .named()?
+            .into_iter()
+            .find(|(_, other, _)| other == name)
+        {
+            Some((_, _, path)) => read_optional(&path),
+            None => Ok(None),
+        }
+    }
+
+    fn list_named(&mut self) -> VMResult<Vec<String>> {
+        let _lock = StoreLock::shared(&self.lock_path())?;
+        Ok(self.named()?.into_iter().map(|(_, name, _)| name).collect())
+    }
+
+    fn delete_named(&mut self, name: &str) -> VMResult<bool> {
+        let _lock = StoreLock::exclusive(&self.lock_path())?;
+        let mut deleted = false;
+        for (_, _, path) in self.named()?.iter().filter(|(_, other, _)| other == name) {
+            fs::remove_file(path).map_err(|_| VMError::StorageWriteError)?;
+            deleted = true;
+        }
+        Ok(deleted)
+    }
+}
+
+// crash safe replace: bytes go to "<path>.tmp", are synced to disk and renamed over path,
+// so path has either old or new content, never truncated one.
+// directory is synced too, otherwise rename itself may be lost on power failure
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> VMResult<()> {
+    let tmp = with_suffix(path, ".tmp");
+
+    let written = File::create(&tmp).and_then(|mut f| {
+        f.write_all(bytes)?;
+        f.sync_all()
+    });
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return Err(VMError::StorageWriteError);
+    }
+    if fs::rename(&tmp, path).is_err() {
+        let _ = fs::remove_file(&tmp);
+        return Err(VMError::StorageWriteError);
+    }
+    sync_dir(path)
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> VMResult<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .map_err(|_| VMError::StorageWriteError)
+}
+
+// directories can't be opened for sync on other platforms, rename is atomic there
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> VMResult<()> {
+    Ok(())
+}
+
+/// In-memory storage for tests and ephemeral runs.
+/// Clones share the same bytes, so VMs built with clones of one backend see each other's saves.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    data: Arc<Mutex<MemoryData>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryData {
+    snapshot: Option<Vec<u8>>,
+    journal: Vec<u8>,
+    // named snapshots, oldest first
+    named: Vec<(String, Vec<u8>)>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn load(&mut self) -> VMResult<Option<Vec<u8>>> {
+        let data = self.data.lock().map_err(|_| VMError::StorageReadError)?;
+        Ok(data.snapshot.clone())
+    }
+
+    fn save(&mut self, bytes: &[u8]) -> VMResult<()> {
+        let mut data = self.data.lock().map_err(|_| VMError::StorageWriteError)?;
+        data.snapshot = Some(bytes.to_vec());
+        data.journal.clear();
+        Ok(())
+    }
+
+    fn load_journal(&mut self) -> VMResult<Option<Vec<u8>>> {
+        let data = self.data.lock().map_err(|_| VMError::StorageReadError)?;
+        Ok((!data.journal.is_empty()).then(|| data.journal.clone()))
+    }
+
+    fn append_journal(&mut self, bytes: &[u8]) -> VMResult<bool> {
+        let mut data = self.data.lock().map_err(|_| VMError::StorageWriteError)?;
+        data.journal.extend_from_slice(bytes);
+        Ok(true)
+    }
+
+    fn save_named(&mut self, name: &str, bytes: &[u8]) -> VMResult<()> {
+        let mut data = self.data.lock().map_err(|_| VMError::StorageWriteError)?;
+        data.named.retain(|(other, _)| other != name);
+        data.named.push((name.to_string(), bytes.to_vec()));
+        Ok(())
+    }
+
+    fn load_named(&mut self, name: &str) -> VMResult<Option<Vec<u8>>> {
+        let data = self.data.lock().map_err(|_| VMError::StorageReadError)?;
+        Ok(data
+            .named
+            .iter()
+            .find(|(other, _)| other == name)
+            .map(|(_, bytes)| bytes.clone()))
+    }
+
+    fn list_named(&mut self) -> VMResult<Vec<String>> {
+        let data = self.data.lock().map_err(|_| VMError::StorageReadError)?;
+        Ok(data.named.iter().map(|(name, _)| name.clone()).collect())
+    }
+
+    fn delete_named(&mut self, name: &str) -> VMResult<bool> {
+        let mut data = self.data.lock().map_err(|_| VMError::StorageWriteError)?;
+        let len = data.named.len();
+        data.named.retain(|(other, _)| other != name);
+        Ok(data.named.len() < len)
+    }
+}
@@ -4,14 +4,23 @@
 // is the right long-term move, would allow vm be dependency free, edge cases would be covered.
 //
 // some comments:
-// to little endian for now similar to bincode default
-// String use u16 ie 2 byte for len. Should be safer on decoding (65535 bytes allocation in worst case)
+// little endian, same as bytecode operands and linear memory (bincode default as well)
+// String use u16 ie 2 byte for len. Longer strings (>= 65535 bytes) are escaped: [0xFFFF][len: u32]
+// (version 0 files predate the escape, their titles are read by decode_v0_task)
 // TaskStatus uses u8 ie 1 byte not u32 like bincode
+// Globals are [kind: u8][payload], section is optional: files saved before globals end after next_id
+// u64 (nan-boxed values in vm image) is not compact, always 8 bytes
+// storage file is versioned and checksummed, see STORAGE FILE below
 //
 // ? we can use separate types for title and instructions , so we can encode them with different len - u8 for title and u16 for instructions
 
+use crate::bytecode::legacy;
 use crate::errors::{VMError, VMResult};
-use crate::storage::task_types::{StorageData, Task, TaskState};
+use crate::inlinevec::InlineVec;
+use crate::storage::crc32::crc32;
+use crate::storage::globals::{GLOBALS_LIMIT, Global};
+use crate::storage::snapshot::Snapshot;
+use crate::storage::task_types::{StorageData, TASK_ID_LIMIT, Task, TaskState};
 use std::io::{Read, Write};
 
 const U8_BYTES: u8 = 1;
@@ -24,6 +33,10 @@ const U32_BYTES: u8 = 4;
 // for safety reasons better to have smaller limit
 // 1_000_000 is still dangerous, need storage max size check and limit
 const VEC_LIMIT: usize = 1_000_000;
+// strings and instructions are limited by linear memory offset space (25 bits)
+const BYTES_LIMIT: usize = 1 << 25;
+// u16 len escape for long strings
+const LONG_LEN: u16 = u16::MAX;
 
 pub trait Encode {
     fn encode<W: Write>(&self, w: &mut W) -> VMResult<()>;
@@ -46,6 +59,32 @@ impl Encode for u8 {
     }
 }
 
+impl Encode for u64 {
+    fn encode<W: Write>(&self, w: &mut W) -> VMResult<()> {
+        w.write_all(&self.to_le_bytes())
+            .map_err(|_| VMError::StorageWriteError)
+    }
+}
+
+// loop frames of control stack (pc, index, limit)
+impl Encode for (u64, u64, u64) {
+    fn encode<W: Write>(&self, w: &mut W) -> VMResult<()> {
+        self.0.encode(w)?;
+        self.1.encode(w)?;
+        self.2.encode(w)
+    }
+}
+
+impl<T: Encode + Default + Copy, const C: usize> Encode for InlineVec<T, C> {
+    fn encode<W: Write>(&self, w: &mut W) -> VMResult<()> {
+        (self.len() as u32).encode(w)?;
+        for item in self.as_slice() {
+            item.encode(w)?;
+        }
+        Ok(())
+    }
+}
+
 // need to write compact - if u32 is <= u8 -> write as single byte
 // to determine amount of bits needed we can use (log2(value)+1)
 // idea is to provide byte size as u8 len val [LEN:u8][VAL u8 | VAL u16 | VAL u32]
@@ -81,12 +120,22 @@ impl Encode for String {
     fn encode<W: Write>(&self, w: &mut W) -> VMResult<()> {
         let bytes = self.as_bytes();
         let len = bytes.len();
-        if len > u16::MAX as usize {
+        if len > BYTES_LIMIT {
             return Err(VMError::StorageSizeTooBig);
         }
-        // directly encoding len as u16
-        w.write_all(&(len as u16).to_le_bytes())
-            .map_err(|_| VMError::StorageWriteError)?;
+        // directly encoding len as u16, long strings are escaped
+        match u16::try_from(len) {
+            Ok(short) if short != LONG_LEN => {
+                w.write_all(&short.to_le_bytes())
+                    .map_err(|_| VMError::StorageWriteError)?;
+            }
+            _ => {
+                w.write_all(&LONG_LEN.to_le_bytes())
+                    .map_err(|_| VMError::StorageWriteError)?;
+                w.write_all(&(len as u32).to_le_bytes())
+                    .map_err(|_| VMError::StorageWriteError)?;
+            }
+        }
         w.write_all(bytes).map_err(|_| VMError::StorageWriteError)?;
         Ok(())
     }
@@ -106,17 +155,15 @@ impl Encode for Vec<Task> {
     }
 }
 
+// bytes are written as is after len
 impl Encode for Vec<u8> {
     fn encode<W: Write>(&self, w: &mut W) -> VMResult<()> {
         let len = self.len();
-        if len > VEC_LIMIT {
+        if len > BYTES_LIMIT {
             return Err(VMError::StorageSizeTooBig);
         }
         (len as u32).encode(w)?;
-        for item in self {
-            item.encode(w)?;
-        }
-        Ok(())
+        w.write_all(self).map_err(|_| VMError::StorageWriteError)
     }
 }
 
@@ -138,10 +185,62 @@ impl Encode for Task {
     }
 }
 
+const GLOBAL_NULL: u8 = 0;
+const GLOBAL_U32: u8 = 1;
+const GLOBAL_BOOL: u8 = 2;
+const GLOBAL_TASK_REF: u8 = 3;
+const GLOBAL_STRING: u8 = 4;
+const GLOBAL_VEC_U32: u8 = 5;
+
+impl Encode for Global {
+    fn encode<W: Write>(&self, w: &mut W) -> VMResult<()> {
+        match self {
+            Global::Null => GLOBAL_NULL.encode(w),
+            Global::U32(v) => {
+                GLOBAL_U32.encode(w)?;
+                v.encode(w)
+            }
+            Global::Bool(b) => {
+                GLOBAL_BOOL.encode(w)?;
+                (*b as u8).encode(w)
+            }
+            Global::TaskRef(id) => {
+                GLOBAL_TASK_REF.encode(w)?;
+                id.encode(w)
+            }
+            Global::String(s) => {
+                GLOBAL_STRING.encode(w)?;
+                s.encode(w)
+            }
+            // elements as is: [len][u32 le]..
+            Global::VecU32(v) => {
+                GLOBAL_VEC_U32.encode(w)?;
+                let bytes: Vec<u8> = v.iter().flat_map(|e| e.to_le_bytes()).collect();
+                bytes.encode(w)
+            }
+        }
+    }
+}
+
+impl Encode for Vec<Global> {
+    fn encode<W: Write>(&self, w: &mut W) -> VMResult<()> {
+        let len = self.len();
+        if len > GLOBALS_LIMIT as usize {
+            return Err(VMError::StorageSizeTooBig);
+        }
+        (len as u32).encode(w)?;
+        for item in self {
+            item.encode(w)?;
+        }
+        Ok(())
+    }
+}
+
 impl Encode for StorageData {
     fn encode<W: Write>(&self, w: &mut W) -> VMResult<()> {
         self.tasks.encode(w)?;
         self.next_id.encode(w)?;
+        self.globals.encode(w)?;
         Ok(())
     }
 }
@@ -163,6 +262,33 @@ impl Decode for u8 {
     }
 }
 
+impl Decode for u64 {
+    fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
+        let mut buf = [0u8; 8];
+        r.read_exact(&mut buf)
+            .map_err(|_| VMError::StorageReadError)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+impl Decode for (u64, u64, u64) {
+    fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
+        Ok((u64::decode(r)?, u64::decode(r)?, u64::decode(r)?))
+    }
+}
+
+// len is checked against capacity, overflow is StackOverflow
+impl<T: Decode + Default + Copy, const C: usize> Decode for InlineVec<T, C> {
+    fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
+        let len = u32::decode(r)?;
+        let mut buf = InlineVec::new();
+        for _ in 0..len {
+            buf.push(T::decode(r)?)?;
+        }
+        Ok(buf)
+    }
+}
+
 impl Decode for u32 {
     fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
         let mut bytes = [0u8; 1];
@@ -199,7 +325,16 @@ impl Decode for String {
         let mut len_bytes = [0u8; 2];
         r.read_exact(&mut len_bytes)
             .map_err(|_| VMError::StorageReadError)?;
-        let len = u16::from_le_bytes(len_bytes) as usize;
+        let mut len = u16::from_le_bytes(len_bytes) as usize;
+        if len == LONG_LEN as usize {
+            let mut long_len_bytes = [0u8; 4];
+            r.read_exact(&mut long_len_bytes)
+                .map_err(|_| VMError::StorageReadError)?;
+            len = u32::from_le_bytes(long_len_bytes) as usize;
+            if len > BYTES_LIMIT {
+                return Err(VMError::StorageSizeTooBig);
+            }
+        }
 
         let mut buf = vec![0u8; len];
         r.read_exact(&mut buf)
@@ -208,12 +343,18 @@ impl Decode for String {
     }
 }
 
+// len of encoded Vec<Task>, fsck decodes tasks one by one after it
+pub(crate) fn task_count<R: Read>(r: &mut R) -> VMResult<usize> {
+    let len = u32::decode(r)? as usize;
+    if len > VEC_LIMIT {
+        return Err(VMError::StorageSizeTooBig);
+    }
+    Ok(len)
+}
+
 impl Decode for Vec<Task> {
     fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
-        let len = u32::decode(r)? as usize;
-        if len > VEC_LIMIT {
-            return Err(VMError::StorageSizeTooBig);
-        }
+        let len = task_count(r)?;
         let mut buf = Vec::with_capacity(len);
         for _ in 0..len {
             buf.push(Task::decode(r)?);
@@ -225,13 +366,12 @@ impl Decode for Vec<Task> {
 impl Decode for Vec<u8> {
     fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
         let len = u32::decode(r)? as usize;
-        if len > VEC_LIMIT {
+        if len > BYTES_LIMIT {
             return Err(VMError::StorageSizeTooBig);
         }
-        let mut buf = Vec::with_capacity(len);
-        for _ in 0..len {
-            buf.push(u8::decode(r)?);
-        }
+        let mut buf = vec![0u8; len];
+        r.read_exact(&mut buf)
+            .map_err(|_| VMError::StorageReadError)?;
         Ok(buf)
     }
 }
@@ -256,11 +396,264 @@ impl Decode for Task {
     }
 }
 
+impl Decode for Global {
+    fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
+        match u8::decode(r)? {
+            GLOBAL_NULL => Ok(Global::Null),
+            GLOBAL_U32 => Ok(Global::U32(u32::decode(r)?)),
+            GLOBAL_BOOL => Ok(Global::Bool(u8::decode(r)? != 0)),
+            GLOBAL_TASK_REF => Ok(Global::TaskRef(u32::decode(r)?)),
+            GLOBAL_STRING => Ok(Global::String(String::decode(r)?)),
+            GLOBAL_VEC_U32 => {
+                let bytes = Vec::<u8>::decode(r)?;
+                if !bytes.len().is_multiple_of(4) {
+                    return Err(VMError::StorageReadError);
+                }
+                Ok(Global::VecU32(
+                    bytes
+                        .chunks_exact(4)
+                        .map(|e| u32::from_le_bytes([e[0], e[1], e[2], e[3]]))
+                        .collect(),
+                ))
+            }
+            _ => Err(VMError::StorageReadError),
+        }
+    }
+}
+
+impl Decode for Vec<Global> {
+    fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
+        let len = u32::decode(r)? as usize;
+        if len > GLOBALS_LIMIT as usize {
+            return Err(VMError::StorageSizeTooBig);
+        }
+        let mut buf = Vec::with_capacity(len);
+        for _ in 0..len {
+            buf.push(Global::decode(r)?);
+        }
+        Ok(buf)
+    }
+}
+
 impl Decode for StorageData {
     fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
         Ok(StorageData {
             tasks: Vec::decode(r)?,
             next_id: u32::decode(r)?,
+            globals: decode_globals_section(r)?,
         })
     }
 }
+
+// optional globals section, EOF means file has no globals
+fn decode_globals_section<R: Read>(r: &mut R) -> VMResult<Vec<Global>> {
+    let mut first = [0u8; 1];
+    match r.read(&mut first) {
+        Ok(0) => Ok(Vec::new()),
+        Ok(_) => Vec::decode(&mut first.chain(r)),
+        Err(_) => Err(VMError::StorageReadError),
+    }
+}
+
+// version 0 task: title len is plain u16 (65535 is not the long string escape there),
+// instructions are returned with big-endian operands, see legacy::to_le
+pub(crate) fn decode_v0_task<R: Read>(r: &mut R) -> VMResult<Task> {
+    let id = u32::decode(r)?;
+    let mut len_bytes = [0u8; 2];
+    r.read_exact(&mut len_bytes)
+        .map_err(|_| VMError::StorageReadError)?;
+    let mut title = vec![0u8; u16::from_le_bytes(len_bytes) as usize];
+    r.read_exact(&mut title)
+        .map_err(|_| VMError::StorageReadError)?;
+    Ok(Task {
+        id,
+        title: String::from_utf8(title).map_err(|_| VMError::StorageUTF8ConversionFailed)?,
+        state: TaskState::decode(r)?,
+        instructions: Vec::decode(r)?,
+    })
+}
+
+//
+// STORAGE FILE
+//
+// [magic "SPDO"][version: u16][flags: u16][payload]
+// versions 2 and 3 are indexed, tasks are decoded on demand, 3 adds code section, see snapshot.rs
+// version 1: [header][StorageData][crc32: u32 of everything before]
+// files without header (saved before versioning) are version 0: StorageData only,
+// task instructions have big-endian operands (see bytecode/legacy.rs)
+// layout change -> bump STORAGE_VERSION and add upgrade of previous version to migrate()
+
+const STORAGE_MAGIC: [u8; 4] = *b"SPDO";
+pub(crate) const STORAGE_VERSION: u16 = 3;
+// first indexed version, indexed versions are read by Snapshot
+pub(crate) const INDEXED_VERSION: u16 = 2;
+// no flags defined yet, unknown flags are rejected
+const STORAGE_FLAGS: u16 = 0;
+const HEADER_LEN: usize = 8;
+const CRC_LEN: usize = 4;
+
+pub(crate) fn write_storage_header(bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(&STORAGE_MAGIC);
+    bytes.extend_from_slice(&STORAGE_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&STORAGE_FLAGS.to_le_bytes());
+}
+
+// version of storage file, 0 for legacy file without header
+pub(crate) fn storage_version(bytes: &[u8]) -> VMResult<u16> {
+    // legacy file starts with compact u32 marker (1, 2 or 4), never with magic
+    if !bytes.starts_with(&STORAGE_MAGIC) {
+        return Ok(0);
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(VMError::StorageReadError);
+    }
+    let flags = u16::from_le_bytes([bytes[6], bytes[7]]);
+    if flags != STORAGE_FLAGS {
+        return Err(VMError::UnsupportedStorageFlags(flags));
+    }
+    Ok(u16::from_le_bytes([bytes[4], bytes[5]]))
+}
+
+// storage compacts through SnapshotWriter, whole StorageData is encoded only in tests
+#[cfg(test)]
+pub(crate) fn encode_storage(data: &StorageData) -> VMResult<Vec<u8>> {
+    let mut writer = crate::storage::snapshot::SnapshotWriter::default();
+    for task in &data.tasks {
+        writer.push_task(task)?;
+    }
+    writer.finish(data.next_id, &data.globals)
+}
+
+// decodes all tasks of storage file of any version
+pub(crate) fn decode_storage(bytes: &[u8]) -> VMResult<StorageData> {
+    match storage_version(bytes)? {
+        INDEXED_VERSION..=STORAGE_VERSION => {
+            let (snapshot, next_id, globals) = Snapshot::decode(bytes.to_vec())?;
+            snapshot.data(next_id, globals)
+        }
+        0 => migrate(0, bytes),
+        1 => {
+            if bytes.len() < HEADER_LEN + CRC_LEN {
+                return Err(VMError::StorageReadError);
+            }
+            let (content, crc) = bytes.split_at(bytes.len() - CRC_LEN);
+            if crc32(content) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+                return Err(VMError::StorageChecksumMismatch);
+            }
+            migrate(1, &content[HEADER_LEN..])
+        }
+        v => Err(VMError::UnsupportedStorageVersion(v)),
+    }
+}
+
+// migration hook: decodes payload of version before indexed one and upgrades it to StorageData
+fn migrate(version: u16, payload: &[u8]) -> VMResult<StorageData> {
+    let mut r = payload;
+    let data = match version {
+        // version 0 instructions have big-endian operands
+        0 => {
+            let count = task_count(&mut r)?;
+            let mut tasks = Vec::with_capacity(count);
+            for _ in 0..count {
+                let mut task = decode_v0_task(&mut r)?;
+                task.instructions = legacy::to_le(&task.instructions)?;
+                tasks.push(task);
+            }
+            StorageData {
+                tasks,
+                next_id: u32::decode(&mut r)?,
+                globals: decode_globals_section(&mut r)?,
+            }
+        }
+        // version 1 added header and checksum to little-endian payload
+        1 => StorageData::decode(&mut r)?,
+        v => return Err(VMError::UnsupportedStorageVersion(v)),
+    };
+    if !r.is_empty() {
+        return Err(VMError::StorageReadError);
+    }
+    // storage places tasks by id
+    if data.tasks.iter().any(|task| task.id >= TASK_ID_LIMIT) {
+        return Err(VMError::StorageCorrupted);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data() -> StorageData {
+        StorageData {
+            tasks: Vec::new(),
+            next_id: 3,
+            globals: vec![Global::U32(7)],
+        }
+    }
+
+    // re-signs bytes after header is patched
+    fn resign(bytes: &mut Vec<u8>) {
+        bytes.truncate(bytes.len() - CRC_LEN);
+        let crc = crc32(bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn test_storage_roundtrip() {
+        let bytes = encode_storage(&data()).unwrap();
+        assert_eq!(&bytes[..HEADER_LEN], b"SPDO\x03\x00\x00\x00");
+        let decoded = decode_storage(&bytes).unwrap();
+        assert_eq!(decoded.next_id, 3);
+        assert_eq!(decoded.globals, vec![Global::U32(7)]);
+    }
+
+    #[test]
+    fn test_v0_title_of_u16_max_len() {
+        // baseline wrote 65535 bytes long title with plain u16 len
+        let mut bytes = vec![0x01, 0x01, 0x01, 0x00, 0xff, 0xff];
+        bytes.extend_from_slice(&[b'a'; 65535]);
+        bytes.extend_from_slice(&[0x03, 0x00, 0x01, 0x00, 0x01, 0x01]);
+        let decoded = decode_storage(&bytes).unwrap();
+        assert_eq!(decoded.tasks[0].title.len(), 65535);
+        assert_eq!(decoded.next_id, 1);
+
+        // same title is escaped by current encoding and read back
+        let encoded = encode_storage(&decoded).unwrap();
+        assert_eq!(decode_storage(&encoded).unwrap().tasks, decoded.tasks);
+    }
+
+    #[test]
+    fn test_storage_unknown_version() {
+        let mut bytes = encode_storage(&data()).unwrap();
+        bytes[4] = 9;
+        resign(&mut bytes);
+        assert!(matches!(
+            decode_storage(&bytes),
+            Err(VMError::UnsupportedStorageVersion(9))
+        ));
+    }
+
+    #[test]
+    fn test_storage_unknown_flags() {
+        let mut bytes = encode_storage(&data()).unwrap();
+        bytes[6] = 1;
+        resign(&mut bytes);
+        assert!(matches!(
+            decode_storage(&bytes),
+            Err(VMError::UnsupportedStorageFlags(1))
+        ));
+    }
+
+    #[test]
+    fn test_storage_truncated() {
+        let bytes = encode_storage(&data()).unwrap();
+        assert!(matches!(
+            decode_storage(&bytes[..bytes.len() - 1]),
+            Err(VMError::StorageChecksumMismatch)
+        ));
+        assert!(matches!(
+            decode_storage(&bytes[..6]),
+            Err(VMError::StorageReadError)
+        ));
+    }
+}
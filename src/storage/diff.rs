@@ -0,0 +1,391 @@
+/*
+ * Differences of two states of task store, field by field, and three-way merge of stores
+ *
+ * VM::diff of forked VM lists changes made by the fork since it was forked (see storage.rs),
+ * commit() applies them to the parent.
+ * StorageDiff::between compares two whole stores (e.g. staging and production tasks.bin).
+ * StorageMerge::three_way applies changes of ours and theirs made since common base:
+ * side which left task, field or global as in base takes the other side's value,
+ * different changes of the same field are conflicts and keep ours.
+ */
+
+use crate::storage::globals::Global;
+use crate::storage::journal::Record;
+use crate::storage::task_types::{StorageData, Task, TaskField, TaskState};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Changes of task store, tasks and globals in ascending id (index) order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StorageDiff {
+    pub created: Vec<Task>,
+    pub deleted: Vec<Task>,
+    pub changed: Vec<TaskChange>,
+    pub globals: Vec<GlobalChange>,
+}
+
+/// Changed fields of task present in both states.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskChange {
+    pub id: u32,
+    pub fields: Vec<FieldChange>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldChange {
+    Title { from: String, to: String },
+    State { from: TaskState, to: TaskState },
+    Instructions { from: Vec<u8>, to: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalChange {
+    pub index: u32,
+    pub from: Global,
+    pub to: Global,
+}
+
+impl StorageDiff {
+    /// Changes turning `from` store into `to` store.
+    pub fn between(from: &StorageData, to: &StorageData) -> Self {
+        let from_tasks = by_id(from);
+        let to_tasks = by_id(to);
+        let ids: BTreeSet<u32> = from_tasks.keys().chain(to_tasks.keys()).copied().collect();
+        let mut diff = Self::default();
+        for id in ids {
+            diff.push_task(
+                from_tasks.get(&id).map(|&task| task.clone()),
+                to_tasks.get(&id).map(|&task| task.clone()),
+            );
+        }
+        for index in 0..from.globals.len().max(to.globals.len()) {
+            diff.push_global(
+                index as u32,
+                global(from, index).clone(),
+                global(to, index).clone(),
+            );
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty()
+            && self.deleted.is_empty()
+            && self.changed.is_empty()
+            && self.globals.is_empty()
+    }
+
+    // adds change of task slot, nothing if it is the same in both states
+    pub(crate) fn push_task(&mut self, from: Option<Task>, to: Option<Task>) {
+        match (from, to) {
+            (None, Some(task)) => self.created.push(task),
+            (Some(task), None) => self.deleted.push(task),
+            (Some(from), Some(to)) => {
+                let mut fields = Vec::new();
+                if from.title != to.title {
+                    fields.push(FieldChange::Title {
+                        from: from.title,
+                        to: to.title,
+                    });
+                }
+                if from.state != to.state {
+                    fields.push(FieldChange::State {
+                        from: from.state,
+                        to: to.state,
+                    });
+                }
+                if from.instructions != to.instructions {
+                    fields.push(FieldChange::Instructions {
+                        from: from.instructions,
+                        to: to.instructions,
+                    });
+                }
+                if !fields.is_empty() {
+                    self.changed.push(TaskChange { id: to.id, fields });
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    pub(crate) fn push_global(&mut self, index: u32, from: Global, to: Global) {
+        if from != to {
+            self.globals.push(GlobalChange { index, from, to });
+        }
+    }
+
+    // records turning "from" state into "to" state
+    pub(crate) fn into_records(self) -> Vec<Record> {
+        let mut records: Vec<Record> = self
+            .deleted
+            .into_iter()
+            .map(|task| Record::Delete(task.id))
+            .collect();
+        records.extend(self.created.into_iter().map(Record::Create));
+        for TaskChange { id, fields } in self.changed {
+            records.extend(fields.into_iter().map(|field| match field {
+                FieldChange::Title { to, .. } => Record::SetTitle(id, to),
+                FieldChange::State { to, .. } => Record::SetState(id, to),
+                FieldChange::Instructions { to, .. } => Record::SetInstructions(id, to),
+            }));
+        }
+        records.extend(
+            self.globals
+                .into_iter()
+                .map(|change| Record::SetGlobal(change.index, change.to)),
+        );
+        records
+    }
+}
+
+/// Result of three-way merge, conflicting tasks, fields and globals keep ours.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageMerge {
+    pub merged: StorageData,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MergeConflict {
+    /// Both sides changed the field to different values.
+    Field { id: u32, field: TaskField },
+    /// Both sides created different tasks with the same id.
+    Created { id: u32 },
+    /// One side deleted task the other one changed, changed task is kept.
+    Deleted { id: u32 },
+    /// Both sides set the global to different values.
+    Global { index: u32 },
+}
+
+impl StorageMerge {
+    /// Merges changes of `ours` and `theirs` made since `base`.
+    pub fn three_way(base: &StorageData, ours: &StorageData, theirs: &StorageData) -> Self {
+        let (base_tasks, our_tasks, their_tasks) = (by_id(base), by_id(ours), by_id(theirs));
+        let ids: BTreeSet<u32> = base_tasks
+            .keys()
+            .chain(our_tasks.keys())
+            .chain(their_tasks.keys())
+            .copied()
+            .collect();
+        let mut conflicts = Vec::new();
+        let mut tasks = Vec::new();
+        for id in ids {
+            let (b, o, t) = (
+                base_tasks.get(&id).copied(),
+                our_tasks.get(&id).copied(),
+                their_tasks.get(&id).copied(),
+            );
+            let task = match (merge3(b, o, t), b, t) {
+                (Ok(task), ..) => task.cloned(),
+                (Err(Some(o)), Some(b), Some(t)) => Some(merge_fields(b, o, t, &mut conflicts)),
+                (Err(o), None, _) => {
+                    conflicts.push(MergeConflict::Created { id });
+                    o.cloned()
+                }
+                (Err(o), Some(_), t) => {
+                    conflicts.push(MergeConflict::Deleted { id });
+                    o.or(t).cloned()
+                }
+            };
+            tasks.extend(task);
+        }
+
+        let len = base
+            .globals
+            .len()
+            .max(ours.globals.len())
+            .max(theirs.globals.len());
+        let mut globals = Vec::with_capacity(len);
+        for index in 0..len {
+            let merged = merge3(
+                global(base, index),
+                global(ours, index),
+                global(theirs, index),
+            );
+            globals.push(
+                merged
+                    .unwrap_or_else(|ours| {
+                        conflicts.push(MergeConflict::Global {
+                            index: index as u32,
+                        });
+                        ours
+                    })
+                    .clone(),
+            );
+        }
+
+        let last_id = tasks
+            .last()
+            .map_or(0, |task: &Task| task.id.saturating_add(1));
+        let next_id = base
+            .next_id
+            .max(ours.next_id)
+            .max(theirs.next_id)
+            .max(last_id);
+        Self {
+            merged: StorageData {
+                tasks,
+                next_id,
+                globals,
+            },
+            conflicts,
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+// task changed differently by both sides, merged field by field
+fn merge_fields(
+    base: &Task,
+    ours: &Task,
+    theirs: &Task,
+    conflicts: &mut Vec<MergeConflict>,
+) -> Task {
+    let id = ours.id;
+    Task {
+        id,
+        title: merge_field(
+            id,
+            TaskField::Title,
+            [&base.title, &ours.title, &theirs.title],
+            conflicts,
+        )
+        .clone(),
+        state: *merge_field(
+            id,
+            TaskField::State,
+            [&base.state, &ours.state, &theirs.state],
+            conflicts,
+        ),
+        instructions: merge_field(
+            id,
+            TaskField::Instructions,
+            [&base.instructions, &ours.instructions, &theirs.instructions],
+            conflicts,
+        )
+        .clone(),
+    }
+}
+
+// [base, ours, theirs]
+fn merge_field<'a, T: PartialEq>(
+    id: u32,
+    field: TaskField,
+    [base, ours, theirs]: [&'a T; 3],
+    conflicts: &mut Vec<MergeConflict>,
+) -> &'a T {
+    merge3(base, ours, theirs).unwrap_or_else(|ours| {
+        conflicts.push(MergeConflict::Field { id, field });
+        ours
+    })
+}
+
+// value of side which changed it, Err(ours) if both changed it differently
+fn merge3<T: PartialEq>(base: T, ours: T, theirs: T) -> Result<T, T> {
+    if ours == theirs || theirs == base {
+        Ok(ours)
+    } else if ours == base {
+        Ok(theirs)
+    } else {
+        Err(ours)
+    }
+}
+
+fn by_id(data: &StorageData) -> BTreeMap<u32, &Task> {
+    data.tasks.iter().map(|task| (task.id, task)).collect()
+}
+
+fn global(data: &StorageData, index: usize) -> &Global {
+    const NULL: &Global = &Global::Null;
+    data.globals.get(index).unwrap_or(NULL)
+}
+
+impl fmt::Display for StorageDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for task in &self.created {
+            writeln!(
+                f,
+                "+ task {}: {:?} state {}",
+                task.id,
+                task.title,
+                state(task.state)
+            )?;
+            writeln!(f, "    instructions: {}", instructions(&task.instructions))?;
+        }
+        for task in &self.deleted {
+            writeln!(f, "- task {}: {:?}", task.id, task.title)?;
+        }
+        for change in &self.changed {
+            writeln!(f, "~ task {}", change.id)?;
+            for field in &change.fields {
+                writeln!(f, "    {field}")?;
+            }
+        }
+        for change in &self.globals {
+            writeln!(
+                f,
+                "~ global {}: {:?} -> {:?}",
+                change.index, change.from, change.to
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldChange::Title { from, to } => write!(f, "title: {from:?} -> {to:?}"),
+            FieldChange::State { from, to } => {
+                write!(f, "state: {} -> {}", state(*from), state(*to))
+            }
+            FieldChange::Instructions { from, to } => write!(
+                f,
+                "instructions: {} -> {}",
+                instructions(from),
+                instructions(to)
+            ),
+        }
+    }
+}
+
+impl fmt::Display for MergeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeConflict::Field { id, field } => {
+                write!(f, "task {id}: {field:?} changed by both sides, ours kept")
+            }
+            MergeConflict::Created { id } => {
+                write!(f, "task {id}: created by both sides, ours kept")
+            }
+            MergeConflict::Deleted { id } => {
+                write!(
+                    f,
+                    "task {id}: deleted by one side and changed by other, changed kept"
+                )
+            }
+            MergeConflict::Global { index } => {
+                write!(f, "global {index}: set by both sides, ours kept")
+            }
+        }
+    }
+}
+
+fn state(state: TaskState) -> String {
+    format!("{}/{}", state.state, state.len)
+}
+
+// disassembled if possible, same as calldata in dot: [ PUSH_U32 1 ]
+fn instructions(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "[ ]".to_string();
+    }
+    #[cfg(feature = "dot")]
+    if let Ok(dot) = crate::dot::bin2dot::bin2dot(bytes) {
+        return format!("[ {dot} ]");
+    }
+    format!("{bytes:02x?}")
+}
@@ -0,0 +1,425 @@
+/*
+ * fsck - integrity check and recovery of stored tasks
+ *
+ * Storage::load rejects whole store when header checksum or layout is broken, so application
+ * can't start. Check decodes as much as possible instead: every task record separately,
+ * journal on top of salvaged tasks, then reports what is wrong with the result:
+ * duplicate ids, ids >= next_id, states >= len, instructions failing verification,
+ * references (globals, PUSH_U32 <id> CALL/T_REF) to tasks which do not exist.
+ * Repair writes salvaged tasks as a new snapshot (journal is merged into it):
+ * lost records stay lost (so do tasks with id past TASK_ID_LIMIT), invalid state is reset to 0,
+ * invalid instructions are cleared, next_id is raised above every salvaged id.
+ * References are only reported.
+ * Version 0 instructions have big-endian operands, they are converted before verification,
+ * repair of version 0 store is refused if instructions of a task can't be converted.
+ */
+
+use crate::bytecode::legacy;
+use crate::bytecode::verify::verify;
+use crate::errors::{VMError, VMResult};
+use crate::storage::backend::StorageBackend;
+use crate::storage::bincodec::{
+    Decode, INDEXED_VERSION, STORAGE_VERSION, decode_v0_task, storage_version, task_count,
+};
+use crate::storage::globals::{GLOBALS_LIMIT, Global};
+use crate::storage::journal::{self, Record};
+use crate::storage::snapshot::{Snapshot, SnapshotWriter};
+use crate::storage::storage::snapshot_crc;
+use crate::storage::task_types::{StorageData, TASK_ID_LIMIT, Task, TaskState};
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::fmt;
+
+/// Problem found by `VM::fsck`.
+#[derive(Debug)]
+pub enum FsckIssue {
+    /// Part of the file could not be decoded, tasks stored after it are lost.
+    Unreadable(VMError),
+    /// Header (next id, globals, index) does not match its checksum.
+    HeaderChecksumMismatch,
+    /// Task record could not be decoded, the task is lost.
+    CorruptRecord {
+        id: u32,
+        error: VMError,
+    },
+    /// Task id is stored more than once, first decodable record is kept.
+    DuplicateId(u32),
+    IdBeyondNextId {
+        id: u32,
+        next_id: u32,
+    },
+    InvalidState {
+        id: u32,
+        state: u8,
+        len: u8,
+    },
+    InvalidInstructions {
+        id: u32,
+        error: VMError,
+    },
+    /// Big-endian instructions of version 0 store can't be converted, repair is refused.
+    UnconvertibleInstructions {
+        id: u32,
+        error: VMError,
+    },
+    /// Global slot refers to task which does not exist.
+    DanglingGlobalRef {
+        index: u32,
+        id: u32,
+    },
+    /// Instructions of task refer to task which does not exist.
+    DanglingTaskRef {
+        id: u32,
+        target: u32,
+    },
+    /// Journal is torn or belongs to other snapshot, only first records are applied.
+    JournalTruncated {
+        applied: usize,
+    },
+}
+
+impl fmt::Display for FsckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsckIssue::Unreadable(error) => write!(f, "unreadable: {error:?}"),
+            FsckIssue::HeaderChecksumMismatch => write!(f, "header checksum mismatch"),
+            FsckIssue::CorruptRecord { id, error } => {
+                write!(f, "task {id}: corrupt record, lost: {error:?}")
+            }
+            FsckIssue::DuplicateId(id) => write!(f, "task {id}: duplicate id"),
+            FsckIssue::IdBeyondNextId { id, next_id } => {
+                write!(f, "task {id}: id >= next id {next_id}")
+            }
+            FsckIssue::InvalidState { id, state, len } => {
+                write!(f, "task {id}: state {state} >= states len {len}")
+            }
+            FsckIssue::InvalidInstructions { id, error } => {
+                write!(f, "task {id}: invalid instructions: {error:?}")
+            }
+            FsckIssue::UnconvertibleInstructions { id, error } => {
+                write!(
+                    f,
+                    "task {id}: legacy instructions can't be converted: {error:?}"
+                )
+            }
+            FsckIssue::DanglingGlobalRef { index, id } => {
+                write!(f, "global {index}: refers to missing task {id}")
+            }
+            FsckIssue::DanglingTaskRef { id, target } => {
+                write!(f, "task {id}: instructions refer to missing task {target}")
+            }
+            FsckIssue::JournalTruncated { applied } => {
+                write!(f, "journal torn or stale, {applied} records applied")
+            }
+        }
+    }
+}
+
+/// Result of `VM::fsck`: issues found and salvaged store, written by `repair`.
+#[derive(Debug)]
+pub struct FsckReport {
+    /// Storage file version, None if nothing was saved yet.
+    pub version: Option<u16>,
+    /// Count of salvaged tasks.
+    pub tasks: usize,
+    pub issues: Vec<FsckIssue>,
+    repaired: StorageData,
+    // tasks of version 0 store whose instructions are still big-endian
+    unconverted: Vec<u32>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Saves salvaged tasks and globals to backend, replacing stored snapshot and journal.
+    /// Fails with `UnconvertibleInstructions` without saving if instructions of version 0 store
+    /// could not be converted, they would be lost otherwise.
+    pub fn repair<B: StorageBackend>(&self, backend: &mut B) -> VMResult<()> {
+        if let Some(&id) = self.unconverted.first() {
+            return Err(VMError::UnconvertibleInstructions(id));
+        }
+        backend.save(&SnapshotWriter::encode(&self.repaired)?)
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.version {
+            Some(version) => write!(f, "storage version {version}")?,
+            None => write!(f, "storage is empty")?,
+        }
+        writeln!(
+            f,
+            ", {} tasks salvaged, {} issues",
+            self.tasks,
+            self.issues.len()
+        )?;
+        for issue in &self.issues {
+            writeln!(f, "{issue}")?;
+        }
+        Ok(())
+    }
+}
+
+// store being salvaged
+#[derive(Default)]
+struct Salvage {
+    tasks: BTreeMap<u32, Task>,
+    next_id: u32,
+    globals: Vec<Global>,
+    issues: Vec<FsckIssue>,
+    // identifies snapshot for journal
+    base_crc: Option<u32>,
+    unconverted: Vec<u32>,
+}
+
+pub(crate) fn check(backend: &mut dyn StorageBackend) -> VMResult<FsckReport> {
+    let bytes = backend.load()?;
+    let journal = backend.load_journal()?;
+    let mut salvage = Salvage::default();
+    let version = bytes.map(|bytes| salvage.snapshot(bytes));
+    if let Some(bytes) = journal {
+        salvage.journal(&bytes);
+    }
+    salvage.check();
+    let Salvage {
+        tasks,
+        next_id,
+        globals,
+        issues,
+        unconverted,
+        ..
+    } = salvage;
+    Ok(FsckReport {
+        version,
+        tasks: tasks.len(),
+        issues,
+        repaired: StorageData {
+            tasks: tasks.into_values().collect(),
+            next_id,
+            globals,
+        },
+        unconverted,
+    })
+}
+
+impl Salvage {
+    // returns file version, 0 for legacy file and for file with unreadable header
+    fn snapshot(&mut self, bytes: Vec<u8>) -> u16 {
+        let version = match storage_version(&bytes) {
+            Ok(version) => version,
+            Err(error) => {
+                self.issues.push(FsckIssue::Unreadable(error));
+                return 0;
+            }
+        };
+        match version {
+            INDEXED_VERSION..=STORAGE_VERSION => self.indexed(bytes),
+            0 => self.legacy(&bytes, &bytes, 0),
+            // [header][payload][crc32]
+            1 => match bytes.get(8..bytes.len().saturating_sub(4)) {
+                Some(payload) => {
+                    if snapshot_crc(&bytes) != read_crc(&bytes) {
+                        self.issues.push(FsckIssue::HeaderChecksumMismatch);
+                    }
+                    self.legacy(&bytes, payload, 1)
+                }
+                None => self
+                    .issues
+                    .push(FsckIssue::Unreadable(VMError::StorageReadError)),
+            },
+            v => self
+                .issues
+                .push(FsckIssue::Unreadable(VMError::UnsupportedStorageVersion(v))),
+        }
+        version
+    }
+
+    fn indexed(&mut self, bytes: Vec<u8>) {
+        let (snapshot, scan) = match Snapshot::scan(bytes) {
+            Ok(scanned) => scanned,
+            Err(error) => return self.issues.push(FsckIssue::Unreadable(error)),
+        };
+        if !scan.crc_ok {
+            self.issues.push(FsckIssue::HeaderChecksumMismatch);
+        }
+        self.base_crc = Some(snapshot.crc());
+        self.next_id = scan.next_id;
+        self.globals = scan.globals;
+        for (id, entry) in scan.entries {
+            match snapshot.task_at(id, entry) {
+                Ok(task) => self.push(task),
+                Err(error) => self.issues.push(FsckIssue::CorruptRecord { id, error }),
+            }
+        }
+    }
+
+    // version 0 and 1 payload: [tasks][next_id][globals], tasks are decoded until first broken one
+    fn legacy(&mut self, bytes: &[u8], payload: &[u8], version: u16) {
+        self.base_crc = Some(snapshot_crc(bytes));
+        let mut r = payload;
+        let decoded = task_count(&mut r).and_then(|count| {
+            for _ in 0..count {
+                let task = if version == 0 {
+                    self.convert(decode_v0_task(&mut r)?)
+                } else {
+                    Task::decode(&mut r)?
+                };
+                self.push(task);
+            }
+            let next_id = u32::decode(&mut r)?;
+            let globals = if r.is_empty() {
+                Vec::new()
+            } else {
+                Vec::decode(&mut r)?
+            };
+            Ok((next_id, globals))
+        });
+        match decoded {
+            Ok((next_id, globals)) => {
+                self.next_id = next_id;
+                self.globals = globals;
+            }
+            Err(error) => self.issues.push(FsckIssue::Unreadable(error)),
+        }
+    }
+
+    // big-endian operands of version 0 task to little-endian, unconvertible ones are kept as they are
+    fn convert(&mut self, mut task: Task) -> Task {
+        match legacy::to_le(&task.instructions) {
+            Ok(instructions) => task.instructions = instructions,
+            Err(error) => {
+                self.issues
+                    .push(FsckIssue::UnconvertibleInstructions { id: task.id, error });
+                self.unconverted.push(task.id);
+            }
+        }
+        task
+    }
+
+    fn push(&mut self, task: Task) {
+        match self.tasks.entry(task.id) {
+            Entry::Occupied(_) => self.issues.push(FsckIssue::DuplicateId(task.id)),
+            Entry::Vacant(slot) => {
+                slot.insert(task);
+            }
+        }
+    }
+
+    // replays journal same as Storage::load
+    fn journal(&mut self, bytes: &[u8]) {
+        let (records, complete) = journal::read(bytes, self.base_crc);
+        if !complete {
+            self.issues.push(FsckIssue::JournalTruncated {
+                applied: records.len(),
+            });
+        }
+        for record in records {
+            match record {
+                Record::Create(task) => {
+                    self.next_id = self.next_id.max(task.id.saturating_add(1));
+                    self.tasks.insert(task.id, task);
+                }
+                Record::SetTitle(id, title) => {
+                    if let Some(task) = self.tasks.get_mut(&id) {
+                        task.title = title;
+                    }
+                }
+                Record::SetState(id, state) => {
+                    if let Some(task) = self.tasks.get_mut(&id) {
+                        task.state = state;
+                    }
+                }
+                Record::SetInstructions(id, instructions) => {
+                    if let Some(task) = self.tasks.get_mut(&id) {
+                        task.instructions = instructions;
+                    }
+                }
+                Record::Delete(id) => {
+                    self.tasks.remove(&id);
+                }
+                Record::SetGlobal(index, global) if index < GLOBALS_LIMIT => {
+                    if self.globals.len() <= index as usize {
+                        self.globals.resize(index as usize + 1, Global::Null);
+                    }
+                    self.globals[index as usize] = global;
+                }
+                Record::SetGlobal(..) => {}
+            }
+        }
+    }
+
+    // reports issues of salvaged tasks and fixes them for repair
+    fn check(&mut self) {
+        let mut issues = Vec::new();
+        // store can't place task past id limit, such task is lost
+        for id in self.tasks.split_off(&TASK_ID_LIMIT).into_keys() {
+            issues.push(FsckIssue::CorruptRecord {
+                id,
+                error: VMError::StorageCorrupted,
+            });
+        }
+        let mut next_id = self.next_id;
+        for (&id, task) in &self.tasks {
+            if id >= self.next_id {
+                issues.push(FsckIssue::IdBeyondNextId {
+                    id,
+                    next_id: self.next_id,
+                });
+                next_id = next_id.max(id.saturating_add(1));
+            }
+            let TaskState { len, state } = task.state;
+            if state >= len {
+                issues.push(FsckIssue::InvalidState { id, state, len });
+            }
+            if self.unconverted.contains(&id) {
+                continue;
+            }
+            match verify(&task.instructions) {
+                Ok(refs) => issues.extend(
+                    refs.into_iter()
+                        .filter(|target| !self.tasks.contains_key(target))
+                        .map(|target| FsckIssue::DanglingTaskRef { id, target }),
+                ),
+                Err(error) => issues.push(FsckIssue::InvalidInstructions { id, error }),
+            }
+        }
+        for (index, global) in self.globals.iter().enumerate() {
+            if let Global::TaskRef(id) = global
+                && !self.tasks.contains_key(id)
+            {
+                issues.push(FsckIssue::DanglingGlobalRef {
+                    index: index as u32,
+                    id: *id,
+                });
+            }
+        }
+        for issue in &issues {
+            match issue {
+                FsckIssue::InvalidState { id, len, .. } => {
+                    if let Some(task) = self.tasks.get_mut(id) {
+                        task.state = TaskState {
+                            len: (*len).max(1),
+                            state: 0,
+                        };
+                    }
+                }
+                FsckIssue::InvalidInstructions { id, .. } => {
+                    if let Some(task) = self.tasks.get_mut(id) {
+                        task.instructions.clear();
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.next_id = next_id;
+        self.issues.append(&mut issues);
+    }
+}
+
+fn read_crc(bytes: &[u8]) -> u32 {
+    let at = bytes.len() - 4;
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
@@ -0,0 +1,59 @@
+use crate::errors::{VMError, VMResult};
+use crate::memory::LinearMemory;
+use crate::values::*;
+
+// global slots are indexed 0..GLOBALS_LIMIT, unset slots are Null
+pub(crate) const GLOBALS_LIMIT: u32 = 1024;
+
+/// Value of a global slot, owned by storage and persisted by S_SAVE.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Global {
+    #[default]
+    Null,
+    U32(u32),
+    Bool(bool),
+    TaskRef(u32),
+    String(String),
+    VecU32(Vec<u32>),
+}
+
+impl LinearMemory {
+    // G_GET: strings and vectors are copied into linear memory
+    pub(crate) fn global_val(&mut self, global: &Global) -> VMResult<Value> {
+        match global {
+            Global::Null => Ok(NULL_VAL),
+            Global::U32(v) => Ok(to_u32_val(*v)),
+            Global::Bool(b) => Ok(to_bool_val(*b)),
+            Global::TaskRef(id) => Ok(to_task_ref_val(*id)),
+            Global::String(s) => self.alloc(s.len() as u32, TAG_STRING as u8, s.as_bytes()),
+            Global::VecU32(v) => {
+                let bytes: Vec<u8> = v.iter().flat_map(|e| e.to_le_bytes()).collect();
+                self.alloc(bytes.len() as u32, TAG_U32 as u8, &bytes)
+            }
+        }
+    }
+
+    // G_SET: strings and vectors are copied out of linear memory
+    // calldata, maps and lists can't be stored in globals
+    pub(crate) fn to_global(&self, v: Value) -> VMResult<Global> {
+        match get_value_type(v)? {
+            ValueType::Null => Ok(Global::Null),
+            ValueType::U32 => Ok(Global::U32(to_u32(v))),
+            ValueType::Bool => Ok(Global::Bool(v == TRUE_VAL)),
+            ValueType::TaskRef => Ok(Global::TaskRef(to_u32(v))),
+            ValueType::String => {
+                let (offset, size) = self.fat_pointer(v)?;
+                Ok(Global::String(
+                    self.get_slice_as_str(offset, size)?.to_string(),
+                ))
+            }
+            ValueType::VecU32 => {
+                let (offset, size) = self.fat_pointer(v)?;
+                Ok(Global::VecU32(
+                    self.get_slice_as_u32(offset, size)?.to_vec(),
+                ))
+            }
+            ValueType::CallData | ValueType::Map | ValueType::List => Err(VMError::InvalidType),
+        }
+    }
+}
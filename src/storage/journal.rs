@@ -0,0 +1,168 @@
+/*
+ * Write-ahead journal of storage mutations
+ *
+ * S_SAVE appends mutations made since last save to the journal instead of rewriting every task.
+ * Storage::load replays the journal on top of the snapshot (last full save).
+ * Journal is compacted - whole storage is saved as a new snapshot and journal is dropped -
+ * when it grows bigger than the snapshot, so saving stays O(changes) amortized.
+ *
+ * journal: [magic "SPDJ"][version: u16][checksum of base snapshot: u32][record]..
+ * record:  [len: u32][kind: u8][payload][crc32 of kind and payload: u32]
+ * (fixed size little-endian u32s)
+ *
+ * journal of other snapshot (crash between snapshot rename and journal removal) is ignored,
+ * replay stops at first incomplete or corrupted record (torn append), next save compacts.
+ */
+
+use crate::errors::{VMError, VMResult};
+use crate::storage::bincodec::{Decode, Encode};
+use crate::storage::crc32::crc32;
+use crate::storage::globals::Global;
+use crate::storage::task_types::{Task, TaskField, TaskState};
+use std::io::{Read, Write};
+
+const MAGIC: [u8; 4] = *b"SPDJ";
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 10;
+
+// mutation recorded by storage, turned into record with current values on save
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Mutation {
+    Create(u32),
+    SetField(u32, TaskField),
+    Delete(u32),
+    SetGlobal(u32),
+}
+
+#[derive(Debug)]
+pub(crate) enum Record {
+    Create(Task),
+    SetTitle(u32, String),
+    SetState(u32, TaskState),
+    SetInstructions(u32, Vec<u8>),
+    Delete(u32),
+    SetGlobal(u32, Global),
+}
+
+impl Record {
+    pub(crate) fn mutation(&self) -> Mutation {
+        match self {
+            Record::Create(task) => Mutation::Create(task.id),
+            Record::SetTitle(id, _) => Mutation::SetField(*id, TaskField::Title),
+            Record::SetState(id, _) => Mutation::SetField(*id, TaskField::State),
+            Record::SetInstructions(id, _) => Mutation::SetField(*id, TaskField::Instructions),
+            Record::Delete(id) => Mutation::Delete(*id),
+            Record::SetGlobal(index, _) => Mutation::SetGlobal(*index),
+        }
+    }
+}
+
+const CREATE: u8 = 0;
+const SET_TITLE: u8 = 1;
+const SET_STATE: u8 = 2;
+const SET_INSTRUCTIONS: u8 = 3;
+const DELETE: u8 = 4;
+const SET_GLOBAL: u8 = 5;
+
+impl Encode for Record {
+    fn encode<W: Write>(&self, w: &mut W) -> VMResult<()> {
+        match self {
+            Record::Create(task) => {
+                CREATE.encode(w)?;
+                task.encode(w)
+            }
+            Record::SetTitle(id, title) => {
+                SET_TITLE.encode(w)?;
+                id.encode(w)?;
+                title.encode(w)
+            }
+            Record::SetState(id, state) => {
+                SET_STATE.encode(w)?;
+                id.encode(w)?;
+                state.encode(w)
+            }
+            Record::SetInstructions(id, instructions) => {
+                SET_INSTRUCTIONS.encode(w)?;
+                id.encode(w)?;
+                instructions.encode(w)
+            }
+            Record::Delete(id) => {
+                DELETE.encode(w)?;
+                id.encode(w)
+            }
+            Record::SetGlobal(index, global) => {
+                SET_GLOBAL.encode(w)?;
+                index.encode(w)?;
+                global.encode(w)
+            }
+        }
+    }
+}
+
+impl Decode for Record {
+    fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
+        match u8::decode(r)? {
+            CREATE => Ok(Record::Create(Task::decode(r)?)),
+            SET_TITLE => Ok(Record::SetTitle(u32::decode(r)?, String::decode(r)?)),
+            SET_STATE => Ok(Record::SetState(u32::decode(r)?, TaskState::decode(r)?)),
+            SET_INSTRUCTIONS => Ok(Record::SetInstructions(u32::decode(r)?, Vec::decode(r)?)),
+            DELETE => Ok(Record::Delete(u32::decode(r)?)),
+            SET_GLOBAL => Ok(Record::SetGlobal(u32::decode(r)?, Global::decode(r)?)),
+            _ => Err(VMError::StorageReadError),
+        }
+    }
+}
+
+pub(crate) fn header(base_crc: u32) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN);
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&base_crc.to_le_bytes());
+    bytes
+}
+
+pub(crate) fn append_record(record: &Record, out: &mut Vec<u8>) -> VMResult<()> {
+    let mut body = Vec::new();
+    record.encode(&mut body)?;
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    out.extend_from_slice(&crc32(&body).to_le_bytes());
+    Ok(())
+}
+
+// records of journal written for snapshot with base_crc
+// complete is false if journal is stale or has torn tail, such journal must not be appended to
+pub(crate) fn read(bytes: &[u8], base_crc: Option<u32>) -> (Vec<Record>, bool) {
+    let mut records = Vec::new();
+    if bytes.len() < HEADER_LEN
+        || bytes[..4] != MAGIC
+        || u16::from_le_bytes([bytes[4], bytes[5]]) != VERSION
+        || Some(u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]])) != base_crc
+    {
+        return (records, false);
+    }
+    let mut rest = &bytes[HEADER_LEN..];
+    while !rest.is_empty() {
+        let Some(record) = read_record(&mut rest) else {
+            return (records, false);
+        };
+        records.push(record);
+    }
+    (records, true)
+}
+
+fn read_record(rest: &mut &[u8]) -> Option<Record> {
+    let len = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+    let body = rest.get(4..4 + len)?;
+    let crc = u32::from_le_bytes(rest.get(4 + len..8 + len)?.try_into().ok()?);
+    if crc32(body) != crc {
+        return None;
+    }
+    let mut r = body;
+    let record = Record::decode(&mut r).ok()?;
+    if !r.is_empty() {
+        return None;
+    }
+    *rest = &rest[8 + len..];
+    Some(record)
+}
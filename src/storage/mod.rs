@@ -1,3 +1,11 @@
-mod bincodec;
+pub mod backend;
+pub(crate) mod bincodec;
+mod crc32;
+pub mod diff;
+pub mod fsck;
+pub mod globals;
+mod journal;
+pub mod shared;
+mod snapshot;
 pub mod storage;
 pub mod task_types;
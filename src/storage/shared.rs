@@ -0,0 +1,115 @@
+/*
+ * Storage shared by VMs on different threads
+ *
+ * VM attached to SharedStorage (VM::builder().shared(..)) works on private copy of shared state
+ * taken at the start of a run, so a run never observes changes of concurrent runs (snapshot isolation).
+ * Changes of successful run are published when it ends, S_SAVE publishes them and saves shared state
+ * to backend. Publishing fails with StorageConflict if task or global slot changed by the run was
+ * published by other VM after the copy was taken (first publisher wins), changes of conflicting
+ * or failed run are dropped and next run starts from fresh copy. Publish whose apply or save
+ * fails is rolled back, shared state and its version stay as they were.
+ */
+
+use crate::errors::{VMError, VMResult};
+use crate::pools::InstructionsPool;
+use crate::storage::backend::StorageBackend;
+use crate::storage::journal::{Mutation, Record};
+use crate::storage::storage::Storage;
+use crate::storage::task_types::StorageData;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Handle to storage shared by many VMs, clones refer to the same storage.
+#[derive(Debug, Clone)]
+pub struct SharedStorage {
+    inner: Arc<Mutex<Shared>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    storage: Storage,
+    // instructions of shared tasks
+    pool: InstructionsPool,
+    // bumped by every publish
+    version: u64,
+    // version which last changed task or global slot
+    written: HashMap<Slot, u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Slot {
+    Task(u32),
+    Global(u32),
+}
+
+impl From<Mutation> for Slot {
+    fn from(mutation: Mutation) -> Self {
+        match mutation {
+            Mutation::Create(id) | Mutation::SetField(id, _) | Mutation::Delete(id) => {
+                Slot::Task(id)
+            }
+            Mutation::SetGlobal(index) => Slot::Global(index),
+        }
+    }
+}
+
+impl SharedStorage {
+    /// Loads storage from backend, S_SAVE of attached VMs writes there.
+    pub fn new<B: StorageBackend + 'static>(backend: B) -> VMResult<Self> {
+        let mut pool = InstructionsPool::default();
+        let storage = Storage::load(Box::new(backend), &mut pool)?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Shared {
+                storage,
+                pool,
+                version: 0,
+                written: HashMap::new(),
+            })),
+        })
+    }
+
+    pub(crate) fn version(&self) -> VMResult<u64> {
+        let shared = self.inner.lock().map_err(|_| VMError::StorageReadError)?;
+        Ok(shared.version)
+    }
+
+    pub(crate) fn snapshot(&self) -> VMResult<(StorageData, u64)> {
+        let shared = self.inner.lock().map_err(|_| VMError::StorageReadError)?;
+        Ok((shared.storage.data(&shared.pool)?, shared.version))
+    }
+
+    // applies records of copy taken at base version, returns new version
+    pub(crate) fn publish(&self, records: Vec<Record>, base: u64, save: bool) -> VMResult<u64> {
+        let mut shared = self.inner.lock().map_err(|_| VMError::StorageWriteError)?;
+        let shared = &mut *shared;
+        let conflict = records.iter().any(|record| {
+            let slot = Slot::from(record.mutation());
+            shared.written.get(&slot).is_some_and(|&v| v > base)
+        });
+        if conflict {
+            return Err(VMError::StorageConflict);
+        }
+        // records are applied inside transaction: failed apply or save leaves shared state untouched
+        let slots: Vec<Slot> = records.iter().map(|r| Slot::from(r.mutation())).collect();
+        shared.storage.begin();
+        for record in records {
+            if let Err(e) = shared.storage.apply_logged(record, &mut shared.pool) {
+                shared.storage.rollback_all();
+                return Err(e);
+            }
+        }
+        if save {
+            shared.storage.save(&shared.pool, true)?;
+        }
+        shared.storage.commit(&shared.pool, true)?;
+        if !slots.is_empty() {
+            shared.version += 1;
+            for slot in slots {
+                shared.written.insert(slot, shared.version);
+            }
+            // replaced and deleted task instructions
+            shared.pool.collect(shared.storage.instructions_refs());
+        }
+        Ok(shared.version)
+    }
+}
@@ -0,0 +1,428 @@
+/*
+ * Indexed snapshot - storage file version 3 (version 2 is still read)
+ *
+ * Every task is a separate record located by index, so opening storage decodes the index only:
+ * Storage decodes a task on first access and interns its instructions only when they are needed
+ * (CALL, T_GET_FIELD), see storage.rs. Only decoding is lazy, backend still returns whole file
+ * and snapshot keeps its bytes in memory.
+ * Instructions live in content-addressed code section: identical instructions of many tasks
+ * are stored once and task records refer to them by code index.
+ *
+ * [magic "SPDO"][version: u16][flags: u16][next_id][globals]
+ * [index len: u32][(id: u32, offset: u32, len: u32, crc32 of record: u32)..]
+ * [code len: u32][(offset: u32, len: u32, crc32 of code: u32)..]
+ * [crc32 of everything before: u32]
+ * [record]..[code]..
+ * record: [id][title][state][code index: u32]
+ * (index entries are fixed size little-endian u32s, tasks sorted by id, offsets are relative to first record)
+ *
+ * version 2 has no code section, its record is encoded Task with instructions.
+ * header checksum is checked on load, record and code checksums when they are read.
+ * header checksum covers checksums of all records and code, so it identifies snapshot for journal.
+ */
+
+use crate::errors::{VMError, VMResult};
+use crate::pools::instructions::content_hash;
+use crate::storage::bincodec::{Decode, Encode, write_storage_header};
+use crate::storage::crc32::crc32;
+use crate::storage::globals::Global;
+use crate::storage::task_types::{StorageData, TASK_ID_LIMIT, Task, TaskState, TaskVM};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const ENTRY_LEN: usize = 16;
+const CODE_ENTRY_LEN: usize = 12;
+const CRC_LEN: usize = 4;
+// first version with code section
+const CODE_VERSION: u16 = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Entry {
+    offset: u32,
+    len: u32,
+    crc: u32,
+}
+
+// header and index as stored
+#[derive(Debug)]
+pub(crate) struct Scan {
+    pub next_id: u32,
+    pub globals: Vec<Global>,
+    // (task id, record) in file order, may have duplicates
+    pub entries: Vec<(u32, Entry)>,
+    pub crc_ok: bool,
+}
+
+// clones (forked storage) share bytes
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Snapshot {
+    bytes: Arc<[u8]>,
+    version: u16,
+    // offset of first record
+    records: usize,
+    // by task id
+    index: Vec<Option<Entry>>,
+    code: Vec<Entry>,
+    crc: u32,
+}
+
+impl Snapshot {
+    // checks header checksum, returns snapshot with next_id and globals
+    pub(crate) fn decode(bytes: Vec<u8>) -> VMResult<(Self, u32, Vec<Global>)> {
+        let (mut snapshot, scan) = Self::scan(bytes)?;
+        if !scan.crc_ok {
+            return Err(VMError::StorageChecksumMismatch);
+        }
+        for (id, entry) in scan.entries {
+            if id >= scan.next_id.min(TASK_ID_LIMIT) || snapshot.index.len() > id as usize {
+                return Err(VMError::StorageCorrupted);
+            }
+            snapshot.index.resize(id as usize + 1, None);
+            snapshot.index[id as usize] = Some(entry);
+        }
+        Ok((snapshot, scan.next_id, scan.globals))
+    }
+
+    // reads layout without validating it, index of returned snapshot is empty:
+    // fsck reads records by entries of scan, including those decode rejects
+    pub(crate) fn scan(bytes: Vec<u8>) -> VMResult<(Self, Scan)> {
+        let header = bytes.get(..8).ok_or(VMError::StorageReadError)?;
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        let mut r = &bytes[8..];
+        let next_id = u32::decode(&mut r)?;
+        let globals = Vec::decode(&mut r)?;
+        let index_at = bytes.len() - r.len();
+        let index_len = read_u32(&bytes, index_at)? as usize;
+        let code_at = section_end(&bytes, index_at, index_len, ENTRY_LEN)?;
+        let (code_len, crc_at) = if version >= CODE_VERSION {
+            let code_len = read_u32(&bytes, code_at)? as usize;
+            (
+                code_len,
+                section_end(&bytes, code_at, code_len, CODE_ENTRY_LEN)?,
+            )
+        } else {
+            (0, code_at)
+        };
+        let crc = read_u32(&bytes, crc_at)?;
+        let crc_ok = crc32(&bytes[..crc_at]) == crc;
+        let entries = (0..index_len)
+            .map(|i| {
+                let at = index_at + 4 + i * ENTRY_LEN;
+                Ok((read_u32(&bytes, at)?, read_entry(&bytes, at + 4)?))
+            })
+            .collect::<VMResult<Vec<_>>>()?;
+        let code = (0..code_len)
+            .map(|i| read_entry(&bytes, code_at + 4 + i * CODE_ENTRY_LEN))
+            .collect::<VMResult<Vec<_>>>()?;
+        let snapshot = Self {
+            bytes: bytes.into(),
+            version,
+            records: crc_at + CRC_LEN,
+            index: Vec::new(),
+            code,
+            crc,
+        };
+        let scan = Scan {
+            next_id,
+            globals,
+            entries,
+            crc_ok,
+        };
+        Ok((snapshot, scan))
+    }
+
+    // identifies snapshot for journal header
+    pub(crate) fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    // ids of stored tasks in ascending order
+    pub(crate) fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.index
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.is_some())
+            .map(|(id, _)| id as u32)
+    }
+
+    pub(crate) fn task(&self, id: u32) -> VMResult<Task> {
+        let (mut task, code) = self.record(id)?;
+        if let Some(code) = code {
+            task.instructions = self.code(code)?.to_vec();
+        }
+        Ok(task)
+    }
+
+    // task without its instructions, they are read by instructions() when needed
+    pub(crate) fn task_vm(&self, id: u32) -> VMResult<TaskVM> {
+        Ok(TaskVM::stored(self.record(id)?.0))
+    }
+
+    pub(crate) fn instructions(&self, id: u32) -> VMResult<Vec<u8>> {
+        Ok(self.task(id)?.instructions)
+    }
+
+    // task of index entry read by scan
+    pub(crate) fn task_at(&self, id: u32, entry: Entry) -> VMResult<Task> {
+        let (mut task, code) = self.record_at(id, entry)?;
+        if let Some(code) = code {
+            task.instructions = self.code(code)?.to_vec();
+        }
+        Ok(task)
+    }
+
+    fn record(&self, id: u32) -> VMResult<(Task, Option<u32>)> {
+        let entry = self
+            .index
+            .get(id as usize)
+            .copied()
+            .flatten()
+            .ok_or(VMError::TaskNotFound(id))?;
+        self.record_at(id, entry)
+    }
+
+    // task with code index of its instructions, version 2 record carries instructions itself
+    fn record_at(&self, id: u32, entry: Entry) -> VMResult<(Task, Option<u32>)> {
+        let mut r = self.read(entry)?;
+        let (task, code) = if self.version >= CODE_VERSION {
+            let task = Task {
+                id: u32::decode(&mut r)?,
+                title: String::decode(&mut r)?,
+                state: TaskState::decode(&mut r)?,
+                instructions: Vec::new(),
+            };
+            (task, Some(u32::decode(&mut r)?))
+        } else {
+            (Task::decode(&mut r)?, None)
+        };
+        if task.id != id || !r.is_empty() {
+            return Err(VMError::StorageReadError);
+        }
+        Ok((task, code))
+    }
+
+    fn code(&self, index: u32) -> VMResult<&[u8]> {
+        let entry = self
+            .code
+            .get(index as usize)
+            .copied()
+            .ok_or(VMError::StorageReadError)?;
+        self.read(entry)
+    }
+
+    // bytes of record or code, checksum is checked
+    fn read(&self, entry: Entry) -> VMResult<&[u8]> {
+        let start = self.records + entry.offset as usize;
+        let bytes = self
+            .bytes
+            .get(start..start + entry.len as usize)
+            .ok_or(VMError::StorageReadError)?;
+        if crc32(bytes) != entry.crc {
+            return Err(VMError::StorageChecksumMismatch);
+        }
+        Ok(bytes)
+    }
+
+    // all tasks decoded
+    pub(crate) fn data(&self, next_id: u32, globals: Vec<Global>) -> VMResult<StorageData> {
+        let tasks = self
+            .ids()
+            .map(|id| self.task(id))
+            .collect::<VMResult<Vec<_>>>()?;
+        Ok(StorageData {
+            tasks,
+            next_id,
+            globals,
+        })
+    }
+}
+
+// end of index section of len entries starting at given offset
+fn section_end(bytes: &[u8], at: usize, len: usize, entry_len: usize) -> VMResult<usize> {
+    len.checked_mul(entry_len)
+        .and_then(|entries| entries.checked_add(at + 4))
+        .filter(|&end| end + CRC_LEN <= bytes.len())
+        .ok_or(VMError::StorageChecksumMismatch)
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> VMResult<u32> {
+    let b = bytes.get(at..at + 4).ok_or(VMError::StorageReadError)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_entry(bytes: &[u8], at: usize) -> VMResult<Entry> {
+    Ok(Entry {
+        offset: read_u32(bytes, at)?,
+        len: read_u32(bytes, at + 4)?,
+        crc: read_u32(bytes, at + 8)?,
+    })
+}
+
+// builds snapshot from tasks pushed in ascending id order, identical instructions are written once
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotWriter {
+    index: Vec<u8>,
+    records: Vec<u8>,
+    len: u32,
+    code: Vec<u8>,
+    // (offset in code, len) by code index
+    code_entries: Vec<(usize, usize)>,
+    // content hash -> code indices with that hash
+    interned: HashMap<u64, Vec<u32>>,
+}
+
+impl SnapshotWriter {
+    // whole store, tasks are written in ascending id order and next_id is raised above
+    // every id, so decode accepts the result. Duplicate ids are rejected, nothing is written
+    pub(crate) fn encode(data: &StorageData) -> VMResult<Vec<u8>> {
+        let mut tasks: Vec<&Task> = data.tasks.iter().collect();
+        tasks.sort_by_key(|task| task.id);
+        let mut next_id = data.next_id;
+        for (i, task) in tasks.iter().enumerate() {
+            if i > 0 && tasks[i - 1].id == task.id || task.id >= TASK_ID_LIMIT {
+                return Err(VMError::InvalidTaskId(task.id));
+            }
+            next_id = next_id.max(task.id + 1);
+        }
+        let mut writer = Self::default();
+        for task in tasks {
+            writer.push_task(task)?;
+        }
+        writer.finish(next_id, &data.globals)
+    }
+
+    pub(crate) fn push_task(&mut self, task: &Task) -> VMResult<()> {
+        let code = self.intern(&task.instructions);
+        let start = self.records.len();
+        task.id.encode(&mut self.records)?;
+        task.title.encode(&mut self.records)?;
+        task.state.encode(&mut self.records)?;
+        code.encode(&mut self.records)?;
+        let record = &self.records[start..];
+        let offset = u32::try_from(start).map_err(|_| VMError::StorageSizeTooBig)?;
+        self.index.extend_from_slice(&task.id.to_le_bytes());
+        self.index.extend_from_slice(&offset.to_le_bytes());
+        self.index
+            .extend_from_slice(&(record.len() as u32).to_le_bytes());
+        self.index.extend_from_slice(&crc32(record).to_le_bytes());
+        self.len += 1;
+        Ok(())
+    }
+
+    // copies task not decoded by storage, its instructions are not interned to instructions pool
+    pub(crate) fn copy(&mut self, snapshot: &Snapshot, id: u32) -> VMResult<()> {
+        self.push_task(&snapshot.task(id)?)
+    }
+
+    fn intern(&mut self, instructions: &[u8]) -> u32 {
+        let candidates = self.interned.entry(content_hash(instructions)).or_default();
+        if let Some(&index) = candidates.iter().find(|&&index| {
+            let (offset, len) = self.code_entries[index as usize];
+            &self.code[offset..offset + len] == instructions
+        }) {
+            return index;
+        }
+        let index = self.code_entries.len() as u32;
+        candidates.push(index);
+        self.code_entries
+            .push((self.code.len(), instructions.len()));
+        self.code.extend_from_slice(instructions);
+        index
+    }
+
+    pub(crate) fn finish(self, next_id: u32, globals: &Vec<Global>) -> VMResult<Vec<u8>> {
+        let code_len = 4 + self.code_entries.len() * CODE_ENTRY_LEN;
+        let mut bytes = Vec::with_capacity(
+            64 + self.index.len() + code_len + self.records.len() + self.code.len(),
+        );
+        write_storage_header(&mut bytes);
+        next_id.encode(&mut bytes)?;
+        globals.encode(&mut bytes)?;
+        bytes.extend_from_slice(&self.len.to_le_bytes());
+        bytes.extend_from_slice(&self.index);
+        bytes.extend_from_slice(&(self.code_entries.len() as u32).to_le_bytes());
+        for &(offset, len) in &self.code_entries {
+            let code = &self.code[offset..offset + len];
+            let offset = u32::try_from(self.records.len() + offset)
+                .map_err(|_| VMError::StorageSizeTooBig)?;
+            bytes.extend_from_slice(&offset.to_le_bytes());
+            bytes.extend_from_slice(&(len as u32).to_le_bytes());
+            bytes.extend_from_slice(&crc32(code).to_le_bytes());
+        }
+        let crc = crc32(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes.extend_from_slice(&self.records);
+        bytes.extend_from_slice(&self.code);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::task_types::TaskState;
+
+    fn task(id: u32, title: &str) -> Task {
+        Task {
+            id,
+            title: title.to_string(),
+            state: TaskState { len: 2, state: 1 },
+            instructions: vec![0x12],
+        }
+    }
+
+    #[test]
+    fn test_snapshot_records() {
+        let mut writer = SnapshotWriter::default();
+        writer.push_task(&task(1, "A")).unwrap();
+        writer.push_task(&task(4, "B")).unwrap();
+        let bytes = writer.finish(5, &vec![Global::U32(7)]).unwrap();
+        let (snapshot, next_id, globals) = Snapshot::decode(bytes.clone()).unwrap();
+        assert_eq!(next_id, 5);
+        assert_eq!(globals, vec![Global::U32(7)]);
+        assert_eq!(snapshot.ids().collect::<Vec<_>>(), [1, 4]);
+        assert_eq!(snapshot.task(4).unwrap().title, "B");
+        assert_eq!(snapshot.task(4).unwrap().instructions, [0x12]);
+        // both tasks share one code entry
+        assert_eq!(snapshot.code.len(), 1);
+        assert!(matches!(snapshot.task(2), Err(VMError::TaskNotFound(2))));
+
+        // record checksum is checked on decode and on copy, other records stay readable
+        let mut corrupted = bytes;
+        let at = corrupted.iter().rposition(|&b| b == b'B').unwrap();
+        corrupted[at] = b'C';
+        let (snapshot, _, _) = Snapshot::decode(corrupted).unwrap();
+        assert_eq!(snapshot.task(1).unwrap().title, "A");
+        assert!(matches!(
+            snapshot.task(4),
+            Err(VMError::StorageChecksumMismatch)
+        ));
+        let mut writer = SnapshotWriter::default();
+        writer.copy(&snapshot, 1).unwrap();
+        assert!(matches!(
+            writer.copy(&snapshot, 4),
+            Err(VMError::StorageChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_task_id_limit() {
+        // index is by id, id past limit would allocate it
+        let mut writer = SnapshotWriter::default();
+        writer.push_task(&task(u32::MAX - 1, "A")).unwrap();
+        let bytes = writer.finish(u32::MAX, &vec![]).unwrap();
+        assert!(matches!(
+            Snapshot::decode(bytes),
+            Err(VMError::StorageCorrupted)
+        ));
+        let data = StorageData {
+            tasks: vec![task(TASK_ID_LIMIT, "A")],
+            next_id: 0,
+            globals: vec![],
+        };
+        assert!(matches!(
+            SnapshotWriter::encode(&data),
+            Err(VMError::InvalidTaskId(TASK_ID_LIMIT))
+        ));
+    }
+}
@@ -1,55 +1,390 @@
 use crate::errors::{VMError, VMResult};
 use crate::pools::InstructionsPool;
-use crate::storage::bincodec::{Decode, Encode};
-use crate::storage::task_types::{StorageData, TaskVM};
-use std::fs::File;
-use std::io::BufWriter;
+use crate::storage::backend::{FileBackend, StorageBackend};
+use crate::storage::bincodec::{
+    Decode, Encode, INDEXED_VERSION, STORAGE_VERSION, decode_storage, storage_version,
+};
+use crate::storage::crc32::crc32;
+use crate::storage::diff::StorageDiff;
+use crate::storage::globals::{GLOBALS_LIMIT, Global};
+use crate::storage::journal::{self, Mutation, Record};
+use crate::storage::shared::SharedStorage;
+use crate::storage::snapshot::{Snapshot, SnapshotWriter};
+use crate::storage::task_types::{StorageData, TASK_ID_LIMIT, Task, TaskField, TaskState, TaskVM};
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Write};
 
 /*
 StorageData (tasks:Vec<Task>) is dense ->  stores only non-deleted tasks.
 Storage (Vec<Option<TaskVM>>) is the in-memory representation, and is sparse (reconstructed during self.load(), where deleted tasks/gaps are None ):
 task_vm format is: [None,Some(TaskVM{id=1}),None,Some(TaskVM{id=3})] Thus task_vm.id is same as index in Vec.
+tasks of indexed snapshot (see snapshot.rs) are decoded on first access, until then their slot is empty OnceCell
+(snapshot bytes are already in memory, backend loads whole file),
+their instructions stay in snapshot (instructions_ref None) until CALL or T_GET_FIELD interns them.
+globals are global slots (G_GET/G_SET), saved and loaded together with tasks.
+StorageData is encoded by storage itself, backend (file, memory, ..) keeps only bytes.
+mutations since last save are appended to backend journal on save, see journal.rs.
+transactions (TX_BEGIN/TX_COMMIT/TX_ROLLBACK, VM::run_atomic) keep undo log of overwritten tasks and globals,
+rollback restores them, S_SAVE inside transaction is deferred until outermost commit.
+storage attached to SharedStorage is private copy of shared state, see shared.rs.
+forked storage (VM::fork) is copy of parent storage which is never saved: tasks not decoded yet
+stay in shared snapshot bytes, first overwrite of a slot keeps its previous value in fork base,
+so diff and commit into parent visit only slots changed by the fork.
 */
 
+// where storage is loaded from and saved to
+#[derive(Debug)]
+pub(crate) enum StorageSource {
+    Backend(Box<dyn StorageBackend>),
+    Shared(SharedStorage),
+    // storage which is never saved: fork, its changes are committed into parent (see fork()),
+    // and store read by read_data
+    Detached,
+}
+
 #[derive(Debug)]
 pub(crate) struct Storage {
-    tasks_vm: Vec<Option<TaskVM>>,
+    tasks_vm: Vec<Slot>,
     pub next_id: u32,
     alive: usize,
+    globals: Vec<Global>,
+    source: StorageSource,
+    // version of shared state this copy was taken from, None -> refreshed before next run
+    version: Option<u64>,
+    // mutations since last save in order, without duplicates
+    mutations: Vec<Mutation>,
+    recorded: HashSet<Mutation>,
+    journal: Journal,
+    // open transactions, innermost last
+    transactions: Vec<Transaction>,
+    // S_SAVE was deferred by save policy, written by flush
+    pending: bool,
+    // last loaded or saved snapshot, tasks not decoded yet are read from it
+    snapshot: Snapshot,
+    // slots of forked storage before fork changed them, None for storage which is not a fork
+    fork_base: Option<ForkBase>,
+}
+
+// values of slots before fork overwrote them first, other slots are the same as in parent at fork time
+#[derive(Debug, Default)]
+struct ForkBase {
+    tasks: BTreeMap<u32, Slot>,
+    globals: BTreeMap<u32, Global>,
+}
+
+// None -> no task, empty cell -> task not decoded from snapshot yet
+type Slot = Option<OnceCell<TaskVM>>;
+
+#[derive(Debug)]
+struct Transaction {
+    undo: Vec<Undo>,
+    next_id: u32,
+    alive: usize,
+    tasks_len: usize,
+    globals_len: usize,
+    mutations_len: usize,
+    // S_SAVE was called inside transaction
+    save: bool,
+    // named snapshots taken inside transaction, written by outermost commit
+    snapshots: Vec<NamedSnapshot>,
+}
+
+#[derive(Debug)]
+struct NamedSnapshot {
+    name: String,
+    bytes: Vec<u8>,
+    retention: Option<usize>,
+}
+
+// previous value of overwritten slot
+#[derive(Debug)]
+enum Undo {
+    Task(u32, Slot),
+    Global(u32, Global),
+}
+
+// checksum identifying snapshot for journal header.
+// crc32 of whole file is useless here: file ending with its own crc32 always gives the same residue,
+// so it is crc32 of bytes before trailing checksum (stored checksum itself for current format)
+pub(crate) fn snapshot_crc(bytes: &[u8]) -> u32 {
+    crc32(&bytes[..bytes.len().saturating_sub(4)])
+}
+
+// snapshot names are file names of FileBackend
+const SNAPSHOT_NAME_LIMIT: usize = 64;
+
+fn check_snapshot_name(name: &str) -> VMResult<()> {
+    let valid = !name.is_empty()
+        && name.len() <= SNAPSHOT_NAME_LIMIT
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if !valid {
+        return Err(VMError::InvalidSnapshotName(name.to_string()));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct Journal {
+    // crc32 of last snapshot, None -> next save is full (no snapshot yet, stale journal, restored image)
+    base_crc: Option<u32>,
+    snapshot_len: usize,
+    // bytes appended since snapshot, header included
+    len: usize,
 }
 
 /// storage is NOT thread-safe!
 impl Storage {
-    pub(crate) fn save(&mut self, instructions_pool: &InstructionsPool) -> VMResult<()> {
-        let f = File::create("tasks.bin").map_err(|_| VMError::StorageWriteError)?;
-        //do we need buffer? values are relatively small
-        let mut writer = BufWriter::new(f);
-        let mut tasks = Vec::with_capacity(self.alive);
-        for task_vm in self.tasks_vm.iter().flatten() {
-            tasks.push(task_vm.to_task(instructions_pool)?);
+    // inside transaction save is deferred until outermost commit,
+    // not immediate save is deferred until flush
+    pub(crate) fn save(
+        &mut self,
+        instructions_pool: &InstructionsPool,
+        immediate: bool,
+    ) -> VMResult<()> {
+        if let Some(tx) = self.transactions.last_mut() {
+            tx.save = true;
+            return Ok(());
+        }
+        if !immediate {
+            self.pending = true;
+            return Ok(());
         }
+        self.write(instructions_pool)
+    }
 
-        let data = StorageData {
-            tasks,
-            next_id: self.next_id,
+    // performs deferred save, all S_SAVEs since last write are one write of changed records
+    pub(crate) fn flush(
+        &mut self,
+        instructions_pool: &InstructionsPool,
+        force: bool,
+    ) -> VMResult<()> {
+        if !self.transactions.is_empty() {
+            return Err(VMError::TransactionNotCommitted);
+        }
+        if std::mem::take(&mut self.pending) || force {
+            self.write(instructions_pool)?;
+        }
+        Ok(())
+    }
+
+    // appends mutations to journal, compacts when journal would outgrow snapshot
+    // or backend has no journal
+    // shared storage: publishes changes and saves shared state
+    fn write(&mut self, instructions_pool: &InstructionsPool) -> VMResult<()> {
+        match self.source {
+            StorageSource::Shared(_) => return self.publish_shared(instructions_pool, true),
+            StorageSource::Detached => {
+                self.clear_mutations();
+                return Ok(());
+            }
+            StorageSource::Backend(_) => {}
+        }
+        if let Some(base_crc) = self.journal.base_crc {
+            let mut bytes = Vec::new();
+            if self.journal.len == 0 {
+                bytes = journal::header(base_crc);
+            }
+            let mut records = 0;
+            for mutation in &self.mutations {
+                if let Some(record) = self.record(*mutation, instructions_pool)? {
+                    journal::append_record(&record, &mut bytes)?;
+                    records += 1;
+                }
+            }
+            if records == 0 {
+                self.clear_mutations();
+                return Ok(());
+            }
+            let appended = match &mut self.source {
+                StorageSource::Backend(backend)
+                    if self.journal.len + bytes.len() <= self.journal.snapshot_len =>
+                {
+                    // failed append may leave torn tail, records appended after it would be lost
+                    backend
+                        .append_journal(&bytes)
+                        .inspect_err(|_| self.journal.base_crc = None)?
+                }
+                _ => false,
+            };
+            if appended {
+                self.journal.len += bytes.len();
+                self.clear_mutations();
+                return Ok(());
+            }
+        }
+        self.compact(instructions_pool)
+    }
+
+    // full save: new snapshot, journal is dropped by backend
+    // tasks not decoded since last snapshot are copied as they are
+    fn compact(&mut self, instructions_pool: &InstructionsPool) -> VMResult<()> {
+        let bytes = self.encode_snapshot(instructions_pool)?;
+        if let StorageSource::Backend(backend) = &mut self.source {
+            backend.save(&bytes)?;
+        }
+        let snapshot_len = bytes.len();
+        let (snapshot, _, _) = Snapshot::decode(bytes)?;
+        self.journal = Journal {
+            base_crc: Some(snapshot.crc()),
+            snapshot_len,
+            len: 0,
         };
-        //add context?
-        data.encode(&mut writer)?;
+        self.snapshot = snapshot;
+        self.clear_mutations();
         Ok(())
     }
 
-    pub(crate) fn load(op_pool: &mut InstructionsPool) -> VMResult<Self> {
-        use std::io::ErrorKind;
-        let data: StorageData = match File::open("tasks.bin") {
-            Ok(mut file) => StorageData::decode(&mut file)?,
-            Err(e) if e.kind() == ErrorKind::NotFound => StorageData {
-                tasks: Vec::new(),
-                next_id: 0,
-            },
-            Err(_) => return Err(VMError::StorageReadError),
+    // whole storage as snapshot file
+    fn encode_snapshot(&self, instructions_pool: &InstructionsPool) -> VMResult<Vec<u8>> {
+        let mut writer = SnapshotWriter::default();
+        for (id, slot) in self.tasks_vm.iter().enumerate() {
+            match slot {
+                Some(cell) if cell.get().is_none() => writer.copy(&self.snapshot, id as u32)?,
+                Some(_) => {
+                    if let Some(task) = self.task(id as u32, instructions_pool)? {
+                        writer.push_task(&task)?;
+                    }
+                }
+                None => {}
+            }
+        }
+        writer.finish(self.next_id, &self.globals)
+    }
+
+    // named snapshot of current state, unsaved changes included.
+    // inside transaction it is written by outermost commit and dropped by rollback
+    pub(crate) fn snapshot_named(
+        &mut self,
+        name: &str,
+        instructions_pool: &InstructionsPool,
+        retention: Option<usize>,
+    ) -> VMResult<()> {
+        check_snapshot_name(name)?;
+        self.backend()?;
+        let snapshot = NamedSnapshot {
+            name: name.to_string(),
+            bytes: self.encode_snapshot(instructions_pool)?,
+            retention,
         };
+        match self.transactions.last_mut() {
+            Some(tx) => tx.snapshots.push(snapshot),
+            None => self.save_named(&snapshot)?,
+        }
+        Ok(())
+    }
+
+    // oldest snapshots beyond retention are deleted
+    fn save_named(&mut self, snapshot: &NamedSnapshot) -> VMResult<()> {
+        let backend = self.backend()?;
+        backend.save_named(&snapshot.name, &snapshot.bytes)?;
+        if let Some(retention) = snapshot.retention {
+            let names = backend.list_named()?;
+            for old in &names[..names.len().saturating_sub(retention)] {
+                backend.delete_named(old)?;
+            }
+        }
+        Ok(())
+    }
+
+    // replaces store with named snapshot, unsaved changes are dropped.
+    // restored store is saved as S_SAVE is (see save()), inside transaction it is undone by rollback
+    pub(crate) fn restore_named(
+        &mut self,
+        name: &str,
+        op_pool: &mut InstructionsPool,
+        immediate: bool,
+    ) -> VMResult<()> {
+        check_snapshot_name(name)?;
+        let bytes = self
+            .backend()?
+            .load_named(name)?
+            .ok_or_else(|| VMError::SnapshotNotFound(name.to_string()))?;
+        let data = decode_storage(&bytes)?;
+        let mut tasks_vm: Vec<Slot> = Vec::new();
+        for task in data.tasks {
+            let task_vm = TaskVM::from_task(task, op_pool)?;
+            let id = task_vm.id as usize;
+            if tasks_vm.len() <= id {
+                tasks_vm.resize(id + 1, None);
+            }
+            tasks_vm[id] = Some(OnceCell::from(task_vm));
+        }
+        // every slot is overwritten through undo log, restored tasks are decoded
+        // so slots kept by undo log still read tasks from current snapshot
+        for id in 0..self.tasks_vm.len().max(tasks_vm.len()) as u32 {
+            self.log_task(id);
+        }
+        for index in 0..self.globals.len() as u32 {
+            self.log_global(index);
+        }
+        self.alive = tasks_vm.iter().flatten().count();
+        self.tasks_vm = tasks_vm;
+        self.next_id = data.next_id;
+        self.globals = data.globals;
+        // restored store replaces saved snapshot and journal on next write
+        self.journal.base_crc = None;
+        self.save(op_pool, immediate)
+    }
 
-        let mut tasks_vm: Vec<Option<TaskVM>> = Vec::new();
+    pub(crate) fn snapshots(&mut self) -> VMResult<Vec<String>> {
+        self.backend()?.list_named()
+    }
+
+    pub(crate) fn delete_snapshot(&mut self, name: &str) -> VMResult<bool> {
+        check_snapshot_name(name)?;
+        self.backend()?.delete_named(name)
+    }
+
+    // named snapshots are kept by backend, shared storage has none
+    fn backend(&mut self) -> VMResult<&mut Box<dyn StorageBackend>> {
+        match &mut self.source {
+            StorageSource::Backend(backend) => Ok(backend),
+            StorageSource::Shared(_) | StorageSource::Detached => {
+                Err(VMError::SnapshotsNotSupported)
+            }
+        }
+    }
+
+    // tasks and globals of stored store (journal applied) without vm opened on it,
+    // backend keeps generation of the read store, so write_data to it detects stale write
+    pub(crate) fn read_data(backend: &mut dyn StorageBackend) -> VMResult<StorageData> {
+        let snapshot = backend.load()?;
+        let journal_bytes = backend.load_journal()?;
+        let mut instructions_pool = InstructionsPool::default();
+        Self::from_bytes(
+            StorageSource::Detached,
+            snapshot,
+            journal_bytes,
+            &mut instructions_pool,
+        )?
+        .data(&instructions_pool)
+    }
+
+    // replaces stored snapshot and journal with data
+    pub(crate) fn write_data(backend: &mut dyn StorageBackend, data: &StorageData) -> VMResult<()> {
+        backend.save(&SnapshotWriter::encode(data)?)
+    }
+
+    // dense copy of tasks and globals
+    pub(crate) fn data(&self, instructions_pool: &InstructionsPool) -> VMResult<StorageData> {
+        let mut tasks = Vec::with_capacity(self.alive);
+        for id in 0..self.tasks_vm.len() as u32 {
+            if let Some(task) = self.task(id, instructions_pool)? {
+                tasks.push(task);
+            }
+        }
+        Ok(StorageData {
+            tasks,
+            next_id: self.next_id,
+            globals: self.globals.clone(),
+        })
+    }
+
+    fn set_data(&mut self, data: StorageData, op_pool: &mut InstructionsPool) -> VMResult<()> {
+        let mut tasks_vm: Vec<Slot> = Vec::new();
         let mut alive = 0;
 
         for t in data.tasks {
@@ -60,30 +395,554 @@ impl Storage {
                 tasks_vm.resize(id + 1, None);
             }
             //Task ids are restored from Task.id, not from vector index.
-            tasks_vm[id] = Some(task_vm);
+            tasks_vm[id] = Some(OnceCell::from(task_vm));
             alive += 1;
         }
+        self.tasks_vm = tasks_vm;
+        self.alive = alive; //keep?
+        self.next_id = data.next_id;
+        self.globals = data.globals;
+        self.snapshot = Snapshot::default();
+        Ok(())
+    }
 
-        Ok(Self {
-            tasks_vm,
-            next_id: data.next_id,
-            alive, //keep?
-        })
+    // storage of saved file, journal starts empty
+    fn set_bytes(&mut self, bytes: Vec<u8>, op_pool: &mut InstructionsPool) -> VMResult<()> {
+        let snapshot_len = bytes.len();
+        let base_crc = if (INDEXED_VERSION..=STORAGE_VERSION).contains(&storage_version(&bytes)?) {
+            let (snapshot, next_id, globals) = Snapshot::decode(bytes)?;
+            let crc = snapshot.crc();
+            self.set_snapshot(snapshot, next_id, globals);
+            crc
+        } else {
+            // older versions are decoded whole, next compaction upgrades them
+            let crc = snapshot_crc(&bytes);
+            self.set_data(decode_storage(&bytes)?, op_pool)?;
+            crc
+        };
+        self.journal = Journal {
+            base_crc: Some(base_crc),
+            snapshot_len,
+            len: 0,
+        };
+        Ok(())
+    }
+
+    // tasks of snapshot are decoded on first access
+    fn set_snapshot(&mut self, snapshot: Snapshot, next_id: u32, globals: Vec<Global>) {
+        let mut tasks_vm: Vec<Slot> = Vec::new();
+        let mut alive = 0;
+        for id in snapshot.ids() {
+            tasks_vm.resize(id as usize, None);
+            tasks_vm.push(Some(OnceCell::new()));
+            alive += 1;
+        }
+        self.tasks_vm = tasks_vm;
+        self.alive = alive;
+        self.next_id = next_id;
+        self.globals = globals;
+        self.snapshot = snapshot;
+    }
+
+    fn clear_mutations(&mut self) {
+        self.mutations.clear();
+        self.recorded.clear();
+    }
+
+    fn record_mutation(&mut self, mutation: Mutation) {
+        if self.recorded.insert(mutation) {
+            self.mutations.push(mutation);
+        }
+    }
+
+    // journal record with current values, None if task was deleted after mutation
+    fn record(
+        &self,
+        mutation: Mutation,
+        instructions_pool: &InstructionsPool,
+    ) -> VMResult<Option<Record>> {
+        let record = match mutation {
+            Mutation::Create(id) => match self.task(id, instructions_pool)? {
+                Some(task) => Record::Create(task),
+                None => return Ok(None),
+            },
+            Mutation::SetField(id, field) => {
+                let Some(task_vm) = self.get(id)? else {
+                    return Ok(None);
+                };
+                match field {
+                    TaskField::Title => Record::SetTitle(
+                        id,
+                        String::from_utf8(task_vm.title.clone())
+                            .map_err(|_| VMError::BytesToStringConversionError)?,
+                    ),
+                    TaskField::State => Record::SetState(id, task_vm.state),
+                    TaskField::Instructions => {
+                        Record::SetInstructions(id, self.instructions(task_vm, instructions_pool)?)
+                    }
+                }
+            }
+            Mutation::Delete(id) => Record::Delete(id),
+            Mutation::SetGlobal(index) => Record::SetGlobal(index, self.global(index)?.clone()),
+        };
+        Ok(Some(record))
+    }
+
+    // applies record published by other storage, it is journaled on next save
+    pub(crate) fn apply(&mut self, record: Record, op_pool: &mut InstructionsPool) -> VMResult<()> {
+        let mutation = record.mutation();
+        self.replay(record, op_pool)?;
+        self.record_mutation(mutation);
+        Ok(())
+    }
+
+    fn replay(&mut self, record: Record, op_pool: &mut InstructionsPool) -> VMResult<()> {
+        match record {
+            Record::Create(task) => {
+                if task.id >= TASK_ID_LIMIT {
+                    return Err(VMError::StorageCorrupted);
+                }
+                let task_vm = TaskVM::from_task(task, op_pool)?;
+                let id = task_vm.id;
+                if self.tasks_vm.len() <= id as usize {
+                    self.tasks_vm.resize(id as usize + 1, None);
+                }
+                if self.tasks_vm[id as usize]
+                    .replace(OnceCell::from(task_vm))
+                    .is_none()
+                {
+                    self.alive += 1;
+                }
+                self.next_id = self.next_id.max(id + 1);
+            }
+            Record::SetTitle(id, title) => {
+                if let Ok(task_vm) = self.get_mut(id) {
+                    task_vm.title = title.into_bytes();
+                }
+            }
+            Record::SetState(id, state) => {
+                if let Ok(task_vm) = self.get_mut(id) {
+                    task_vm.state = state;
+                }
+            }
+            Record::SetInstructions(id, instructions) => {
+                if let Ok(task_vm) = self.get_mut(id) {
+                    task_vm.instructions_ref = Some(op_pool.intern_instructions(instructions));
+                }
+            }
+            Record::Delete(id) => {
+                let _ = self.remove(id);
+            }
+            Record::SetGlobal(index, global) => self.put_global(index, global)?,
+        }
+        Ok(())
+    }
+
+    fn empty(source: StorageSource) -> Self {
+        Self {
+            tasks_vm: Vec::new(),
+            next_id: 0,
+            alive: 0,
+            globals: Vec::new(),
+            source,
+            version: None,
+            mutations: Vec::new(),
+            recorded: HashSet::new(),
+            journal: Journal::default(),
+            transactions: Vec::new(),
+            pending: false,
+            snapshot: Snapshot::default(),
+            fork_base: None,
+        }
+    }
+
+    pub(crate) fn open(source: StorageSource, op_pool: &mut InstructionsPool) -> VMResult<Self> {
+        match source {
+            StorageSource::Backend(backend) => Self::load(backend, op_pool),
+            StorageSource::Shared(shared) => {
+                let mut storage = Self::empty(StorageSource::Shared(shared));
+                storage.refresh(op_pool)?;
+                Ok(storage)
+            }
+            StorageSource::Detached => Ok(Self::empty(StorageSource::Detached)),
+        }
+    }
+
+    pub(crate) fn load(
+        mut backend: Box<dyn StorageBackend>,
+        op_pool: &mut InstructionsPool,
+    ) -> VMResult<Self> {
+        let snapshot = backend.load()?;
+        let journal_bytes = backend.load_journal()?;
+        Self::from_bytes(
+            StorageSource::Backend(backend),
+            snapshot,
+            journal_bytes,
+            op_pool,
+        )
+    }
+
+    // re-reads store from backend, unsaved changes are dropped. Backend takes generation
+    // of the reloaded store, so storage can be saved again after StorageConflict
+    pub(crate) fn reload(&mut self, op_pool: &mut InstructionsPool) -> VMResult<()> {
+        if !self.transactions.is_empty() {
+            return Err(VMError::TransactionNotCommitted);
+        }
+        match &mut self.source {
+            StorageSource::Backend(backend) => {
+                let snapshot = backend.load()?;
+                let journal_bytes = backend.load_journal()?;
+                let mut reloaded =
+                    Self::from_bytes(StorageSource::Detached, snapshot, journal_bytes, op_pool)?;
+                reloaded.source = std::mem::replace(&mut self.source, StorageSource::Detached);
+                *self = reloaded;
+            }
+            StorageSource::Shared(_) => {
+                self.clear_mutations();
+                self.discard();
+                self.refresh(op_pool)?;
+            }
+            // fork has no store of its own
+            StorageSource::Detached => {}
+        }
+        Ok(())
+    }
+
+    fn from_bytes(
+        source: StorageSource,
+        snapshot: Option<Vec<u8>>,
+        journal_bytes: Option<Vec<u8>>,
+        op_pool: &mut InstructionsPool,
+    ) -> VMResult<Self> {
+        let mut storage = Self::empty(source);
+        if let Some(bytes) = snapshot {
+            storage.set_bytes(bytes, op_pool)?;
+        }
+        let mut journal = std::mem::take(&mut storage.journal);
+        if let Some(bytes) = journal_bytes {
+            let (records, complete) = journal::read(&bytes, journal.base_crc);
+            for record in records {
+                storage.replay(record, op_pool)?;
+            }
+            if complete {
+                journal.len = bytes.len();
+            } else {
+                journal.base_crc = None;
+            }
+        }
+        storage.journal = journal;
+        Ok(storage)
     }
 
-    pub(crate) fn add(&mut self, task: TaskVM) {
+    // takes copy of shared state if it changed since last one, no-op for backend storage
+    pub(crate) fn refresh(&mut self, op_pool: &mut InstructionsPool) -> VMResult<()> {
+        let StorageSource::Shared(shared) = &self.source else {
+            return Ok(());
+        };
+        if self.version == Some(shared.version()?) {
+            return Ok(());
+        }
+        let (data, version) = shared.snapshot()?;
+        self.set_data(data, op_pool)?;
+        self.version = Some(version);
+        self.clear_mutations();
+        Ok(())
+    }
+
+    // publishes changes of finished run to shared storage
+    pub(crate) fn publish(&mut self, instructions_pool: &InstructionsPool) -> VMResult<()> {
+        if matches!(self.source, StorageSource::Shared(_)) && !self.mutations.is_empty() {
+            return self.publish_shared(instructions_pool, false);
+        }
+        Ok(())
+    }
+
+    // changes of failed run are dropped by next refresh, its deferred save is cancelled
+    pub(crate) fn discard(&mut self) {
+        self.version = None;
+        self.pending = false;
+    }
+
+    fn publish_shared(&mut self, instructions_pool: &InstructionsPool, save: bool) -> VMResult<()> {
+        let StorageSource::Shared(shared) = &self.source else {
+            return Ok(());
+        };
+        let mut records = Vec::with_capacity(self.mutations.len());
+        for mutation in &self.mutations {
+            if let Some(record) = self.record(*mutation, instructions_pool)? {
+                records.push(record);
+            }
+        }
+        let base = self.version.ok_or(VMError::StorageConflict)?;
+        let published = !records.is_empty() as u64;
+        match shared.publish(records, base, save) {
+            // copy is still exact only if nobody published in between
+            Ok(version) => {
+                self.version = (version == base + published).then_some(version);
+                self.clear_mutations();
+                Ok(())
+            }
+            Err(e) => {
+                self.version = None;
+                Err(e)
+            }
+        }
+    }
+
+    pub(crate) fn begin(&mut self) {
+        self.transactions.push(Transaction {
+            undo: Vec::new(),
+            next_id: self.next_id,
+            alive: self.alive,
+            tasks_len: self.tasks_vm.len(),
+            globals_len: self.globals.len(),
+            mutations_len: self.mutations.len(),
+            save: false,
+            snapshots: Vec::new(),
+        });
+    }
+
+    // nested commit hands its undo log and snapshots to outer transaction,
+    // outermost one writes named snapshots and performs deferred save,
+    // it is rolled back if any write fails. Not immediate save is deferred until flush
+    pub(crate) fn commit(
+        &mut self,
+        instructions_pool: &InstructionsPool,
+        immediate: bool,
+    ) -> VMResult<()> {
+        let tx = self.transactions.pop().ok_or(VMError::NoTransaction)?;
+        if let Some(outer) = self.transactions.last_mut() {
+            outer.undo.extend(tx.undo);
+            outer.save |= tx.save;
+            outer.snapshots.extend(tx.snapshots);
+            return Ok(());
+        }
+        let written = tx
+            .snapshots
+            .iter()
+            .try_for_each(|snapshot| self.save_named(snapshot));
+        let written = match written {
+            Ok(()) if tx.save && !immediate => {
+                self.pending = true;
+                Ok(())
+            }
+            Ok(()) if tx.save => self.write(instructions_pool),
+            result => result,
+        };
+        if let Err(e) = written {
+            self.undo(tx);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn rollback(&mut self) -> VMResult<()> {
+        let tx = self.transactions.pop().ok_or(VMError::NoTransaction)?;
+        self.undo(tx);
+        Ok(())
+    }
+
+    pub(crate) fn rollback_all(&mut self) {
+        while let Some(tx) = self.transactions.pop() {
+            self.undo(tx);
+        }
+    }
+
+    pub(crate) fn transactions(&self) -> usize {
+        self.transactions.len()
+    }
+
+    fn undo(&mut self, tx: Transaction) {
+        for undo in tx.undo.into_iter().rev() {
+            match undo {
+                // slots may be missing after restore_named shortened the store
+                Undo::Task(id, task_vm) => {
+                    if self.tasks_vm.len() <= id as usize {
+                        self.tasks_vm.resize(id as usize + 1, None);
+                    }
+                    self.tasks_vm[id as usize] = task_vm;
+                }
+                Undo::Global(index, global) => {
+                    if self.globals.len() <= index as usize {
+                        self.globals.resize(index as usize + 1, Global::default());
+                    }
+                    self.globals[index as usize] = global;
+                }
+            }
+        }
+        self.tasks_vm.truncate(tx.tasks_len);
+        self.globals.truncate(tx.globals_len);
+        self.next_id = tx.next_id;
+        self.alive = tx.alive;
+        for mutation in self.mutations.drain(tx.mutations_len..) {
+            self.recorded.remove(&mutation);
+        }
+    }
+
+    // slots beyond transaction start are truncated on rollback, only existing ones are logged
+    fn log_task(&mut self, id: u32) {
+        let slot = self.tasks_vm.get(id as usize);
+        if let Some(base) = &mut self.fork_base {
+            base.tasks
+                .entry(id)
+                .or_insert_with(|| slot.cloned().flatten());
+        }
+        if let Some(tx) = self.transactions.last_mut()
+            && let Some(task_vm) = slot
+        {
+            tx.undo.push(Undo::Task(id, task_vm.clone()));
+        }
+    }
+
+    fn log_global(&mut self, index: u32) {
+        let global = self.globals.get(index as usize);
+        if let Some(base) = &mut self.fork_base {
+            base.globals
+                .entry(index)
+                .or_insert_with(|| global.cloned().unwrap_or_default());
+        }
+        if let Some(tx) = self.transactions.last_mut()
+            && let Some(global) = global
+        {
+            tx.undo.push(Undo::Global(index, global.clone()));
+        }
+    }
+
+    // copy of storage for VM::fork, never saved. Slots not decoded yet stay undecoded,
+    // snapshot bytes are shared
+    pub(crate) fn fork(&self) -> VMResult<Self> {
+        if !self.transactions.is_empty() {
+            return Err(VMError::TransactionNotCommitted);
+        }
+        let mut fork = Self::empty(StorageSource::Detached);
+        fork.tasks_vm = self.tasks_vm.clone();
+        fork.next_id = self.next_id;
+        fork.alive = self.alive;
+        fork.globals = self.globals.clone();
+        fork.snapshot = self.snapshot.clone();
+        fork.fork_base = Some(ForkBase::default());
+        Ok(fork)
+    }
+
+    // changes of fork since it was forked
+    pub(crate) fn diff(&self, instructions_pool: &InstructionsPool) -> VMResult<StorageDiff> {
+        let base = self.fork_base.as_ref().ok_or(VMError::NotForked)?;
+        let mut diff = StorageDiff::default();
+        for (&id, slot) in &base.tasks {
+            diff.push_task(
+                self.slot_task(id, slot, instructions_pool)?,
+                self.task(id, instructions_pool)?,
+            );
+        }
+        for (&index, global) in &base.globals {
+            diff.push_global(index, global.clone(), self.global(index)?.clone());
+        }
+        Ok(diff)
+    }
+
+    // applies changes of fork, fails with StorageConflict without applying any
+    // if parent changed the same task or global after fork was taken
+    pub(crate) fn merge(
+        &mut self,
+        fork: &Storage,
+        fork_pool: &InstructionsPool,
+        op_pool: &mut InstructionsPool,
+    ) -> VMResult<()> {
+        let base = fork.fork_base.as_ref().ok_or(VMError::NotForked)?;
+        if !self.transactions.is_empty() || !fork.transactions.is_empty() {
+            return Err(VMError::TransactionNotCommitted);
+        }
+        for (&id, slot) in &base.tasks {
+            if fork.slot_task(id, slot, fork_pool)? != self.task(id, op_pool)? {
+                return Err(VMError::StorageConflict);
+            }
+        }
+        for (&index, global) in &base.globals {
+            if global != self.global(index)? {
+                return Err(VMError::StorageConflict);
+            }
+        }
+        for record in fork.diff(fork_pool)?.into_records() {
+            self.apply_logged(record, op_pool)?;
+        }
+        Ok(())
+    }
+
+    // apply() undone by rollback of open transaction (and listed by diff of fork)
+    pub(crate) fn apply_logged(
+        &mut self,
+        record: Record,
+        op_pool: &mut InstructionsPool,
+    ) -> VMResult<()> {
+        match record.mutation() {
+            Mutation::SetGlobal(index) => self.log_global(index),
+            Mutation::Create(id) | Mutation::SetField(id, _) | Mutation::Delete(id) => {
+                self.log_task(id)
+            }
+        }
+        self.apply(record, op_pool)
+    }
+
+    // task of logged slot, slot not decoded yet is read from snapshot
+    fn slot_task(
+        &self,
+        id: u32,
+        slot: &Slot,
+        instructions_pool: &InstructionsPool,
+    ) -> VMResult<Option<Task>> {
+        match slot.as_ref().map(|cell| cell.get()) {
+            None => Ok(None),
+            Some(Some(task_vm)) => Ok(Some(
+                task_vm.to_task(self.instructions(task_vm, instructions_pool)?)?,
+            )),
+            Some(None) => Ok(Some(self.snapshot.task(id)?)),
+        }
+    }
+
+    // image does not keep backend, restored storage saves to the given one,
+    // shared storage replaces restored tasks and globals with shared ones.
+    // backend is loaded so that it keeps generation of the store at restore: first save
+    // (full, restored tasks replace stored ones) fails with StorageConflict if store was written since
+    pub(crate) fn set_source(
+        &mut self,
+        mut source: StorageSource,
+        op_pool: &mut InstructionsPool,
+    ) -> VMResult<()> {
+        if let StorageSource::Backend(backend) = &mut source {
+            backend.load()?;
+            backend.load_journal()?;
+        }
+        self.source = source;
+        self.version = None;
+        self.refresh(op_pool)
+    }
+
+    pub(crate) fn add(&mut self, task: TaskVM) -> VMResult<()> {
         let id = self.next_id;
+        if id >= TASK_ID_LIMIT {
+            return Err(VMError::StorageSizeTooBig);
+        }
         self.next_id += 1;
 
         if self.tasks_vm.len() <= id as usize {
             self.tasks_vm.resize(id as usize + 1, None);
         }
 
-        self.tasks_vm[id as usize] = Some(task);
+        self.log_task(id);
+        self.tasks_vm[id as usize] = Some(OnceCell::from(task));
         self.alive += 1;
+        self.record_mutation(Mutation::Create(id));
+        Ok(())
     }
 
     pub(crate) fn delete(&mut self, id: u32) -> VMResult<()> {
+        self.log_task(id);
+        self.remove(id)?;
+        self.record_mutation(Mutation::Delete(id));
+        Ok(())
+    }
+
+    fn remove(&mut self, id: u32) -> VMResult<()> {
         let idx = id as usize;
 
         if let Some(task_vm) = self.tasks_vm.get_mut(idx)
@@ -96,15 +955,137 @@ impl Storage {
         Err(VMError::TaskNotFound(id))
     }
 
-    pub(crate) fn get(&self, id: u32) -> Option<&TaskVM> {
-        self.tasks_vm.get(id as usize).and_then(|opt| opt.as_ref())
+    // decodes task from snapshot on first access
+    pub(crate) fn get(&self, id: u32) -> VMResult<Option<&TaskVM>> {
+        let Some(Some(cell)) = self.tasks_vm.get(id as usize) else {
+            return Ok(None);
+        };
+        if let Some(task_vm) = cell.get() {
+            return Ok(Some(task_vm));
+        }
+        let task_vm = self.snapshot.task_vm(id)?;
+        Ok(Some(cell.get_or_init(|| task_vm)))
     }
 
-    pub(crate) fn get_mut(&mut self, id: u32) -> VMResult<&mut TaskVM> {
-        self.tasks_vm
+    fn get_mut(&mut self, id: u32) -> VMResult<&mut TaskVM> {
+        let cell = self
+            .tasks_vm
             .get_mut(id as usize)
             .and_then(|opt| opt.as_mut())
-            .ok_or(VMError::TaskNotFound(id))
+            .ok_or(VMError::TaskNotFound(id))?;
+        if cell.get().is_none() {
+            let _ = cell.set(self.snapshot.task_vm(id)?);
+        }
+        cell.get_mut().ok_or(VMError::TaskNotFound(id))
+    }
+
+    pub(crate) fn task(
+        &self,
+        id: u32,
+        instructions_pool: &InstructionsPool,
+    ) -> VMResult<Option<Task>> {
+        match self.get(id)? {
+            Some(task_vm) => Ok(Some(
+                task_vm.to_task(self.instructions(task_vm, instructions_pool)?)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn instructions(
+        &self,
+        task_vm: &TaskVM,
+        instructions_pool: &InstructionsPool,
+    ) -> VMResult<Vec<u8>> {
+        match task_vm.instructions_ref {
+            Some(instructions_ref) => {
+                Ok(instructions_pool.get(instructions_ref as usize)?.to_vec())
+            }
+            None => Ok(self.snapshot.instructions(task_vm.id)?),
+        }
+    }
+
+    // instructions referenced by tasks and by undo log of open transactions
+    pub(crate) fn instructions_refs(&self) -> impl Iterator<Item = u32> + '_ {
+        let undo = self.transactions.iter().flat_map(|tx| &tx.undo);
+        let logged = undo.filter_map(|undo| match undo {
+            Undo::Task(_, slot) => Some(slot),
+            Undo::Global(..) => None,
+        });
+        let base = self.fork_base.iter().flat_map(|base| base.tasks.values());
+        self.tasks_vm
+            .iter()
+            .chain(logged)
+            .chain(base)
+            .flatten()
+            .filter_map(|cell| cell.get()?.instructions_ref)
+    }
+
+    // interns instructions of task still in snapshot, None if there is no such task
+    pub(crate) fn instructions_ref(
+        &mut self,
+        id: u32,
+        instructions_pool: &mut InstructionsPool,
+    ) -> VMResult<Option<u32>> {
+        let Some(task_vm) = self.get(id)? else {
+            return Ok(None);
+        };
+        if let Some(instructions_ref) = task_vm.instructions_ref {
+            return Ok(Some(instructions_ref));
+        }
+        let instructions = self.snapshot.instructions(id)?;
+        let instructions_ref = instructions_pool.intern_instructions(instructions);
+        self.get_mut(id)?.instructions_ref = Some(instructions_ref);
+        Ok(Some(instructions_ref))
+    }
+
+    // task field setters record mutation for journal
+    pub(crate) fn set_title(&mut self, id: u32, title: Vec<u8>) -> VMResult<()> {
+        self.log_task(id);
+        self.get_mut(id)?.title = title;
+        self.record_mutation(Mutation::SetField(id, TaskField::Title));
+        Ok(())
+    }
+
+    pub(crate) fn set_state(&mut self, id: u32, state: u32) -> VMResult<()> {
+        self.log_task(id);
+        self.get_mut(id)?.state.set_state(state)?;
+        self.record_mutation(Mutation::SetField(id, TaskField::State));
+        Ok(())
+    }
+
+    pub(crate) fn set_instructions(&mut self, id: u32, instructions_ref: u32) -> VMResult<()> {
+        self.log_task(id);
+        self.get_mut(id)?.instructions_ref = Some(instructions_ref);
+        self.record_mutation(Mutation::SetField(id, TaskField::Instructions));
+        Ok(())
+    }
+
+    // unset slots are Null
+    pub(crate) fn global(&self, index: u32) -> VMResult<&Global> {
+        const NULL: &Global = &Global::Null;
+        if index >= GLOBALS_LIMIT {
+            return Err(VMError::GlobalIndexOutOfBounds(index));
+        }
+        Ok(self.globals.get(index as usize).unwrap_or(NULL))
+    }
+
+    pub(crate) fn set_global(&mut self, index: u32, value: Global) -> VMResult<()> {
+        self.log_global(index);
+        self.put_global(index, value)?;
+        self.record_mutation(Mutation::SetGlobal(index));
+        Ok(())
+    }
+
+    fn put_global(&mut self, index: u32, value: Global) -> VMResult<()> {
+        if index >= GLOBALS_LIMIT {
+            return Err(VMError::GlobalIndexOutOfBounds(index));
+        }
+        if self.globals.len() <= index as usize {
+            self.globals.resize(index as usize + 1, Global::Null);
+        }
+        self.globals[index as usize] = value;
+        Ok(())
     }
 
     //rename to capacity + add alive (non-deleted tasks)
@@ -113,3 +1094,112 @@ impl Storage {
         self.tasks_vm.len()
     }
 }
+
+// vm image: storage is saved sparse (with deleted slots) and with unsaved changes
+// [len][(flag, id, title, state, instructions ref)..][next_id][globals]
+impl Storage {
+    // image is independent of snapshot: stored tasks are decoded, their instructions interned to pool
+    pub(crate) fn encode_image<W: Write>(
+        &self,
+        w: &mut W,
+        instructions_pool: &mut InstructionsPool,
+    ) -> VMResult<()> {
+        (self.tasks_vm.len() as u32).encode(w)?;
+        for id in 0..self.tasks_vm.len() as u32 {
+            match self.get(id)? {
+                Some(task_vm) => {
+                    let instructions_ref = match task_vm.instructions_ref {
+                        Some(instructions_ref) => instructions_ref,
+                        None => {
+                            instructions_pool.intern_instructions(self.snapshot.instructions(id)?)
+                        }
+                    };
+                    1u8.encode(w)?;
+                    task_vm.id.encode(w)?;
+                    task_vm.title.encode(w)?;
+                    task_vm.state.encode(w)?;
+                    instructions_ref.encode(w)?;
+                }
+                None => 0u8.encode(w)?,
+            }
+        }
+        self.next_id.encode(w)?;
+        self.globals.encode(w)
+    }
+}
+
+impl Decode for Storage {
+    fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
+        let len = u32::decode(r)?;
+        let mut tasks_vm = Vec::new();
+        let mut alive = 0;
+        let mut ids_end = 0;
+        for index in 0..len {
+            let task_vm = match u8::decode(r)? {
+                0 => None,
+                _ => {
+                    // each task sits at its id, as storage is written
+                    let id = u32::decode(r)?;
+                    if id != index {
+                        return Err(VMError::InvalidImage);
+                    }
+                    alive += 1;
+                    ids_end = index + 1;
+                    Some(OnceCell::from(TaskVM {
+                        id,
+                        title: Vec::decode(r)?,
+                        state: TaskState::decode(r)?,
+                        instructions_ref: Some(u32::decode(r)?),
+                    }))
+                }
+            };
+            tasks_vm.push(task_vm);
+        }
+        let next_id = u32::decode(r)?;
+        if next_id < ids_end {
+            return Err(VMError::InvalidImage);
+        }
+        Ok(Self {
+            tasks_vm,
+            next_id,
+            alive,
+            globals: Vec::decode(r)?,
+            source: StorageSource::Backend(Box::new(FileBackend::default())),
+            version: None,
+            mutations: Vec::new(),
+            recorded: HashSet::new(),
+            journal: Journal::default(),
+            transactions: Vec::new(),
+            pending: false,
+            snapshot: Snapshot::default(),
+            fork_base: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_task_id_limit() {
+        let snapshot = SnapshotWriter::default().finish(0, &vec![]).unwrap();
+        let (decoded, _, _) = Snapshot::decode(snapshot.clone()).unwrap();
+        let mut journal = journal::header(decoded.crc());
+        let task = Task {
+            id: u32::MAX - 1,
+            title: "A".to_string(),
+            state: TaskState { len: 2, state: 0 },
+            instructions: vec![],
+        };
+        journal::append_record(&Record::Create(task), &mut journal).unwrap();
+        let mut pool = InstructionsPool::default();
+        let storage = Storage::from_bytes(
+            StorageSource::Detached,
+            Some(snapshot),
+            Some(journal),
+            &mut pool,
+        );
+        assert!(matches!(storage, Err(VMError::StorageCorrupted)));
+    }
+}
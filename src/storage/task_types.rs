@@ -1,7 +1,11 @@
 use crate::errors::{VMError, VMResult};
 use crate::pools::InstructionsPool;
+use crate::storage::globals::Global;
 
-#[derive(Debug, Clone)]
+// task ids index dense slot vectors (storage, snapshot index), store with bigger id is corrupted
+pub(crate) const TASK_ID_LIMIT: u32 = 1_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Task {
     pub id: u32,
     pub title: String,
@@ -14,7 +18,8 @@ pub(crate) struct TaskVM {
     pub id: u32,
     pub title: Vec<u8>,
     pub state: TaskState,
-    pub instructions_ref: u32,
+    // None -> instructions are still in snapshot, interned on first use
+    pub instructions_ref: Option<u32>,
 }
 impl TaskVM {
     pub(crate) fn from_task(
@@ -27,14 +32,19 @@ impl TaskVM {
             id: task.id,
             title: task.title.into_bytes(),
             state: task.state,
-            instructions_ref: inst_ref,
+            instructions_ref: Some(inst_ref),
         })
     }
-    pub(crate) fn to_task(&self, instructions_pool: &InstructionsPool) -> VMResult<Task> {
-        let instructions = instructions_pool
-            .get(self.instructions_ref as usize)?
-            .to_vec();
-
+    // task decoded from snapshot, instructions are left there
+    pub(crate) fn stored(task: Task) -> Self {
+        Self {
+            id: task.id,
+            title: task.title.into_bytes(),
+            state: task.state,
+            instructions_ref: None,
+        }
+    }
+    pub(crate) fn to_task(&self, instructions: Vec<u8>) -> VMResult<Task> {
         let title = std::str::from_utf8(&self.title)
             .map_err(|_| VMError::BytesToStringConversionError)?
             .to_string();
@@ -73,7 +83,7 @@ impl TaskState {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum TaskField {
     Title = 0,
     State = 1,
@@ -93,8 +103,10 @@ impl TryFrom<u32> for TaskField {
     }
 }
 
-#[derive(Debug)]
-pub(crate) struct StorageData {
-    pub(crate) tasks: Vec<Task>,
-    pub(crate) next_id: u32,
+/// Whole task store: tasks in ascending id order, next id and globals.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StorageData {
+    pub tasks: Vec<Task>,
+    pub next_id: u32,
+    pub globals: Vec<Global>,
 }
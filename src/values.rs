@@ -15,6 +15,7 @@ TRUE = 3
 STRING = 4
 CALLDATA_REF = 5
 U32 = 6
+TASK_REF = 7
 
 
 //vector and tag represents what exactly is stored there like Vec<u8> | Vec<u32> |
@@ -25,6 +26,18 @@ U32 = 6
     1        -   11111111111 11   - 0000000000000000000000000 - 0000000000000000 -  000000   - 110
  SIGN_BIT(1) -    QNAN BITS(13)   -         OFFSET(25)        -     SIZE(16)     - UNUSED(6) - TAG BITS(3)
 
+slices bigger than u16::MAX bytes are referenced by handle (index into linear memory heap table)
+HANDLE bit set -> first 25 bits are handle, not offset. (offset, size) are resolved by LinearMemory::fat_pointer
+
+    1        -   11111111111 11   - 0000000000000000000000000 - 0000000000000000 -  00000    -    1      - 100
+ SIGN_BIT(1) -    QNAN BITS(13)   -         HANDLE(25)        -    UNUSED(16)    - UNUSED(5) - HANDLE(1) - TAG BITS(3)
+
+VEC TAGS (sign bit set):
+MAP = 1 (offset of map header, see collections/map.rs)
+LIST = 2 (offset of list header, see collections/list.rs)
+STRING = 4
+U32 = 6
+
 
 */
 
@@ -32,25 +45,30 @@ use crate::errors::{VMError, VMResult};
 
 pub type Value = u64;
 
-const U25_MAX: u32 = 1 << 25;
+pub(crate) const U25_MAX: u32 = 1 << 25;
 // quite NaN - if all those bits set => NaN tagged value of some type
 // 0111111111111100000000000000000000000000000000000000000000000000
 const QNAN: u64 = 0x7ffc000000000000;
 const SIGN_BIT: u64 = 0x8000000000000000;
 const TAG_MASK: u64 = 0b111;
+const HANDLE_BIT: u64 = 1 << 3;
 const TAG_NULL: u64 = 1;
 const TAG_FALSE: u64 = 2;
 const TAG_TRUE: u64 = 3;
 pub(crate) const TAG_STRING: u64 = 4;
 const TAG_CALLDATA: u64 = 5;
 pub(crate) const TAG_U32: u64 = 6;
+// vec tag only
+pub(crate) const TAG_MAP: u64 = 1;
+pub(crate) const TAG_LIST: u64 = 2;
+const TAG_TASK_REF: u64 = 7;
 pub(crate) const FALSE_VAL: Value = QNAN | (TAG_FALSE);
 pub(crate) const TRUE_VAL: Value = QNAN | (TAG_TRUE);
-//pub(crate) const NULL_VAL: Value = QNAN | (TAG_NULL);
+pub(crate) const NULL_VAL: Value = QNAN | (TAG_NULL);
 
 // fat poiner to a vec (offset , size)
 #[inline]
-const fn to_vec_val(offset: u32, size: u16, type_tag: u64) -> VMResult<Value> {
+pub(crate) const fn to_vec_val(offset: u32, size: u16, type_tag: u64) -> VMResult<Value> {
     if offset < U25_MAX {
         Ok((SIGN_BIT | QNAN) | ((offset as u64) << 25) | ((size as u64) << 9) | (type_tag))
     } else {
@@ -58,14 +76,24 @@ const fn to_vec_val(offset: u32, size: u16, type_tag: u64) -> VMResult<Value> {
     }
 }
 
+// handle to a slice in heap table, for slices bigger than u16::MAX
+#[inline]
+pub(crate) const fn to_handle_val(handle: u32, type_tag: u64) -> VMResult<Value> {
+    if handle < U25_MAX {
+        Ok((SIGN_BIT | QNAN) | ((handle as u64) << 25) | HANDLE_BIT | (type_tag))
+    } else {
+        Err(VMError::MSliceParamOverflow)
+    }
+}
+
 #[inline]
-pub(crate) const fn to_string_vec_val(offset: u32, size: u16) -> VMResult<Value> {
-    to_vec_val(offset, size, TAG_STRING)
+pub(crate) const fn to_map_val(offset: u32, size: u16) -> VMResult<Value> {
+    to_vec_val(offset, size, TAG_MAP)
 }
 
 #[inline]
-pub(crate) const fn to_u32_vec_val(offset: u32, size: u16) -> VMResult<Value> {
-    to_vec_val(offset, size, TAG_U32)
+pub(crate) const fn to_list_val(offset: u32, size: u16) -> VMResult<Value> {
+    to_vec_val(offset, size, TAG_LIST)
 }
 
 //shifting 18 bits (unused(15) + tag (3))
@@ -84,6 +112,11 @@ pub(crate) const fn to_calldata_val(idx: u32) -> Value {
     make_scalar_tagged(TAG_CALLDATA, idx)
 }
 
+#[inline]
+pub(crate) const fn to_task_ref_val(id: u32) -> Value {
+    make_scalar_tagged(TAG_TASK_REF, id)
+}
+
 pub(crate) const fn to_bool_val(b: bool) -> Value {
     if b { TRUE_VAL } else { FALSE_VAL }
 }
@@ -108,17 +141,27 @@ pub(crate) const fn is_vec(v: Value) -> bool {
     is_sign_bit(v) && is_qnan(v)
 }
 
-/* hiding for now
- #[inline]
+#[inline]
 pub(crate) const fn is_string_vec(v: Value) -> bool {
     is_vec(v) && raw_tag(v) == TAG_STRING
 }
 
+/* hiding for now
 #[inline]
 pub(crate) const fn is_u32_vec(v: Value) -> bool {
     is_vec(v) && raw_tag(v) == TAG_U32
 } */
 
+#[inline]
+pub(crate) const fn is_map(v: Value) -> bool {
+    is_vec(v) && raw_tag(v) == TAG_MAP
+}
+
+#[inline]
+pub(crate) const fn is_list(v: Value) -> bool {
+    is_vec(v) && raw_tag(v) == TAG_LIST
+}
+
 #[inline]
 const fn raw_tag(v: Value) -> u64 {
     v & TAG_MASK
@@ -139,10 +182,15 @@ pub(crate) const fn bool_not(v: Value) -> Value {
 }
 
 #[inline]
-const fn is_u32_val(v: Value) -> bool {
+pub(crate) const fn is_u32_val(v: Value) -> bool {
     is_scalar(v) && raw_tag(v) == TAG_U32
 }
 
+#[inline]
+pub(crate) const fn is_task_ref_val(v: Value) -> bool {
+    is_scalar(v) && raw_tag(v) == TAG_TASK_REF
+}
+
 // u32_val & string_val & calldata_val all u32
 // potentially risky, need to add validation
 // shouldn't be accesible - wrong conversions are possible - ie nan boxed TRUE_VAL and FALSE_VAL both returns 0
@@ -152,10 +200,20 @@ pub(crate) const fn to_u32(v: Value) -> u32 {
     // unused 15 bits  + tag bits 3
 }
 
+#[inline]
+pub(crate) const fn to_handle(v: Value) -> Option<u32> {
+    if is_vec(v) && (v & HANDLE_BIT) == HANDLE_BIT {
+        Some(((v >> 25) & 0x1FFFFFF) as u32)
+    } else {
+        None
+    }
+}
+
 //returns tuple u32 - offset u16- size
+// only for inline fat pointers, handles are resolved by linear memory
 #[inline]
 pub const fn to_fat_pointer(v: Value) -> VMResult<(u32, u16)> {
-    if !is_vec(v) {
+    if !is_vec(v) || to_handle(v).is_some() {
         return Err(VMError::InvalidType);
     }
     let offset = ((v >> 25) & 0x1FFFFFF) as u32;
@@ -163,6 +221,27 @@ pub const fn to_fat_pointer(v: Value) -> VMResult<(u32, u16)> {
     Ok((offset, size))
 }
 
+// task id of a task reference
+// in strict mode only TaskRef is accepted, otherwise any scalar is read as id (legacy behaviour)
+#[inline]
+pub(crate) const fn to_task_id(v: Value, strict: bool) -> VMResult<u32> {
+    if strict && !is_task_ref_val(v) {
+        return Err(VMError::TypeMismatch);
+    }
+    Ok(to_u32(v))
+}
+
+// explicit cast u32 -> TaskRef
+pub(crate) const fn cast_task_ref(v: Value) -> VMResult<Value> {
+    if is_task_ref_val(v) {
+        return Ok(v);
+    }
+    if !is_u32_val(v) {
+        return Err(VMError::InvalidType);
+    }
+    Ok(to_task_ref_val(to_u32(v)))
+}
+
 pub(crate) fn mul_checked(lhs: Value, rhs: Value) -> VMResult<Value> {
     if !is_u32_val(lhs) || !is_u32_val(rhs) {
         Err(VMError::InvalidType)
@@ -193,7 +272,7 @@ pub(crate) fn value_eq(left: Value, right: Value) -> VMResult<Value> {
         Err(VMError::TypeMismatch)
     } else {
         match tag_left {
-            TAG_U32 | TAG_CALLDATA => Ok(to_bool_val(to_u32(left) == to_u32(right))),
+            TAG_U32 | TAG_CALLDATA | TAG_TASK_REF => Ok(to_bool_val(to_u32(left) == to_u32(right))),
             TAG_TRUE | TAG_FALSE => Ok(to_bool_val(left == right)),
             _ => Err(VMError::InvalidType),
         }
@@ -223,6 +302,9 @@ pub(crate) enum ValueType {
     CallData,
     Bool,
     VecU32,
+    TaskRef,
+    Map,
+    List,
     Null,
 }
 
@@ -231,6 +313,8 @@ pub(crate) fn get_value_type(nan_boxed_val: Value) -> VMResult<ValueType> {
         return match raw_tag(nan_boxed_val) {
             TAG_U32 => Ok(ValueType::VecU32),
             TAG_STRING => Ok(ValueType::String),
+            TAG_MAP => Ok(ValueType::Map),
+            TAG_LIST => Ok(ValueType::List),
             _ => Err(VMError::InvalidType),
         };
     }
@@ -243,6 +327,7 @@ pub(crate) fn get_value_type(nan_boxed_val: Value) -> VMResult<ValueType> {
         TAG_STRING => Ok(ValueType::String),
         TAG_CALLDATA => Ok(ValueType::CallData),
         TAG_FALSE | TAG_TRUE => Ok(ValueType::Bool),
+        TAG_TASK_REF => Ok(ValueType::TaskRef),
         TAG_NULL => Ok(ValueType::Null),
         _ => Err(VMError::InvalidType),
     }
@@ -255,6 +340,10 @@ pub enum Return<'a> {
     CallData(&'a [u8]),
     Bool(bool),
     VecU32(&'a [u32]),
+    TaskRef(u32),
+    // map entries in insertion order (key, value)
+    Map(Vec<(Return<'a>, Return<'a>)>),
+    List(Vec<Return<'a>>),
     Null,
 }
 
@@ -292,4 +381,26 @@ impl<'a> Return<'a> {
             _ => Err(VMError::TypeMismatch),
         }
     }
+
+    pub fn as_map(&self) -> VMResult<&[(Return<'a>, Return<'a>)]> {
+        match self {
+            Return::Map(m) => Ok(m),
+            _ => Err(VMError::TypeMismatch),
+        }
+    }
+
+    pub fn as_list(&self) -> VMResult<&[Return<'a>]> {
+        match self {
+            Return::List(l) => Ok(l),
+            _ => Err(VMError::TypeMismatch),
+        }
+    }
+
+    // returns task id, ready to be passed to vm.print_task
+    pub fn as_task_ref(&self) -> VMResult<u32> {
+        match self {
+            Return::TaskRef(id) => Ok(*id),
+            _ => Err(VMError::TypeMismatch),
+        }
+    }
 }
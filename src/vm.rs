@@ -1,16 +1,28 @@
+use crate::builder::{SavePolicy, VMBuilder, VMConfig};
 use crate::bytecode::{helpers::*, opcodes::*};
 #[cfg(feature = "dot")]
 use crate::dot::{bin2dot::bin2dot, dot2bin::dot2bin};
 use crate::errors::{VMError, VMResult};
+use crate::image::{read_end, read_header, write_header};
 use crate::inlinevec::InlineVec;
-use crate::memory::LinearMemory;
+use crate::memory::{LinearMemory, MemoryStats};
 use crate::pools::InstructionsPool;
-use crate::storage::{storage::Storage, task_types::*};
+use crate::storage::backend::{MemoryBackend, StorageBackend, write_atomic};
+use crate::storage::bincodec::{Decode, Encode};
+use crate::storage::diff::StorageDiff;
+use crate::storage::fsck::{self, FsckReport};
+use crate::storage::storage::{Storage, StorageSource};
+use crate::storage::{globals::Global, task_types::*};
 use crate::values::*;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
 
 const STACK_LIMIT: usize = 1_000;
 const CONTROL_STACK_LIMIT: usize = 2;
 const CALL_STACK_LIMIT: usize = 2;
+// lists may contain themselves, unboxing stops at this depth
+const UNBOX_DEPTH_LIMIT: usize = 32;
 //signaling byte
 //const WO_PAYLOAD: u8 = 0;
 const W_PAYLOAD: u8 = 1;
@@ -25,6 +37,22 @@ struct InstructionsFrame {
     pc: usize,
 }
 
+impl Encode for InstructionsFrame {
+    fn encode<W: Write>(&self, w: &mut W) -> VMResult<()> {
+        self.instructions_ref.encode(w)?;
+        (self.pc as u32).encode(w)
+    }
+}
+
+impl Decode for InstructionsFrame {
+    fn decode<R: Read>(r: &mut R) -> VMResult<Self> {
+        Ok(Self {
+            instructions_ref: u32::decode(r)?,
+            pc: u32::decode(r)? as usize,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct VM {
     stack: Stack,
@@ -33,18 +61,51 @@ pub struct VM {
     instructions_pool: InstructionsPool,
     call_stack: CallStack,
     memory: LinearMemory,
+    config: VMConfig,
+    // run stopped at YIELD, next run resumes
+    suspended: bool,
+    // outermost transaction was opened by run_atomic
+    atomic: bool,
 }
 
-/// VM is NOT thread-safe.
+/// VM is Send but not Sync, VMs on different threads share storage through SharedStorage.
 impl VM {
     pub fn init(instructions: Vec<u8>) -> VMResult<Self> {
+        Self::builder().build(instructions)
+    }
+
+    // ephemeral storage: starts empty, S_SAVE keeps data in memory and never touches filesystem
+    pub fn init_in_memory(instructions: Vec<u8>) -> VMResult<Self> {
+        Self::builder()
+            .storage(MemoryBackend::new())
+            .build(instructions)
+    }
+
+    pub fn builder() -> VMBuilder {
+        VMBuilder::default()
+    }
+
+    pub(crate) fn with_config(
+        instructions: Vec<u8>,
+        config: VMConfig,
+        source: StorageSource,
+    ) -> VMResult<Self> {
         if instructions.is_empty() {
             return Err(VMError::EmptyInstructions);
         }
-        let memory = LinearMemory::new();
         let mut vm_instructions = InstructionsPool::default();
         let program_ref = vm_instructions.intern_instructions(instructions);
-        let storage = Storage::load(&mut vm_instructions)?;
+        let storage = Storage::open(source, &mut vm_instructions)?;
+        Self::with_storage(program_ref, vm_instructions, storage, config)
+    }
+
+    fn with_storage(
+        program_ref: u32,
+        instructions_pool: InstructionsPool,
+        storage: Storage,
+        config: VMConfig,
+    ) -> VMResult<Self> {
+        let memory = LinearMemory::new(config.memory_limit);
         let mut call_stack = CallStack::default();
         let call_frame = InstructionsFrame {
             instructions_ref: program_ref,
@@ -56,15 +117,225 @@ impl VM {
             stack: Stack::default(),
             control_stack: ControlStack::default(),
             storage,
-            instructions_pool: vm_instructions,
+            instructions_pool,
             call_stack,
             memory,
+            config,
+            suspended: false,
+            atomic: false,
         })
     }
 
     pub fn print_task(&self, id: u32) -> VMResult<Task> {
-        let task_vm = self.storage.get(id).ok_or(VMError::TaskNotFound(id))?;
-        task_vm.to_task(&self.instructions_pool)
+        self.storage
+            .task(id, &self.instructions_pool)?
+            .ok_or(VMError::TaskNotFound(id))
+    }
+
+    // run stopped at YIELD and is resumed by next run()
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    // writes whole machine (stacks, memory, instructions, storage) into image file, see image.rs
+    // image is written atomically (temp file, fsync, rename)
+    // open transactions are not part of image
+    pub fn snapshot<P: AsRef<Path>>(&self, path: P) -> VMResult<()> {
+        if self.storage.transactions() > 0 {
+            return Err(VMError::TransactionNotCommitted);
+        }
+        let mut w = Vec::new();
+        write_header(&mut w)?;
+        (self.config.strict as u8).encode(&mut w)?;
+        self.config.memory_limit.encode(&mut w)?;
+        (self.suspended as u8).encode(&mut w)?;
+        self.stack.encode(&mut w)?;
+        self.control_stack.encode(&mut w)?;
+        self.call_stack.encode(&mut w)?;
+        self.memory.encode(&mut w)?;
+        // tasks not decoded from storage snapshot yet get their instructions interned in image pool
+        let mut instructions_pool = self.instructions_pool.clone();
+        let mut storage = Vec::new();
+        self.storage
+            .encode_image(&mut storage, &mut instructions_pool)?;
+        instructions_pool.encode(&mut w)?;
+        w.extend_from_slice(&storage);
+        write_atomic(path.as_ref(), &w)
+    }
+
+    // restores machine from image file, suspended run is resumed by run()
+    // restored storage saves to "tasks.bin", use VM::builder().storage(..).restore(path) for other backend
+    pub fn restore<P: AsRef<Path>>(path: P) -> VMResult<Self> {
+        Self::builder().restore(path)
+    }
+
+    pub(crate) fn restore_image<P: AsRef<Path>>(
+        path: P,
+        source: StorageSource,
+        config: VMConfig,
+    ) -> VMResult<Self> {
+        let f = File::open(path).map_err(|_| VMError::StorageReadError)?;
+        let mut r = BufReader::new(f);
+        read_header(&mut r)?;
+        let config = VMConfig {
+            strict: u8::decode(&mut r)? != 0,
+            memory_limit: u32::decode(&mut r)?,
+            ..config
+        };
+        let suspended = u8::decode(&mut r)? != 0;
+        let stack = Stack::decode(&mut r)?;
+        let control_stack = ControlStack::decode(&mut r)?;
+        let call_stack = CallStack::decode(&mut r)?;
+        let memory = LinearMemory::decode(&mut r)?;
+        let mut instructions_pool = InstructionsPool::decode(&mut r)?;
+        let mut storage = Storage::decode(&mut r)?;
+        read_end(&mut r)?;
+        // values and task instructions must point into decoded memory and pool
+        memory.check_values(stack.as_slice(), |index| {
+            instructions_pool.get(index as usize).is_ok()
+        })?;
+        for instructions_ref in storage.instructions_refs() {
+            instructions_pool
+                .get(instructions_ref as usize)
+                .map_err(|_| VMError::InvalidImage)?;
+        }
+        storage.set_source(source, &mut instructions_pool)?;
+        for frame in call_stack.as_slice() {
+            instructions_pool.get(frame.instructions_ref as usize)?;
+        }
+        if call_stack.is_empty() {
+            return Err(VMError::InvalidImage);
+        }
+        Ok(Self {
+            stack,
+            control_stack,
+            storage,
+            instructions_pool,
+            call_stack,
+            memory,
+            config,
+            suspended,
+            atomic: false,
+        })
+    }
+
+    // writes storage changes since last write, S_SAVE relies on it with SavePolicy::Manual
+    // fails with TransactionNotCommitted while suspended run has transaction open
+    pub fn save(&mut self) -> VMResult<()> {
+        self.storage.flush(&self.instructions_pool, true)
+    }
+
+    // drops unsaved changes and reads store again, e.g. to save after StorageConflict
+    // fails with TransactionNotCommitted while suspended run has transaction open
+    pub fn reload(&mut self) -> VMResult<()> {
+        self.storage.reload(&mut self.instructions_pool)
+    }
+
+    // named snapshot of task store (tasks and globals, unsaved changes included) kept by backend,
+    // not to be confused with vm image (snapshot())
+    pub fn snapshot_store(&mut self, name: &str) -> VMResult<()> {
+        let retention = self.config.snapshot_retention;
+        self.storage
+            .snapshot_named(name, &self.instructions_pool, retention)
+    }
+
+    // replaces store with named snapshot, unsaved changes are dropped.
+    // it is saved right away unless SavePolicy::Manual defers it to save()
+    pub fn restore_store(&mut self, name: &str) -> VMResult<()> {
+        let immediate = self.config.save_policy != SavePolicy::Manual;
+        self.storage
+            .restore_named(name, &mut self.instructions_pool, immediate)
+    }
+
+    // names of store snapshots, oldest first
+    pub fn store_snapshots(&mut self) -> VMResult<Vec<String>> {
+        self.storage.snapshots()
+    }
+
+    // returns false if there was no snapshot of that name
+    pub fn delete_store_snapshot(&mut self, name: &str) -> VMResult<bool> {
+        self.storage.delete_snapshot(name)
+    }
+
+    // vm running the same program over copy of storage, storage of fork is never saved:
+    // its changes are listed by diff() and applied to parent by commit(parent).
+    // tasks, globals and instructions pool are cloned, forking costs as much as the store
+    // fails with TransactionNotCommitted while transaction is open
+    pub fn fork(&self) -> VMResult<Self> {
+        let program_ref = self
+            .call_stack
+            .as_slice()
+            .first()
+            .ok_or(VMError::StackUnderflow)?
+            .instructions_ref;
+        let program = self.instructions_pool.get(program_ref as usize)?.to_vec();
+        self.fork_with(program)
+    }
+
+    // fork running other program, e.g. one flipping a state to see what follows from it
+    pub fn fork_with(&self, instructions: Vec<u8>) -> VMResult<Self> {
+        if instructions.is_empty() {
+            return Err(VMError::EmptyInstructions);
+        }
+        let storage = self.storage.fork()?;
+        let mut instructions_pool = self.instructions_pool.clone();
+        let program_ref = instructions_pool.intern_instructions(instructions);
+        Self::with_storage(program_ref, instructions_pool, storage, self.config)
+    }
+
+    // tasks and globals changed by fork since it was forked, NotForked for other vm
+    pub fn diff(&self) -> VMResult<StorageDiff> {
+        self.storage.diff(&self.instructions_pool)
+    }
+
+    // applies changes of fork to parent storage, they are saved by its next save (S_SAVE, save()).
+    // fails with StorageConflict without applying anything if parent changed the same task or global
+    // after fork was taken, fork is kept so its diff() can still be inspected
+    pub fn commit(&self, parent: &mut VM) -> VMResult<()> {
+        parent.storage.refresh(&mut parent.instructions_pool)?;
+        parent.storage.merge(
+            &self.storage,
+            &self.instructions_pool,
+            &mut parent.instructions_pool,
+        )?;
+        parent.storage.publish(&parent.instructions_pool)
+    }
+
+    // drops fork and its changes, parent is not touched
+    pub fn discard(self) {}
+
+    // global slot value, Null if slot is not set
+    pub fn global(&self, index: u32) -> VMResult<Global> {
+        self.storage.global(index).cloned()
+    }
+
+    // linear memory size, peak and allocations count since the start of the last run
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.memory.stats()
+    }
+
+    // instructions pool entries: program, calldata and instructions of loaded tasks,
+    // unreachable ones are freed when next run starts
+    pub fn instructions_count(&self) -> usize {
+        self.instructions_pool.len()
+    }
+
+    // checks stored tasks without loading them into VM, report.repair(backend) writes salvaged ones
+    pub fn fsck<B: StorageBackend>(backend: &mut B) -> VMResult<FsckReport> {
+        fsck::check(backend)
+    }
+
+    // tasks and globals stored by backend (journal applied), e.g. to compare stores
+    // with StorageDiff::between or merge them with StorageMerge::three_way.
+    // save_store to the same backend fails with StorageConflict if store was written meanwhile
+    pub fn load_store<B: StorageBackend>(backend: &mut B) -> VMResult<StorageData> {
+        Storage::read_data(backend)
+    }
+
+    // replaces stored tasks and globals with data, tasks are saved in id order and next_id is
+    // raised above every id. Fails with InvalidTaskId without writing on duplicate ids
+    pub fn save_store<B: StorageBackend>(backend: &mut B, data: &StorageData) -> VMResult<()> {
+        Storage::write_data(backend, data)
     }
 
     #[cfg(feature = "dot")]
@@ -83,9 +354,86 @@ impl VM {
         bin2dot(bytecode)
     }
 
+    // error rolls back open transactions, run must not end with one open
+    // (suspended run keeps them until it is resumed)
+    // shared storage: run starts from fresh copy of shared state, changes are published when it ends
     pub fn run(&mut self) -> VMResult<Stack> {
+        if !self.suspended {
+            self.collect_instructions();
+        }
+        // run_atomic has refreshed before opening its transaction
+        if !self.suspended && !self.atomic {
+            self.storage.refresh(&mut self.instructions_pool)?;
+        }
+        let result = self.execute();
+        if self.suspended && result.is_ok() {
+            return result;
+        }
+        let atomic = std::mem::take(&mut self.atomic);
+        let result = result.and_then(|stack| self.finish(atomic).map(|()| stack));
+        if result.is_err() {
+            self.storage.rollback_all();
+            self.storage.discard();
+        }
+        result
+    }
+
+    // runs inside transaction: storage changes are kept only if run succeeds,
+    // S_SAVE is performed once on success
+    pub fn run_atomic(&mut self) -> VMResult<Stack> {
+        // resumed run continues transaction of the suspended one
+        if !self.suspended {
+            self.storage.refresh(&mut self.instructions_pool)?;
+            self.storage.begin();
+            self.atomic = true;
+        }
+        self.run()
+    }
+
+    // frees instructions pool entries unreachable from call frames, stack and tasks:
+    // replaced or deleted task instructions and calldata of previous runs
+    // (stack returned by previous run must not be unboxed after next run starts)
+    fn collect_instructions(&mut self) {
+        let frames = self
+            .call_stack
+            .as_slice()
+            .iter()
+            .map(|f| f.instructions_ref);
+        let calldata = self
+            .stack
+            .as_slice()
+            .iter()
+            .filter(|&&v| matches!(get_value_type(v), Ok(ValueType::CallData)))
+            .map(|&v| to_u32(v));
+        let tasks = self.storage.instructions_refs();
+        self.instructions_pool
+            .collect(frames.chain(calldata).chain(tasks));
+    }
+
+    fn finish(&mut self, atomic: bool) -> VMResult<()> {
+        if self.storage.transactions() > atomic as usize {
+            return Err(VMError::TransactionNotCommitted);
+        }
+        if atomic {
+            self.storage
+                .commit(&self.instructions_pool, self.immediate_save())?;
+        }
+        if self.config.save_policy == SavePolicy::EndOfRun {
+            self.storage.flush(&self.instructions_pool, false)?;
+        }
+        self.storage.publish(&self.instructions_pool)
+    }
+
+    fn immediate_save(&self) -> bool {
+        self.config.save_policy == SavePolicy::Immediate
+    }
+
+    fn execute(&mut self) -> VMResult<Stack> {
         //need to reset linear memory on run! - vm owns memory, lives only between runs
-        self.memory = LinearMemory::new();
+        // resumed run (after YIELD) keeps memory of the suspended one
+        if !std::mem::take(&mut self.suspended) {
+            self.memory.reset();
+        }
 
         let mut instructions_ref = self
             .call_stack
@@ -96,21 +444,37 @@ impl VM {
         let mut instructions = self.instructions_pool.get(instructions_ref as usize)?;
 
         while pc < instructions.len() {
+            // safe point: all live values are on the stack
+            if self.memory.needs_collect() {
+                self.memory.collect(self.stack.as_mut_slice())?;
+            }
             let op = instructions[pc];
             // println!("After {:?}: stack = {:?}", op, self.stack);
             pc += 1;
             //dbg!(&self.stack.len());
             match op {
                 PUSH_U32 => {
-                    let val = prepare_u32_from_be_checked(instructions, pc)?;
+                    let val = prepare_u32_from_le_checked(instructions, pc)?;
                     //push_stack(&mut self.stack, to_u32_val(val))?;
                     self.stack.push(to_u32_val(val))?;
                     pc += 4; //magic number
                 }
-                PUSH_CALLDATA => {
-                    let size = prepare_u16_from_be_checked(instructions, pc)? as usize;
-                    pc += 2; // for u16
-                    let calldata_vec = instructions[pc..pc + size].to_vec();
+                // wide variant carries u32 size for calldata bigger than u16::MAX
+                PUSH_CALLDATA | PUSH_CALLDATA_W => {
+                    let size = if op == PUSH_CALLDATA_W {
+                        let size = prepare_u32_from_le_checked(instructions, pc)? as usize;
+                        pc += 4;
+                        size
+                    } else {
+                        let size = prepare_u16_from_le_checked(instructions, pc)? as usize;
+                        pc += 2; // for u16
+                        size
+                    };
+                    let end = pc.checked_add(size);
+                    let calldata_vec = instructions
+                        .get(pc..end.ok_or(VMError::UnexpectedEOB)?)
+                        .ok_or(VMError::UnexpectedEOB)?
+                        .to_vec();
                     pc += size;
                     let val = self.instructions_pool.intern_instructions(calldata_vec);
                     self.stack.push(to_calldata_val(val))?;
@@ -132,7 +496,7 @@ impl VM {
 
                 MULI => {
                     let lhs = self.stack.pop()?;
-                    let rhs = prepare_u32_from_be_checked(instructions, pc)?;
+                    let rhs = prepare_u32_from_le_checked(instructions, pc)?;
                     pc += 4;
                     let res = mul_checked_i(lhs, rhs)?;
                     self.stack.push(res)?
@@ -144,7 +508,7 @@ impl VM {
                     // while should not allow on assembly carefully check, if somehow allows bigger than u8 ->
                     // -> it will trucate 3 msb and leave 1 full ie 255
                     let state = TaskState::default(max_states as u8)?;
-                    let (offset, size) = to_fat_pointer(self.stack.pop()?)?;
+                    let (offset, size) = self.memory.fat_pointer(self.stack.pop()?)?;
                     let bytes_vec: Vec<u8> = self.memory.get_slice_bytes(offset, size).into();
                     let id = self.storage.next_id;
 
@@ -152,31 +516,45 @@ impl VM {
                         id,
                         title: bytes_vec,
                         state,
-                        instructions_ref,
+                        instructions_ref: Some(instructions_ref),
                     };
-                    self.storage.add(task);
-                    //return id?
+                    self.storage.add(task)?;
+                    // strict mode returns reference to created task
+                    if self.config.strict {
+                        self.stack.push(to_task_ref_val(id))?;
+                    }
+                }
+
+                T_REF => {
+                    let val = cast_task_ref(self.stack.pop()?)?;
+                    self.stack.push(val)?;
                 }
 
                 T_GET_FIELD => {
                     let field_byte = to_u32(self.stack.pop()?);
                     let field = TaskField::try_from(field_byte)?;
-                    let id = to_u32(self.stack.pop()?);
-                    let task = &self.storage.get(id).ok_or(VMError::TaskNotFound(id))?;
+                    let id = to_task_id(self.stack.pop()?, self.config.strict)?;
+                    let task = self.storage.get(id)?.ok_or(VMError::TaskNotFound(id))?;
                     match field {
                         TaskField::Title => {
                             let bytes = task.title.as_slice();
                             let val =
                                 self.memory
-                                    .alloc(bytes.len() as u16, TAG_STRING as u8, bytes)?;
+                                    .alloc(bytes.len() as u32, TAG_STRING as u8, bytes)?;
                             self.stack.push(val)?
                         }
                         TaskField::State => {
                             self.stack.push(to_u32_val(task.state.get_state() as u32))?
                         }
 
+                        // instructions of stored task are interned on first use
                         TaskField::Instructions => {
-                            self.stack.push(to_calldata_val(task.instructions_ref))?
+                            let task_instructions_ref = self
+                                .storage
+                                .instructions_ref(id, &mut self.instructions_pool)?
+                                .ok_or(VMError::TaskNotFound(id))?;
+                            self.stack.push(to_calldata_val(task_instructions_ref))?;
+                            instructions = self.instructions_pool.get(instructions_ref as usize)?
                         }
                     }
                 }
@@ -188,33 +566,51 @@ impl VM {
                 T_SET_FIELD => {
                     let field_byte = to_u32(self.stack.pop()?);
                     let field = TaskField::try_from(field_byte)?;
-                    let id = to_u32(self.stack.pop()?);
+                    let id = to_task_id(self.stack.pop()?, self.config.strict)?;
 
-                    let task = &mut self.storage.get_mut(id)?;
                     match field {
                         TaskField::Title => {
                             let val = self.stack.pop()?;
-                            let (offset, size) = to_fat_pointer(val)?;
+                            let (offset, size) = self.memory.fat_pointer(val)?;
                             let bytes_vec: Vec<u8> =
                                 self.memory.get_slice_bytes(offset, size).into();
-                            task.title = bytes_vec
+                            self.storage.set_title(id, bytes_vec)?
                         }
                         TaskField::State => {
                             let v = to_u32(self.stack.pop()?);
-                            task.state.set_state(v)?
+                            self.storage.set_state(id, v)?
                         }
                         TaskField::Instructions => {
-                            task.instructions_ref = to_u32(self.stack.pop()?);
+                            let instructions_ref = to_u32(self.stack.pop()?);
+                            self.storage.set_instructions(id, instructions_ref)?
                         }
                     }
                     // push_stack(&mut self.stack, id)?;
                 }
                 T_DELETE => {
-                    let id = to_u32(self.stack.pop()?);
+                    let id = to_task_id(self.stack.pop()?, self.config.strict)?;
                     self.storage.delete(id)?;
                 }
-                S_SAVE => self.storage.save(&self.instructions_pool)?,
+                S_SAVE => {
+                    let immediate = self.immediate_save();
+                    self.storage.save(&self.instructions_pool, immediate)?
+                }
                 S_LEN => self.stack.push(to_u32_val(self.storage.len() as u32))?,
+                S_SNAPSHOT => {
+                    let name = snapshot_name(&self.memory, self.stack.pop()?)?;
+                    self.storage.snapshot_named(
+                        &name,
+                        &self.instructions_pool,
+                        self.config.snapshot_retention,
+                    )?
+                }
+                S_RESTORE => {
+                    let name = snapshot_name(&self.memory, self.stack.pop()?)?;
+                    let immediate = self.immediate_save();
+                    self.storage
+                        .restore_named(&name, &mut self.instructions_pool, immediate)?;
+                    instructions = self.instructions_pool.get(instructions_ref as usize)?
+                }
 
                 DO => {
                     let index = to_u32(self.stack.pop()?);
@@ -253,7 +649,7 @@ impl VM {
                 // forth style if .. then
                 JUMP_IF_FALSE => {
                     if self.stack.pop()? == FALSE_VAL {
-                        let val = prepare_u32_from_be_checked(instructions, pc)?;
+                        let val = prepare_u32_from_le_checked(instructions, pc)?;
                         //dbg!(&val);
                         pc = val as usize;
                     } else {
@@ -283,8 +679,10 @@ impl VM {
                     let eq: Value = if !is_vec(left) {
                         value_eq(left, right)?
                     } else {
-                        self.memory
-                            .is_m_slice_eq(to_fat_pointer(left)?, to_fat_pointer(right)?)
+                        self.memory.is_m_slice_eq(
+                            self.memory.fat_pointer(left)?,
+                            self.memory.fat_pointer(right)?,
+                        )
                     };
                     self.stack.push(eq)?;
                 }
@@ -298,10 +696,10 @@ impl VM {
                     let neq: Value = if !is_vec(left) {
                         value_neq(left, right)?
                     } else {
-                        bool_not(
-                            self.memory
-                                .is_m_slice_eq(to_fat_pointer(left)?, to_fat_pointer(right)?),
-                        )
+                        bool_not(self.memory.is_m_slice_eq(
+                            self.memory.fat_pointer(left)?,
+                            self.memory.fat_pointer(right)?,
+                        ))
                     };
                     self.stack.push(neq)?
                 }
@@ -319,16 +717,27 @@ impl VM {
                 }
                 // accepts size in Bytes . same for string and vec u32
                 // memory store immediate - use next bytes following opcode
-                M_STI => {
-                    let size = prepare_u16_from_be_checked(instructions, pc)?;
-                    pc += 2;
-                    let tag = instructions[pc];
+                // wide variant carries u32 size for slices bigger than u16::MAX
+                M_STI | M_STI_W => {
+                    let size = if op == M_STI_W {
+                        let size = prepare_u32_from_le_checked(instructions, pc)?;
+                        pc += 4;
+                        size
+                    } else {
+                        let size = prepare_u16_from_le_checked(instructions, pc)? as u32;
+                        pc += 2;
+                        size
+                    };
+                    let tag = *prepare_u8(instructions, pc)?;
                     pc += 1;
-                    let signaling_byte = instructions[pc];
+                    let signaling_byte = *prepare_u8(instructions, pc)?;
                     pc += 1;
                     let mut payload: &[u8] = &[];
                     if signaling_byte == W_PAYLOAD {
-                        payload = &instructions[pc..pc + size as usize];
+                        let end = pc.checked_add(size as usize);
+                        payload = instructions
+                            .get(pc..end.ok_or(VMError::UnexpectedEOB)?)
+                            .ok_or(VMError::UnexpectedEOB)?;
                         pc += size as usize;
                     }
                     let val = self.memory.alloc(size, tag, payload)?;
@@ -339,8 +748,7 @@ impl VM {
                 // size in BYTES comes from stack
                 M_ST => {
                     // pop size
-                    let size = u16::try_from(to_u32(self.stack.pop()?))
-                        .map_err(|_| VMError::MSliceParamOverflow)?;
+                    let size = to_u32(self.stack.pop()?);
                     let tag = instructions[pc];
                     pc += 1;
                     let val = self.memory.alloc(size, tag, &[])?;
@@ -354,31 +762,139 @@ impl VM {
                     let payload = to_u32(self.stack.pop()?);
                     let index = to_u32(self.stack.pop()?);
                     let value = self.stack.last().ok_or(VMError::StackUnderflow)?;
-                    let (offset, size) = to_fat_pointer(value)?;
+                    let (offset, size) = self.memory.fat_pointer(value)?;
                     let tag = tag(value)?;
                     self.memory.mut_vec(offset, size, index, payload, tag)?
                 }
 
+                MAP_NEW => {
+                    let map = self.memory.map_new()?;
+                    self.stack.push(map)?;
+                }
+
+                MAP_SET => {
+                    let value = self.stack.pop()?;
+                    let key = self.stack.pop()?;
+                    let map = self.stack.last().ok_or(VMError::StackUnderflow)?;
+                    self.memory.map_set(map, key, value)?
+                }
+
+                MAP_GET => {
+                    let key = self.stack.pop()?;
+                    let map = self.stack.pop()?;
+                    let value = self
+                        .memory
+                        .map_get(map, key)?
+                        .ok_or(VMError::MapKeyNotFound)?;
+                    self.stack.push(value)?
+                }
+
+                MAP_HAS => {
+                    let key = self.stack.pop()?;
+                    let map = self.stack.pop()?;
+                    let has = self.memory.map_get(map, key)?.is_some();
+                    self.stack.push(to_bool_val(has))?
+                }
+
+                MAP_KEYS => {
+                    let map = self.stack.pop()?;
+                    let entries = self.memory.map_entries(map)?;
+                    for (key, _) in &entries {
+                        self.stack.push(*key)?;
+                    }
+                    self.stack.push(to_u32_val(entries.len() as u32))?
+                }
+
+                LIST_NEW => {
+                    let list = self.memory.list_new()?;
+                    self.stack.push(list)?;
+                }
+
+                LIST_PUSH => {
+                    let value = self.stack.pop()?;
+                    let list = self.stack.last().ok_or(VMError::StackUnderflow)?;
+                    self.memory.list_push(list, value)?
+                }
+
+                LIST_GET => {
+                    let index = to_u32(self.stack.pop()?);
+                    let list = self.stack.pop()?;
+                    let value = self.memory.list_get(list, index)?;
+                    self.stack.push(value)?
+                }
+
+                LIST_SET => {
+                    let value = self.stack.pop()?;
+                    let index = to_u32(self.stack.pop()?);
+                    let list = self.stack.last().ok_or(VMError::StackUnderflow)?;
+                    self.memory.list_set(list, index, value)?
+                }
+
+                LIST_LEN => {
+                    let list = self.stack.pop()?;
+                    let len = self.memory.list_len(list)?;
+                    self.stack.push(to_u32_val(len))?
+                }
+
+                YIELD => {
+                    self.call_stack
+                        .last_mut()
+                        .ok_or(VMError::StackUnderflow)?
+                        .pc = pc;
+                    self.suspended = true;
+                    return Ok(self.stack.clone());
+                }
+
+                TX_BEGIN => self.storage.begin(),
+                // transaction of run_atomic is not closable by bytecode
+                TX_COMMIT | TX_ROLLBACK if self.storage.transactions() <= self.atomic as usize => {
+                    return Err(VMError::NoTransaction);
+                }
+                TX_COMMIT => {
+                    let immediate = self.immediate_save();
+                    self.storage.commit(&self.instructions_pool, immediate)?
+                }
+                TX_ROLLBACK => self.storage.rollback()?,
+
+                G_GET => {
+                    let index = to_u32(self.stack.pop()?);
+                    let global = self.storage.global(index)?;
+                    let val = self.memory.global_val(global)?;
+                    self.stack.push(val)?
+                }
+
+                // value comes first: PUSH_STRING config PUSH_U32 0 G_SET
+                G_SET => {
+                    let index = to_u32(self.stack.pop()?);
+                    let global = self.memory.to_global(self.stack.pop()?)?;
+                    self.storage.set_global(index, global)?
+                }
+
                 DROP => {
                     self.stack.pop()?;
                 }
 
                 CALL => {
-                    let id = to_u32(self.stack.pop()?);
-
-                    if let Some(task) = &self.storage.get(id) {
+                    let id = to_task_id(self.stack.pop()?, self.config.strict)?;
+
+                    // instructions of stored task are interned on first call
+                    let task_instructions_ref = self
+                        .storage
+                        .instructions_ref(id, &mut self.instructions_pool)?;
+                    instructions = self.instructions_pool.get(instructions_ref as usize)?;
+                    if let Some(task_instructions_ref) = task_instructions_ref {
                         if let Some(caller_frame) = self.call_stack.last_mut() {
                             caller_frame.pc = pc;
                         }
 
                         let frame = InstructionsFrame {
-                            instructions_ref: task.instructions_ref,
+                            instructions_ref: task_instructions_ref,
                             pc: 0,
                         };
 
                         if !self
                             .instructions_pool
-                            .get(task.instructions_ref as usize)?
+                            .get(task_instructions_ref as usize)?
                             .is_empty()
                         {
                             self.call_stack.push(frame)?;
@@ -413,12 +929,15 @@ impl VM {
     }
 
     #[inline]
-    fn unbox_value<'a>(&'a self, val: Value) -> VMResult<Return<'a>> {
+    fn unbox_value<'a>(&'a self, val: Value, depth: usize) -> VMResult<Return<'a>> {
+        if depth > UNBOX_DEPTH_LIMIT {
+            return Err(VMError::NestingTooDeep);
+        }
         match get_value_type(val)? {
             ValueType::U32 => Ok(Return::U32(to_u32(val))),
             ValueType::Bool => Ok(Return::Bool(val == TRUE_VAL)),
             ValueType::String => {
-                let (offset, size) = to_fat_pointer(val)?;
+                let (offset, size) = self.memory.fat_pointer(val)?;
                 let str = &self.memory.get_slice_as_str(offset, size)?;
                 Ok(Return::String(str))
             }
@@ -428,15 +947,46 @@ impl VM {
             }
             ValueType::VecU32 => {
                 //keep as it is for now
-                let (offset, size) = to_fat_pointer(val)?;
+                let (offset, size) = self.memory.fat_pointer(val)?;
                 let u32_slice = self.memory.get_slice_as_u32(offset, size)?;
                 Ok(Return::VecU32(u32_slice))
             }
+            ValueType::TaskRef => Ok(Return::TaskRef(to_u32(val))),
+            ValueType::Map => {
+                let entries = self.memory.map_entries(val)?;
+                let pairs = entries
+                    .into_iter()
+                    .map(|(k, v)| {
+                        Ok((
+                            self.unbox_value(k, depth + 1)?,
+                            self.unbox_value(v, depth + 1)?,
+                        ))
+                    })
+                    .collect::<VMResult<Vec<_>>>()?;
+                Ok(Return::Map(pairs))
+            }
+            ValueType::List => {
+                let items = self
+                    .memory
+                    .list_items(val)?
+                    .into_iter()
+                    .map(|v| self.unbox_value(v, depth + 1))
+                    .collect::<VMResult<Vec<_>>>()?;
+                Ok(Return::List(items))
+            }
             ValueType::Null => Ok(Return::Null),
         }
     }
 
     pub fn unbox<'a>(&'a self, values: &'a Stack) -> impl Iterator<Item = VMResult<Return<'a>>> {
-        values.as_slice().iter().map(|&v| self.unbox_value(v))
+        values.as_slice().iter().map(|&v| self.unbox_value(v, 0))
+    }
+}
+
+// name of store snapshot popped by S_SNAPSHOT and S_RESTORE
+fn snapshot_name(memory: &LinearMemory, value: Value) -> VMResult<String> {
+    match memory.to_global(value)? {
+        Global::String(name) => Ok(name),
+        _ => Err(VMError::TypeMismatch),
     }
 }
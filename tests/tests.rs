@@ -1,28 +1,47 @@
-use serial_test::serial;
-use spacydo::{Task, TaskState, VM, VMError, VMResult};
+use spacydo::{
+    FieldChange, FileBackend, FsckIssue, Global, GlobalChange, MemoryBackend, MergeConflict,
+    Return, SavePolicy, SharedStorage, StorageBackend, StorageData, StorageDiff, StorageMerge,
+    Task, TaskField, TaskState, VM, VMError, VMResult,
+};
 
 use std::fs;
+use std::path::{Path, PathBuf};
 
-fn clear_storage() {
-    let _ = fs::remove_file("tasks.bin");
+// per-test directory in temp dir, removed with its files when dropped (on panic too)
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(test: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("spacydo-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
 }
 
 #[test]
-#[serial] //?
 fn test_push_u32() {
     let bytecode = VM::dot2bin("PUSH_U32 1234567890").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed: Vec<_> = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     assert_eq!(unboxed[0].as_u32().unwrap(), 1234567890);
 }
 
 #[test]
-#[serial]
 fn test_push_string() {
-    clear_storage();
     let bytecode = VM::dot2bin("PUSH_STRING hello").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     //first string internes to 0 index
     let unboxed: Vec<_> = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
@@ -30,42 +49,38 @@ fn test_push_string() {
 }
 
 #[test]
-#[serial]
 fn test_if_then_true() {
     let bytecode = VM::dot2bin("PUSH_U32 100 PUSH_U32 100 EQ IF PUSH_U32 1 THEN").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed: Vec<_> = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     assert_eq!(unboxed[0].as_u32().unwrap(), 1);
 }
 
 #[test]
-#[serial]
 fn test_if_then_false() {
     let bytecode = VM::dot2bin("PUSH_U32 100 PUSH_U32 100 NEQ IF PUSH_U32 1 THEN").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     assert_eq!(stack.as_slice(), []);
 }
 
 #[test]
-#[serial]
 fn test_if_then_true_nested() {
     let bytecode = VM::dot2bin(
         "PUSH_U32 100 PUSH_U32 100 EQ IF PUSH_U32 1 PUSH_U32 1 EQ IF PUSH_U32 2 THEN THEN",
     )
     .unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed: Vec<_> = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     assert_eq!(unboxed[0].as_u32().unwrap(), 2);
 }
 
 #[test]
-#[serial]
 fn test_if_then_false_nested() {
     let bytecode = VM::dot2bin("PUSH_U32 100 PUSH_U32 99 EQ IF PUSH_U32 1 PUSH_U32 1 EQ IF PUSH_U32 2 THEN THEN PUSH_U32 3").unwrap();
-    let mut vm = VM::init(bytecode).unwrap(); // only 3 on stack, no 2 since if drops and jumps to then
+    let mut vm = VM::init_in_memory(bytecode).unwrap(); // only 3 on stack, no 2 since if drops and jumps to then
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     assert_eq!(unboxed[0].as_u32().unwrap(), 3);
@@ -73,12 +88,19 @@ fn test_if_then_false_nested() {
 
 // instruction disassembly test, probably remove later
 #[test]
-#[serial] //?
+fn test_disassembly_calldata() {
+    let instructions = "PUSH_STRING A PUSH_MAX_STATES 2 PUSH_CALLDATA [ PUSH_U32 1 END_CALL ] T_CREATE PUSH_CALLDATA [ ] DROP";
+    let bytecode = VM::dot2bin(instructions).unwrap();
+    let vm = VM::init_in_memory(bytecode).unwrap();
+    assert_eq!(vm.bin2dot().unwrap(), instructions);
+}
+
+#[test]
 fn test_disassembly_if_then() {
     let instructions =
         "PUSH_U32 100 PUSH_U32 100 EQ IF PUSH_U32 1 PUSH_U32 1 EQ IF PUSH_U32 2 THEN THEN";
     let bytecode = VM::dot2bin(instructions).unwrap();
-    let vm = VM::init(bytecode).unwrap();
+    let vm = VM::init_in_memory(bytecode).unwrap();
     let disassembled_bytecode = vm.bin2dot().unwrap();
     dbg!(&instructions);
     dbg!(&disassembled_bytecode);
@@ -86,10 +108,9 @@ fn test_disassembly_if_then() {
 }
 
 #[test]
-#[serial] //?
 fn test_dup() {
     let bytecode = VM::dot2bin("PUSH_U32 100 DUP").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     let stack_u32: Vec<u32> = unboxed.iter().map(|v| v.as_u32().unwrap()).collect();
@@ -97,10 +118,9 @@ fn test_dup() {
 }
 
 #[test]
-#[serial] //?
 fn test_swap() {
     let bytecode = VM::dot2bin("PUSH_U32 1 PUSH_U32 2 SWAP").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     let stack_u32: Vec<u32> = unboxed.iter().map(|v| v.as_u32().unwrap()).collect();
@@ -108,71 +128,61 @@ fn test_swap() {
 }
 
 #[test]
-#[serial] //?
 fn test_dup_stack_underflow() {
     let bytecode = VM::dot2bin("DUP").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let result = vm.run();
     assert!(matches!(result, Err(VMError::StackUnderflow)));
 }
 
 #[test]
-#[serial] //?
 fn test_eq_true() {
     let bytecode = VM::dot2bin("PUSH_U32 161 PUSH_U32 161 EQ").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     assert!(unboxed[0].as_bool().unwrap());
 }
 
 #[test]
-#[serial]
 fn test_neq_true() {
-    clear_storage();
     let bytecode = VM::dot2bin("PUSH_U32 162 PUSH_U32 222 NEQ").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     assert!(unboxed[0].as_bool().unwrap());
 }
 
 #[test]
-#[serial]
 fn test_lt_true() {
-    clear_storage();
     let bytecode = VM::dot2bin("PUSH_U32 0 PUSH_U32 1 LT").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     assert!(unboxed[0].as_bool().unwrap());
 }
 
 #[test]
-#[serial]
 fn test_gt_true() {
-    clear_storage();
     let bytecode = VM::dot2bin("PUSH_U32 1 PUSH_U32 0 GT").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     assert!(unboxed[0].as_bool().unwrap());
 }
 
 #[test]
-#[serial] //?
 fn test_drop_if_true() {
     let bytecode = VM::dot2bin("PUSH_U32 999 PUSH_U32 2 PUSH_U32 1 GT IF DROP THEN").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     assert_eq!(stack.as_slice(), []); // 999 was dropped
 }
 
 #[test]
-#[serial] //?
 fn test_drop_if_false() {
     let bytecode = VM::dot2bin("PUSH_U32 999 PUSH_U32 2 PUSH_U32 3 GT IF DROP THEN").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     assert_eq!(unboxed[0].as_u32().unwrap(), 999);
@@ -180,14 +190,13 @@ fn test_drop_if_false() {
 
 //memory test
 #[test]
-#[serial] //?
 fn test_write_memory() {
     // vec u32 0..400 (100*4 bytes size) => in loop 0..100 fills slice with loop index  => stack contains slice (0,100) , memory vec![0..100]
     let bytecode = VM::dot2bin(
         "NEW_VEC_U32_I 400 PUSH_U32 100 PUSH_U32 0 DO LOOP_INDEX LOOP_INDEX M_MUTA LOOP",
     )
     .unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     // since vec u32 -> each elemen u32 == 4 bytes -> 100*4
@@ -199,13 +208,12 @@ fn test_write_memory() {
 }
 
 #[test]
-#[serial]
 fn test_write_memory_null_vals() {
     let bytecode = VM::dot2bin(
         "PUSH_U32 5 MULI 4 NEW_VEC_U32 PUSH_U32 1 PUSH_U32 1 M_MUTA PUSH_U32 3 PUSH_U32 3 M_MUTA",
     )
     .unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let raw_stack = vm.run().unwrap();
     assert_eq!(
         vm.unbox(&raw_stack)
@@ -219,42 +227,122 @@ fn test_write_memory_null_vals() {
 }
 
 #[test]
-#[serial]
-// max vec u32 size value is 2^16/4
+// max vec size is limited by 25 bit memory offset space (2^25 bytes)
 fn test_write_memory_error() {
-    let bytecode = VM::dot2bin("PUSH_U32 20000 MULI 4 NEW_VEC_U32").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let bytecode = VM::dot2bin("PUSH_U32 10000000 MULI 4 NEW_VEC_U32").unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let err = vm.run();
     assert!(matches!(err, Err(VMError::MSliceParamOverflow)));
 }
 
 #[test]
-#[serial]
+// vector bigger than 64 KiB is referenced by handle
+fn test_write_memory_large_vec() {
+    let bytecode = VM::dot2bin(
+        "PUSH_U32 100000 MULI 4 NEW_VEC_U32 PUSH_U32 100000 PUSH_U32 0 DO LOOP_INDEX LOOP_INDEX M_MUTA LOOP",
+    )
+    .unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    let right: Vec<u32> = (0..100000).collect();
+    assert_eq!(unboxed[0].as_vec_u32().unwrap(), right);
+}
+
+#[test]
+fn test_large_string_eq() {
+    let long = "a".repeat(70_000);
+    let ops = format!("PUSH_STRING {long} PUSH_STRING {long} EQ PUSH_STRING {long}");
+    let bytecode = VM::dot2bin(&ops).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert!(unboxed[0].as_bool().unwrap());
+    assert_eq!(unboxed[1].as_str().unwrap(), long);
+}
+
+#[test]
+fn test_disassembly_large_string_and_calldata() {
+    let long = "b".repeat(70_000);
+    let calldata = "PUSH_U32 1 ".repeat(14_000);
+    let instructions =
+        format!("PUSH_STRING {long} PUSH_MAX_STATES 2 PUSH_CALLDATA [ {calldata}] T_CREATE");
+    let bytecode = VM::dot2bin(&instructions).unwrap();
+    let vm = VM::init_in_memory(bytecode).unwrap();
+    assert_eq!(vm.bin2dot().unwrap(), instructions);
+}
+
+#[test]
+fn test_truncated_wide_operands() {
+    let truncated = |bytecode: Vec<u8>| {
+        let mut vm = VM::init_in_memory(bytecode).unwrap();
+        matches!(vm.run(), Err(VMError::UnexpectedEOB))
+    };
+    // PUSH_CALLDATA_W with size past the end of instructions
+    assert!(truncated(vec![0x2c, 0xff, 0xff, 0x00, 0x00]));
+    assert!(truncated(vec![0x2c, 0xff, 0xff, 0xff, 0xff, 0x01]));
+    // M_STI_W missing tag, sign byte, then payload
+    assert!(truncated(vec![0x2b, 0x04, 0x00, 0x00, 0x00]));
+    assert!(truncated(vec![0x2b, 0x04, 0x00, 0x00, 0x00, 0x04]));
+    assert!(truncated(vec![
+        0x2b, 0xff, 0xff, 0x00, 0x00, 0x04, 0x01, 0x61
+    ]));
+}
+
+#[test]
+fn test_save_and_load_large_task() {
+    let backend = MemoryBackend::new();
+    let long = "c".repeat(100_000);
+    let calldata = "PUSH_U32 1 DROP ".repeat(14_000);
+    let ops = format!(
+        "PUSH_STRING {long} PUSH_MAX_STATES 2 PUSH_CALLDATA [ {calldata}PUSH_U32 7 END_CALL ] T_CREATE S_SAVE"
+    );
+    let bytecode = VM::dot2bin(&ops).unwrap();
+    VM::builder()
+        .storage(backend.clone())
+        .build(bytecode)
+        .unwrap()
+        .run()
+        .unwrap();
+
+    let bytecode = VM::dot2bin("PUSH_U32 0 PUSH_TASK_FIELD 0 T_GET_FIELD PUSH_U32 0 CALL").unwrap();
+    let mut vm = VM::builder()
+        .storage(backend.clone())
+        .build(bytecode)
+        .unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(unboxed[0].as_str().unwrap(), long);
+    assert_eq!(unboxed[1].as_u32().unwrap(), 7);
+    assert_eq!(
+        vm.print_task(0).unwrap().instructions.len(),
+        14_000 * 6 + 5 + 1
+    );
+}
+
+#[test]
 // mul overflow test
 fn test_mul_overflow() {
     let bytecode = VM::dot2bin("PUSH_U32 2 PUSH_U32 4294967295 MUL").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let err = vm.run();
     assert!(matches!(err, Err(VMError::MultiplicationOverflowed)));
 }
 
 #[test]
-#[serial]
 // mul overflow test
 fn test_muli_overflow() {
     let bytecode = VM::dot2bin("PUSH_U32 4294967295 MULI 2").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let err = vm.run();
     assert!(matches!(err, Err(VMError::MultiplicationOverflowed)));
 }
 
 #[test]
-#[serial]
 fn test_create_task() {
-    clear_storage();
     let ops = "PUSH_STRING TestTask PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE";
     let bytecode = VM::dot2bin(ops).unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     vm.run().unwrap();
     let printed_task = vm.print_task(0).unwrap();
     let test_task = Task {
@@ -269,13 +357,11 @@ fn test_create_task() {
 }
 
 #[test]
-#[serial]
 fn test_get_task_field_title() {
-    clear_storage();
     let ops = "PUSH_STRING MyTask1 PUSH_MAX_STATES 2 PUSH_CALLDATA [ ] T_CREATE \
                    PUSH_U32 0 PUSH_TASK_FIELD 0 T_GET_FIELD";
     let bytecode = VM::dot2bin(ops).unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let stack_slice = stack.as_slice();
     // Should get title string index
@@ -285,14 +371,11 @@ fn test_get_task_field_title() {
 }
 
 #[test]
-#[serial]
 fn test_get_task_field_status() {
-    clear_storage();
-
     let ops = "PUSH_STRING MyTask1 PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
                    PUSH_U32 0 PUSH_TASK_FIELD 1 T_GET_FIELD";
     let bytecode = VM::dot2bin(ops).unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     assert_eq!(unboxed[0].as_u32().unwrap(), 0);
@@ -301,41 +384,35 @@ fn test_get_task_field_status() {
 }
 
 #[test]
-#[serial]
 fn test_set_task_field_status() {
-    clear_storage();
     let ops = "PUSH_STRING TestTask PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
                    PUSH_STATE 2 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD \
                    PUSH_U32 0 PUSH_TASK_FIELD 1 T_GET_FIELD";
     let bytecode = VM::dot2bin(ops).unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     assert_eq!(unboxed[0].as_u32().unwrap(), 2);
 }
 
 #[test]
-#[serial]
 fn test_delete_task() {
-    clear_storage();
     let ops = "PUSH_STRING TaskToDelete PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
                    PUSH_U32 0 T_DELETE";
     let bytecode = VM::dot2bin(ops).unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let _stack = vm.run().unwrap();
     let result = vm.print_task(0);
     assert!(matches!(result, Err(VMError::TaskNotFound(0))));
 }
 
 #[test]
-#[serial]
 fn test_task_with_simple_calldata() {
-    clear_storage();
     let ops = "PUSH_STRING TestTask PUSH_MAX_STATES 3 \
                PUSH_CALLDATA [ PUSH_U32 42 END_CALL ] T_CREATE \
                PUSH_U32 0 DUP CALL"; // DUP is to keep task id
     let bytecode = VM::dot2bin(ops).unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     let stack_u32: Vec<u32> = unboxed.iter().map(|v| v.as_u32().unwrap()).collect();
@@ -344,46 +421,39 @@ fn test_task_with_simple_calldata() {
 
 //assemble error handling
 #[test]
-#[serial]
 fn test_task_not_found() {
-    clear_storage();
     let bytecode = VM::dot2bin("PUSH_U32 99999 PUSH_TASK_FIELD 0 T_GET_FIELD").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let result = vm.run();
     assert!(matches!(result, Err(VMError::TaskNotFound(99999))));
 }
 
 #[test]
-#[serial] //?
 fn test_push_u32_overflow() {
     let bytecode = VM::dot2bin("PUSH_U32 4294967296").unwrap_err();
     assert!(matches!(bytecode, VMError::InvalidUINT { .. }));
 }
 
 #[test]
-#[serial] //?
 fn test_empty_instructions() {
     let bytecode: Vec<u8> = Vec::new();
-    let result = VM::init(bytecode);
+    let result = VM::init_in_memory(bytecode);
     assert!(matches!(result, Err(VMError::EmptyInstructions)));
 }
 
 #[test]
-#[serial] //?
 fn test_unknown_opcode() {
     let bytecode = VM::dot2bin("INVALID_OP");
     assert!(matches!(bytecode, Err(VMError::UnknownOpcode { .. })));
 }
 
 #[test]
-#[serial] //?
 fn test_missing_u32_val() {
     let bytecode = VM::dot2bin("PUSH_U32");
     assert!(matches!(bytecode, Err(VMError::UnexpectedEOI { .. })));
 }
 
 #[test]
-#[serial] //?
 fn test_malformed_calldata_missing_start_bracket() {
     let bytecode = VM::dot2bin("PUSH_CALLDATA PUSH_U32 1 ");
     assert!(matches!(
@@ -396,21 +466,18 @@ fn test_malformed_calldata_missing_start_bracket() {
 }
 
 #[test]
-#[serial] //?
 fn test_malformed_if_then_missing_if() {
     let bytecode = VM::dot2bin("PUSH_U32 1 PUSH_U32 1 EQ PUSH_U32 3 THEN");
     assert!(matches!(bytecode, Err(VMError::StackUnderflow)));
 }
 
 #[test]
-#[serial] //?
 fn test_malformed_if_then_missing_then() {
     let bytecode = VM::dot2bin("PUSH_U32 1 PUSH_U32 1 EQ IF PUSH_U32 3");
     assert!(matches!(bytecode, Err(VMError::MalformedIfThen { .. })));
 }
 
 #[test]
-#[serial] //?
 fn test_eoi_calldata_missing_end_bracket() {
     let bytecode = VM::dot2bin("PUSH_CALLDATA [ PUSH_U32 1 ");
     assert!(matches!(
@@ -423,23 +490,21 @@ fn test_eoi_calldata_missing_end_bracket() {
 }
 
 #[test]
-#[serial] //?
 fn test_invalid_status() {
     let ops = "PUSH_STRING Task PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
         PUSH_STATE 99 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD";
     let bytecode = VM::dot2bin(ops).unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let result = vm.run();
     assert!(matches!(result, Err(VMError::InvalidStatus(99))));
 }
 
 #[test]
-#[serial]
 fn nested_loop_test() {
     let bytecode =
         VM::dot2bin("PUSH_U32 2 PUSH_U32 0 DO PUSH_U32 3 PUSH_U32 0 DO LOOP_INDEX LOOP LOOP")
             .unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let raw_stack = vm.run().unwrap();
     let unboxed = vm.unbox(&raw_stack).collect::<VMResult<Vec<_>>>().unwrap();
     let result_vec: Vec<u32> = unboxed.into_iter().map(|v| v.as_u32().unwrap()).collect();
@@ -447,39 +512,35 @@ fn nested_loop_test() {
 }
 
 #[test]
-#[serial]
 fn nested_loop_overflow() {
     //current Control stack limit is 2
     let bytecode = VM::dot2bin("PUSH_U32 2 PUSH_U32 0 DO PUSH_U32 3 PUSH_U32 0 DO PUSH_U32 4 PUSH_U32 0 DO LOOP_INDEX LOOP LOOP LOOP").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let result = vm.run();
     assert!(matches!(result, Err(VMError::StackOverflow)));
 }
 
 #[test]
-#[serial] //?
 fn test_stack_overflow() {
     //loop 1_000_001 times push 99 - should stack overflow since limit 1 mil
     let bytecode = VM::dot2bin("PUSH_U32 1000001 PUSH_U32 0 DO PUSH_U32 99 LOOP").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let result = vm.run();
     assert!(matches!(result, Err(VMError::StackOverflow)));
 }
 
 #[test]
-#[serial] //?
 fn test_type_mismatch_eq() {
     let bytecode = VM::dot2bin("PUSH_U32 1 PUSH_STRING aaa EQ").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let result = vm.run();
     assert!(matches!(result, Err(VMError::TypeMismatch)));
 }
 
 #[test]
-#[serial] //?
 fn test_invalid_type_lt() {
     let bytecode = VM::dot2bin("PUSH_STRING aa PUSH_STRING aaa LT").unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let result = vm.run();
     assert!(matches!(result, Err(VMError::InvalidType)));
 }
@@ -488,14 +549,12 @@ fn test_invalid_type_lt() {
 
 // Create task with title TargetTask -> Find task by id -> get it's task field 0 (Title) value -> push refference title TargetTask -> Compare string values -> drop task from stack if true
 #[test]
-#[serial]
 fn test_conditional_task_filtering() {
-    clear_storage();
     let ops = "PUSH_STRING TargetTask PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
                    PUSH_U32 0 DUP PUSH_TASK_FIELD 0 T_GET_FIELD \
                    PUSH_STRING TargetTask EQ IF DROP THEN";
     let bytecode = VM::dot2bin(ops).unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let stack_slice = stack.as_slice();
     //dbg!(&stack);
@@ -504,15 +563,13 @@ fn test_conditional_task_filtering() {
 
 // create 3 tasks -> loop over tasks and push index (same as task id since based on s_len) to stack
 #[test]
-#[serial]
 fn test_multiple_tasks_iteration() {
-    clear_storage();
     let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
                    PUSH_STRING B PUSH_MAX_STATES 4 PUSH_CALLDATA [ ] T_CREATE \
                    PUSH_STRING C PUSH_MAX_STATES 5 PUSH_CALLDATA [ ] T_CREATE \
                    S_LEN PUSH_U32 0 DO LOOP_INDEX LOOP";
     let bytecode = VM::dot2bin(ops).unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     let stack_u32: Vec<u32> = unboxed.iter().map(|v| v.as_u32().unwrap()).collect();
@@ -520,9 +577,7 @@ fn test_multiple_tasks_iteration() {
 }
 
 #[test]
-#[serial]
 fn test_task_creates_subtask() {
-    clear_storage();
     // Task with calldata that creates another task
     let ops = "PUSH_STRING Parent PUSH_MAX_STATES 3 \
                PUSH_CALLDATA [ PUSH_STRING Child PUSH_MAX_STATES 2 PUSH_CALLDATA [ ] T_CREATE END_CALL ] \
@@ -530,29 +585,34 @@ fn test_task_creates_subtask() {
                PUSH_U32 0 CALL \
                S_LEN";
     let bytecode = VM::dot2bin(ops).unwrap();
-    let mut vm = VM::init(bytecode).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
     let stack = vm.run().unwrap();
     let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
     assert_eq!(unboxed[0].as_u32().unwrap(), 2);
 }
 
 #[test]
-#[serial]
 fn test_save_and_load() {
-    clear_storage();
+    let backend = MemoryBackend::new();
     // Create and save 2 tasks
     {
         let ops = "PUSH_STRING Task1 PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
                        PUSH_STRING Task2 PUSH_MAX_STATES 2 PUSH_CALLDATA [ ] T_CREATE \
                        S_SAVE";
         let bytecode = VM::dot2bin(ops).unwrap();
-        let mut vm = VM::init(bytecode).unwrap();
+        let mut vm = VM::builder()
+            .storage(backend.clone())
+            .build(bytecode)
+            .unwrap();
         vm.run().unwrap();
     }
     // Load
     {
         let bytecode = VM::dot2bin("S_LEN").unwrap();
-        let mut vm = VM::init(bytecode).unwrap();
+        let mut vm = VM::builder()
+            .storage(backend.clone())
+            .build(bytecode)
+            .unwrap();
         let stack = vm.run().unwrap();
         let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
         assert_eq!(unboxed[0].as_u32().unwrap(), 2);
@@ -560,9 +620,8 @@ fn test_save_and_load() {
 }
 
 #[test]
-#[serial]
 fn test_delete_complex() {
-    clear_storage();
+    let backend = MemoryBackend::new();
     // Create and save 3 tasks
     {
         let ops = "PUSH_STRING Task1 PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
@@ -570,13 +629,19 @@ fn test_delete_complex() {
                        PUSH_STRING Task3 PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE
                        S_SAVE";
         let bytecode = VM::dot2bin(ops).unwrap();
-        let mut vm = VM::init(bytecode).unwrap();
+        let mut vm = VM::builder()
+            .storage(backend.clone())
+            .build(bytecode)
+            .unwrap();
         vm.run().unwrap();
     }
     // Load
     {
         let bytecode = VM::dot2bin("S_LEN").unwrap();
-        let mut vm = VM::init(bytecode).unwrap();
+        let mut vm = VM::builder()
+            .storage(backend.clone())
+            .build(bytecode)
+            .unwrap();
         let stack = vm.run().unwrap();
         let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
         assert_eq!(unboxed[0].as_u32().unwrap(), 3);
@@ -586,7 +651,10 @@ fn test_delete_complex() {
         //deleting 2nd task
         let ops_delete = "PUSH_U32 1 T_DELETE S_SAVE";
         let bytecode = VM::dot2bin(ops_delete).unwrap();
-        let mut vm = VM::init(bytecode).unwrap();
+        let mut vm = VM::builder()
+            .storage(backend.clone())
+            .build(bytecode)
+            .unwrap();
         vm.run().unwrap();
         let t0 = vm.print_task(0);
         let t1 = vm.print_task(1);
@@ -597,3 +665,2002 @@ fn test_delete_complex() {
         // after deletion tasks_vm : task1 , none, task2
     }
 }
+
+// strict mode / TaskRef
+#[test]
+fn test_strict_create_returns_task_ref() {
+    let ops = "PUSH_STRING RefTask PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::builder()
+        .storage(MemoryBackend::new())
+        .strict(true)
+        .build(bytecode)
+        .unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(unboxed[0], Return::TaskRef(0));
+    let id = unboxed[0].as_task_ref().unwrap();
+    assert_eq!(vm.print_task(id).unwrap().title, "RefTask");
+}
+
+#[test]
+fn test_strict_task_ref_get_field() {
+    let ops = "PUSH_STRING RefTask PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_TASK_FIELD 1 T_GET_FIELD";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::builder()
+        .storage(MemoryBackend::new())
+        .strict(true)
+        .build(bytecode)
+        .unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(unboxed[0].as_u32().unwrap(), 0);
+}
+
+#[test]
+fn test_strict_rejects_u32_id() {
+    let ops = "PUSH_STRING RefTask PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_U32 0 T_DELETE";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::builder()
+        .storage(MemoryBackend::new())
+        .strict(true)
+        .build(bytecode)
+        .unwrap();
+    assert!(matches!(vm.run(), Err(VMError::TypeMismatch)));
+}
+
+#[test]
+fn test_strict_t_ref_cast() {
+    let ops = "PUSH_STRING RefTask PUSH_MAX_STATES 3 PUSH_CALLDATA [ PUSH_U32 7 END_CALL ] T_CREATE \
+               DROP PUSH_U32 0 T_REF CALL";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::builder()
+        .storage(MemoryBackend::new())
+        .strict(true)
+        .build(bytecode)
+        .unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(unboxed[0].as_u32().unwrap(), 7);
+}
+
+#[test]
+fn test_task_ref_eq() {
+    let bytecode = VM::dot2bin("PUSH_U32 3 T_REF PUSH_U32 3 T_REF EQ").unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert!(unboxed[0].as_bool().unwrap());
+}
+
+// map tests
+#[test]
+fn test_map_get_u32_key() {
+    let ops = "MAP_NEW PUSH_U32 0 PUSH_STRING red MAP_SET PUSH_U32 1 PUSH_STRING green MAP_SET \
+               PUSH_U32 1 MAP_GET";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(unboxed[0].as_str().unwrap(), "green");
+}
+
+#[test]
+fn test_map_string_key_overwrite() {
+    let ops = "MAP_NEW PUSH_STRING 0110 PUSH_U32 6 MAP_SET PUSH_STRING 0110 PUSH_U32 7 MAP_SET \
+               DUP PUSH_STRING 0110 MAP_GET SWAP PUSH_STRING 1111 MAP_HAS";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(unboxed[0].as_u32().unwrap(), 7);
+    assert!(!unboxed[1].as_bool().unwrap());
+}
+
+#[test]
+fn test_map_grows_and_keys() {
+    // 10 entries > initial capacity
+    let ops = "MAP_NEW PUSH_U32 10 PUSH_U32 0 DO LOOP_INDEX LOOP_INDEX MULI 2 MAP_SET LOOP \
+               DUP PUSH_U32 9 MAP_GET SWAP MAP_KEYS";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    let stack_u32: Vec<u32> = unboxed.iter().map(|v| v.as_u32().unwrap()).collect();
+    let mut expected = vec![18];
+    expected.extend(0..10);
+    expected.push(10);
+    assert_eq!(stack_u32, expected);
+}
+
+#[test]
+fn test_map_unbox() {
+    let ops = "MAP_NEW PUSH_STRING on PUSH_U32 1 MAP_SET PUSH_STRING off PUSH_U32 0 MAP_SET";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(
+        unboxed[0].as_map().unwrap(),
+        &[
+            (Return::String("on"), Return::U32(1)),
+            (Return::String("off"), Return::U32(0))
+        ]
+    );
+}
+
+#[test]
+fn test_map_key_not_found() {
+    let bytecode = VM::dot2bin("MAP_NEW PUSH_U32 3 MAP_GET").unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    assert!(matches!(vm.run(), Err(VMError::MapKeyNotFound)));
+}
+
+#[test]
+fn test_map_invalid_key() {
+    let bytecode = VM::dot2bin("MAP_NEW PUSH_U32 1 PUSH_U32 1 EQ PUSH_U32 3 MAP_SET").unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    assert!(matches!(vm.run(), Err(VMError::InvalidType)));
+}
+
+// list tests
+#[test]
+fn test_list_of_task_tuples() {
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_STRING B PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_STATE 2 PUSH_U32 1 PUSH_TASK_FIELD 1 T_SET_FIELD \
+               LIST_NEW S_LEN PUSH_U32 0 DO \
+               LIST_NEW LOOP_INDEX LIST_PUSH \
+               LOOP_INDEX PUSH_TASK_FIELD 0 T_GET_FIELD LIST_PUSH \
+               LOOP_INDEX PUSH_TASK_FIELD 1 T_GET_FIELD LIST_PUSH \
+               LIST_PUSH LOOP";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(
+        unboxed[0].as_list().unwrap(),
+        &[
+            Return::List(vec![Return::U32(0), Return::String("A"), Return::U32(0)]),
+            Return::List(vec![Return::U32(1), Return::String("B"), Return::U32(2)]),
+        ]
+    );
+}
+
+#[test]
+fn test_list_get_set_len() {
+    let ops = "LIST_NEW PUSH_U32 1 LIST_PUSH NEW_VEC_U32_I 8 LIST_PUSH \
+               PUSH_U32 0 PUSH_STRING x LIST_SET DUP PUSH_U32 0 LIST_GET SWAP LIST_LEN";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(unboxed[0].as_str().unwrap(), "x");
+    assert_eq!(unboxed[1].as_u32().unwrap(), 2);
+}
+
+#[test]
+fn test_list_out_of_bounds() {
+    let bytecode = VM::dot2bin("LIST_NEW PUSH_U32 0 LIST_GET").unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    assert!(matches!(
+        vm.run(),
+        Err(VMError::MSliceOutOfBounds { index: 0, size: 0 })
+    ));
+}
+
+#[test]
+fn test_list_self_reference_unbox() {
+    let bytecode = VM::dot2bin("LIST_NEW DUP LIST_PUSH").unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    let result = vm.unbox(&stack).next().unwrap();
+    assert!(matches!(result, Err(VMError::NestingTooDeep)));
+}
+
+// memory limit and accounting
+#[test]
+fn test_memory_limit_exceeded() {
+    let bytecode = VM::dot2bin("PUSH_U32 1000 MULI 4 NEW_VEC_U32").unwrap();
+    let mut vm = VM::builder()
+        .storage(MemoryBackend::new())
+        .memory_limit(1024)
+        .build(bytecode)
+        .unwrap();
+    assert!(matches!(
+        vm.run(),
+        Err(VMError::MemoryLimitExceeded {
+            size: 4000,
+            limit: 1024
+        })
+    ));
+}
+
+#[test]
+fn test_memory_limit_within() {
+    let bytecode = VM::dot2bin("PUSH_U32 256 MULI 4 NEW_VEC_U32").unwrap();
+    let mut vm = VM::builder()
+        .storage(MemoryBackend::new())
+        .memory_limit(1024)
+        .build(bytecode)
+        .unwrap();
+    assert!(vm.run().is_ok());
+}
+
+#[test]
+fn test_memory_stats() {
+    let bytecode = VM::dot2bin("PUSH_STRING abc NEW_VEC_U32_I 8 PUSH_STRING de").unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    vm.run().unwrap();
+    // "abc" 0..3, vec aligned to 4: 4..12, "de" 12..14
+    let stats = vm.memory_stats();
+    assert_eq!(stats.size, 14);
+    assert_eq!(stats.peak, 14);
+    assert_eq!(stats.allocations, 3);
+    // memory is reset on every run
+    vm.run().unwrap();
+    assert_eq!(vm.memory_stats().allocations, 3);
+}
+
+#[test]
+fn test_gc_reclaims_loop_garbage() {
+    let ops = "PUSH_STRING hello PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_U32 20000 PUSH_U32 0 DO \
+               PUSH_U32 0 PUSH_TASK_FIELD 0 T_GET_FIELD PUSH_STRING hello EQ DROP LOOP";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::builder()
+        .storage(MemoryBackend::new())
+        .memory_limit(4096)
+        .build(bytecode)
+        .unwrap();
+    assert!(vm.run().is_ok());
+    let stats = vm.memory_stats();
+    assert!(stats.collections > 0);
+    assert!(stats.peak <= 4096);
+}
+
+#[test]
+fn test_gc_keeps_live_values() {
+    // garbage below live values forces them to move on compaction
+    let ops = "PUSH_STRING junk PUSH_STRING keep SWAP DROP \
+               PUSH_STRING junk NEW_VEC_U32_I 80000 SWAP DROP \
+               LIST_NEW PUSH_STRING inner LIST_PUSH NEW_VEC_U32_I 8 LIST_PUSH \
+               MAP_NEW PUSH_STRING key PUSH_U32 7 MAP_SET \
+               LIST_NEW DUP LIST_PUSH \
+               PUSH_U32 20000 PUSH_U32 0 DO PUSH_STRING garbage DROP LOOP \
+               LIST_LEN";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::builder()
+        .storage(MemoryBackend::new())
+        .memory_limit(1 << 18)
+        .build(bytecode)
+        .unwrap();
+    let stack = vm.run().unwrap();
+    assert!(vm.memory_stats().collections > 0);
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(unboxed[0].as_str().unwrap(), "keep");
+    assert_eq!(unboxed[1].as_vec_u32().unwrap().len(), 20000);
+    let list = unboxed[2].as_list().unwrap();
+    assert_eq!(list[0].as_str().unwrap(), "inner");
+    assert_eq!(list[1].as_vec_u32().unwrap(), &[0, 0]);
+    let map = unboxed[3].as_map().unwrap();
+    assert_eq!(map[0].0.as_str().unwrap(), "key");
+    assert_eq!(map[0].1.as_u32().unwrap(), 7);
+    // list containing itself survives collection
+    assert_eq!(unboxed[4].as_u32().unwrap(), 1);
+}
+
+#[test]
+fn test_bytecode_little_endian() {
+    // PUSH_U32 258
+    let bytecode = VM::dot2bin("PUSH_U32 258").unwrap();
+    assert_eq!(bytecode, vec![0x01, 0x02, 0x01, 0x00, 0x00]);
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    assert_eq!(
+        vm.unbox(&stack).next().unwrap().unwrap().as_u32().unwrap(),
+        258
+    );
+}
+
+#[test]
+fn test_storage_file_bytes() {
+    let dir = TestDir::new("storage_file_bytes");
+    let path = &dir.path("tasks.bin");
+    let ops =
+        "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ PUSH_U32 258 END_CALL ] T_CREATE S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    let bytes = fs::read(path).unwrap();
+    #[rustfmt::skip]
+    let expected = vec![
+        0x53, 0x50, 0x44, 0x4F, // magic "SPDO"
+        0x03, 0x00, // format version
+        0x00, 0x00, // flags
+        0x01, 0x01, // next id: u32 (1 byte)
+        0x01, 0x00, // globals len
+        0x01, 0x00, 0x00, 0x00, // index len: fixed u32
+        0x00, 0x00, 0x00, 0x00, // id
+        0x00, 0x00, 0x00, 0x00, // record offset
+        0x09, 0x00, 0x00, 0x00, // record len
+        0x0F, 0xE4, 0x38, 0x39, // crc32 of record
+        0x01, 0x00, 0x00, 0x00, // code len: fixed u32
+        0x09, 0x00, 0x00, 0x00, // code offset
+        0x06, 0x00, 0x00, 0x00, // code len
+        0x4B, 0x37, 0x5B, 0x4B, // crc32 of code
+        0x02, 0xCB, 0xD0, 0x97, // crc32 of all bytes above
+        0x01, 0x00, // record: id
+        0x01, 0x00, 0x41, // title: u16 len, "A"
+        0x03, 0x00, // state: len, current
+        0x01, 0x00, // code index
+        0x01, 0x02, 0x01, 0x00, 0x00, 0x12, // code: PUSH_U32 258 END_CALL
+    ];
+    assert_eq!(bytes, expected);
+}
+
+#[test]
+fn test_globals_persist() {
+    let backend = MemoryBackend::new();
+    let ops = "PUSH_U32 42 PUSH_U32 0 G_SET PUSH_STRING cfg PUSH_U32 1 G_SET \
+               NEW_VEC_U32_I 8 PUSH_U32 1 PUSH_U32 7 M_MUTA PUSH_U32 2 G_SET S_SAVE";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    VM::builder()
+        .storage(backend.clone())
+        .build(bytecode)
+        .unwrap()
+        .run()
+        .unwrap();
+
+    let ops = "PUSH_U32 0 G_GET PUSH_U32 1 G_GET PUSH_U32 2 G_GET PUSH_U32 3 G_GET";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::builder()
+        .storage(backend.clone())
+        .build(bytecode)
+        .unwrap();
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(unboxed[0].as_u32().unwrap(), 42);
+    assert_eq!(unboxed[1].as_str().unwrap(), "cfg");
+    assert_eq!(unboxed[2].as_vec_u32().unwrap(), &[0, 7]);
+    assert_eq!(unboxed[3], Return::Null);
+    assert_eq!(vm.global(1).unwrap(), Global::String("cfg".to_string()));
+}
+
+#[test]
+fn test_globals_errors() {
+    let bytecode = VM::dot2bin("PUSH_U32 1 PUSH_U32 1024 G_SET").unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    assert!(matches!(
+        vm.run(),
+        Err(VMError::GlobalIndexOutOfBounds(1024))
+    ));
+    let bytecode = VM::dot2bin("MAP_NEW PUSH_U32 0 G_SET").unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    assert!(matches!(vm.run(), Err(VMError::InvalidType)));
+}
+
+#[test]
+fn test_load_storage_without_globals() {
+    let dir = TestDir::new("storage_v0");
+    let path = &dir.path("tasks.bin");
+    // file saved by baseline, before header, globals and little-endian operands (version 0):
+    // task 0 "A" with instructions "PUSH_U32 258 PUSH_CALLDATA [ PUSH_U32 1 ] DROP END_CALL"
+    let mut bytes = vec![
+        0x01, 0x01, 0x01, 0x00, 0x01, 0x00, 0x41, 0x03, 0x00, 0x01, 0x0f,
+    ];
+    bytes.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x02]);
+    bytes.extend_from_slice(&[0x04, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x01, 0x13, 0x12]);
+    bytes.extend_from_slice(&[0x01, 0x01]);
+    fs::write(path, bytes).unwrap();
+    let mut vm = file_vm(path, "PUSH_U32 0 CALL");
+    assert_eq!(vm.print_task(0).unwrap().title, "A");
+    assert_eq!(vm.global(0).unwrap(), Global::Null);
+    // operands are converted to little-endian
+    assert_eq!(
+        vm.print_task(0).unwrap().instructions,
+        VM::dot2bin("PUSH_U32 258 PUSH_CALLDATA [ PUSH_U32 1 ] DROP END_CALL").unwrap()
+    );
+    let stack = vm.run().unwrap();
+    assert_eq!(vm.unbox(&stack).last().unwrap().unwrap(), Return::U32(258));
+}
+
+#[test]
+fn test_yield_resumes_run() {
+    let bytecode = VM::dot2bin("PUSH_U32 1 YIELD PUSH_U32 2").unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    assert_eq!(stack.len(), 1);
+    assert!(vm.is_suspended());
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(unboxed, vec![Return::U32(1), Return::U32(2)]);
+    assert!(!vm.is_suspended());
+}
+
+#[test]
+fn test_snapshot_restore_mid_run() {
+    let dir = TestDir::new("vm_image");
+    let image = &dir.path("vm.image");
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ PUSH_U32 7 END_CALL ] T_CREATE \
+               LIST_NEW PUSH_STRING item LIST_PUSH \
+               PUSH_U32 2 PUSH_U32 0 DO LOOP_INDEX YIELD DROP LOOP PUSH_U32 0 CALL";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    vm.run().unwrap();
+    vm.snapshot(image).unwrap();
+    drop(vm);
+
+    let mut vm = VM::builder()
+        .storage(MemoryBackend::new())
+        .restore(image)
+        .unwrap();
+    let stack = vm.run().unwrap();
+    assert_eq!(vm.unbox(&stack).nth(1).unwrap().unwrap(), Return::U32(1));
+    vm.snapshot(image).unwrap();
+    drop(vm);
+
+    let mut vm = VM::builder()
+        .storage(MemoryBackend::new())
+        .restore(image)
+        .unwrap();
+    // task was not saved to storage, it lives in the image
+    assert_eq!(vm.print_task(0).unwrap().title, "A");
+    let stack = vm.run().unwrap();
+    let unboxed = vm.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(unboxed[0].as_list().unwrap()[0].as_str().unwrap(), "item");
+    assert_eq!(unboxed[1].as_u32().unwrap(), 7);
+    assert!(!vm.is_suspended());
+}
+
+#[test]
+fn test_restore_invalid_image() {
+    let dir = TestDir::new("invalid_image");
+    let image = &dir.path("vm.image");
+    fs::write(image, b"tasks").unwrap();
+    assert!(matches!(VM::restore(image), Err(VMError::InvalidImage)));
+    fs::write(image, b"SPDI\x02\x00").unwrap();
+    assert!(matches!(
+        VM::restore(image),
+        Err(VMError::UnsupportedImageVersion(2))
+    ));
+}
+
+#[test]
+fn test_restored_image_detects_stale_write() {
+    let dir = TestDir::new("restored_stale");
+    let path = &dir.path("tasks.bin");
+    let image = &dir.path("vm.image");
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE YIELD \
+               PUSH_STRING B PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    let mut vm = file_vm(path, ops);
+    vm.run().unwrap();
+    vm.snapshot(image).unwrap();
+    let mut restored = VM::builder()
+        .storage(FileBackend::new(path))
+        .restore(image)
+        .unwrap();
+    // store is written by other vm after restore
+    let ops = "PUSH_STRING C PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    assert!(matches!(restored.run(), Err(VMError::StorageConflict)));
+    assert_eq!(file_vm(path, "S_LEN").print_task(1).unwrap().title, "C");
+}
+
+#[test]
+fn test_restore_image_with_dangling_calldata() {
+    let dir = TestDir::new("dangling_image");
+    let image = &dir.path("vm.image");
+    let bytecode = VM::dot2bin("PUSH_CALLDATA [ PUSH_U32 1 ] YIELD").unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    vm.run().unwrap();
+    vm.snapshot(image).unwrap();
+    let restore = || VM::builder().storage(MemoryBackend::new()).restore(image);
+    assert!(restore().is_ok());
+
+    // [header: 6][strict][memory limit: compact u32][suspended][stack len: compact u32][values]
+    let mut bytes = fs::read(image).unwrap();
+    let stack_at = 8 + bytes[7] as usize + 1;
+    let value_at = stack_at + 1 + bytes[stack_at] as usize;
+    // nan-boxed calldata: pool index 1 above 18 bits of tag, rewritten to index that does not exist
+    let value = u64::from_le_bytes(bytes[value_at..value_at + 8].try_into().unwrap());
+    assert_eq!(value >> 18 & 0xFFFF_FFFF, 1);
+    bytes[value_at..value_at + 8].copy_from_slice(&(value + (998 << 18)).to_le_bytes());
+    fs::write(image, &bytes).unwrap();
+    assert!(matches!(restore(), Err(VMError::InvalidImage)));
+}
+
+#[test]
+fn test_restore_image_with_misplaced_task() {
+    let dir = TestDir::new("misplaced_task_image");
+    let image = &dir.path("vm.image");
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE YIELD";
+    let mut vm = VM::init_in_memory(VM::dot2bin(ops).unwrap()).unwrap();
+    vm.run().unwrap();
+    vm.snapshot(image).unwrap();
+    let restore = || VM::builder().storage(MemoryBackend::new()).restore(image);
+    assert!(restore().is_ok());
+
+    // image ends with storage: [len 1][flag][id 0][title "A"]..[next_id 1][no globals]
+    let bytes = fs::read(image).unwrap();
+    let task_at = bytes
+        .windows(8)
+        .rposition(|w| w == [1, 1, 1, 1, 0, 1, 1, b'A'])
+        .unwrap();
+    let next_id_at = bytes.len() - 3;
+    assert_eq!(bytes[next_id_at..], [1, 1, 0]);
+
+    let mut patched = bytes.clone();
+    patched[task_at + 4] = 5;
+    fs::write(image, &patched).unwrap();
+    assert!(matches!(restore(), Err(VMError::InvalidImage)));
+
+    let mut patched = bytes;
+    patched[next_id_at] = 0;
+    fs::write(image, &patched).unwrap();
+    assert!(matches!(restore(), Err(VMError::InvalidImage)));
+}
+
+#[test]
+fn test_in_memory_vm_is_ephemeral() {
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE S_LEN";
+    let mut vm = VM::init_in_memory(VM::dot2bin(ops).unwrap()).unwrap();
+    let stack = vm.run().unwrap();
+    assert_eq!(vm.unbox(&stack).next().unwrap().unwrap(), Return::U32(1));
+    assert_eq!(vm.print_task(0).unwrap().title, "A");
+    // every in-memory vm starts empty
+    let vm = VM::init_in_memory(VM::dot2bin("S_LEN").unwrap()).unwrap();
+    assert!(vm.print_task(0).is_err());
+}
+
+#[test]
+fn test_memory_backend_shared_between_vms() {
+    let backend = MemoryBackend::new();
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    let bytecode = VM::dot2bin(ops).unwrap();
+    let mut vm = VM::builder()
+        .storage(backend.clone())
+        .build(bytecode)
+        .unwrap();
+    vm.run().unwrap();
+
+    let bytecode = VM::dot2bin("S_LEN").unwrap();
+    let mut vm = VM::builder().storage(backend).build(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    assert_eq!(vm.unbox(&stack).next().unwrap().unwrap(), Return::U32(1));
+    assert_eq!(vm.print_task(0).unwrap().title, "A");
+}
+
+#[test]
+fn test_file_backend_path() {
+    let dir = TestDir::new("file_backend");
+    let path = &dir.path("tasks.bin");
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    assert!(path.exists());
+
+    // backend of other path does not see it
+    let vm = file_vm(&dir.path("other.bin"), "S_LEN");
+    assert!(vm.print_task(0).is_err());
+    let vm = file_vm(path, "S_LEN");
+    assert_eq!(vm.print_task(0).unwrap().title, "A");
+}
+
+#[test]
+fn test_failed_save_keeps_previous_file() {
+    let dir = TestDir::new("atomic_save");
+    let path = &dir.path("tasks.bin");
+    let tmp = &dir.path("tasks.bin.tmp");
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    // temp file is renamed over the storage file
+    assert!(!tmp.exists());
+    let saved = fs::read(path).unwrap();
+
+    // temp file can't be created -> error is returned, storage file is untouched
+    // (task bigger than snapshot is not journaled, whole storage is rewritten)
+    fs::create_dir(tmp).unwrap();
+    let ops = format!(
+        "PUSH_STRING {} PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE",
+        "B".repeat(saved.len())
+    );
+    let mut vm = file_vm(path, &ops);
+    assert!(matches!(vm.run(), Err(VMError::StorageWriteError)));
+    assert_eq!(fs::read(path).unwrap(), saved);
+}
+
+#[test]
+fn test_save_to_missing_dir_fails() {
+    let dir = TestDir::new("missing_dir");
+    let bytecode = VM::dot2bin("S_SAVE").unwrap();
+    let mut vm = VM::builder()
+        .storage(FileBackend::new(dir.path("missing_dir/tasks.bin")))
+        .build(bytecode)
+        .unwrap();
+    assert!(matches!(vm.run(), Err(VMError::StorageWriteError)));
+}
+
+#[test]
+fn test_corrupted_storage_detected() {
+    let dir = TestDir::new("corrupted_storage");
+    let path = &dir.path("tasks.bin");
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    let saved = fs::read(path).unwrap();
+
+    // corrupted record is detected when task is loaded
+    let mut bytes = saved.clone();
+    // title "A" -> "B"
+    let at = bytes.iter().position(|&b| b == b'A').unwrap();
+    bytes[at] = b'B';
+    fs::write(path, bytes).unwrap();
+    let mut corrupted = file_vm(path, "PUSH_U32 0 CALL");
+    assert!(matches!(
+        corrupted.print_task(0),
+        Err(VMError::StorageChecksumMismatch)
+    ));
+    assert!(matches!(
+        corrupted.run(),
+        Err(VMError::StorageChecksumMismatch)
+    ));
+
+    // corrupted header or index is detected on load
+    let mut bytes = saved;
+    // next id
+    bytes[9] = 2;
+    fs::write(path, bytes).unwrap();
+    let loaded = VM::builder()
+        .storage(FileBackend::new(path))
+        .build(VM::dot2bin("S_LEN").unwrap());
+    assert!(matches!(loaded, Err(VMError::StorageChecksumMismatch)));
+}
+
+#[test]
+fn test_save_appends_to_journal() {
+    let dir = TestDir::new("journal");
+    let path = &dir.path("tasks.bin");
+    let journal = &dir.path("tasks.bin.journal");
+    let run = |ops: &str| {
+        let mut vm = file_vm(path, ops);
+        vm.run().unwrap();
+        vm
+    };
+    let ops = format!(
+        "PUSH_STRING {} PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+         PUSH_STRING B PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE",
+        "A".repeat(200)
+    );
+    // first save has nothing to append to -> snapshot
+    run(&ops);
+    let snapshot = fs::read(path).unwrap();
+    assert!(!journal.exists());
+
+    // changes are appended to journal, snapshot is untouched
+    run("PUSH_STATE 2 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD \
+         PUSH_U32 1 T_DELETE PUSH_U32 7 PUSH_U32 0 G_SET S_SAVE");
+    assert_eq!(fs::read(path).unwrap(), snapshot);
+    assert!(fs::read(journal).unwrap().len() < snapshot.len());
+
+    // journal is replayed on load
+    let vm = run("S_LEN");
+    let task = vm.print_task(0).unwrap();
+    assert_eq!(task.title, "A".repeat(200));
+    assert_eq!(task.state.state, 2);
+    assert!(vm.print_task(1).is_err());
+    assert_eq!(vm.global(0).unwrap(), Global::U32(7));
+
+    // journal of another snapshot (crash before journal removal) is ignored
+    let stale = fs::read(journal).unwrap();
+    run(&format!(
+        "PUSH_STRING {} PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE",
+        "C".repeat(snapshot.len())
+    ));
+    assert!(!journal.exists());
+    fs::write(journal, &stale).unwrap();
+    run("PUSH_STATE 1 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD S_SAVE");
+    // stale journal is not appended to, next save rewrites snapshot
+    assert!(!journal.exists());
+    let vm = run("S_LEN");
+    assert_eq!(vm.print_task(0).unwrap().state.state, 1);
+    assert_eq!(vm.print_task(2).unwrap().title.len(), snapshot.len());
+}
+
+#[test]
+fn test_journal_compaction() {
+    let dir = TestDir::new("journal_compact");
+    let path = &dir.path("tasks.bin");
+    let journal = &dir.path("tasks.bin.journal");
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    let snapshot = fs::read(path).unwrap();
+
+    // every S_SAVE appends one record, journal never outgrows snapshot
+    let ops = "PUSH_U32 200 PUSH_U32 0 DO \
+               PUSH_STATE 1 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD S_SAVE LOOP";
+    file_vm(path, ops).run().unwrap();
+    assert_ne!(fs::read(path).unwrap(), snapshot);
+    let journal_len = fs::metadata(journal).map_or(0, |m| m.len());
+    assert!(journal_len <= fs::metadata(path).unwrap().len());
+
+    let vm = file_vm(path, "S_LEN");
+    assert_eq!(vm.print_task(0).unwrap().state.state, 1);
+}
+
+#[test]
+fn test_memory_backend_journal() {
+    let backend = MemoryBackend::new();
+    let run = |ops: &str| {
+        let mut vm = VM::builder()
+            .storage(backend.clone())
+            .build(VM::dot2bin(ops).unwrap())
+            .unwrap();
+        vm.run().unwrap();
+        vm
+    };
+    run("PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE");
+    run("PUSH_STRING B PUSH_U32 0 PUSH_TASK_FIELD 0 T_SET_FIELD S_SAVE");
+    let vm = run("S_LEN");
+    assert_eq!(vm.print_task(0).unwrap().title, "B");
+}
+
+fn memory_vm(backend: &MemoryBackend, ops: &str) -> VM {
+    VM::builder()
+        .storage(backend.clone())
+        .build(VM::dot2bin(ops).unwrap())
+        .unwrap()
+}
+
+fn file_vm(path: &Path, ops: &str) -> VM {
+    VM::builder()
+        .storage(FileBackend::new(path))
+        .build(VM::dot2bin(ops).unwrap())
+        .unwrap()
+}
+
+// tasks A (0) and B (1) with 3 states
+fn two_tasks_backend() -> MemoryBackend {
+    let backend = MemoryBackend::new();
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_STRING B PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    memory_vm(&backend, ops).run().unwrap();
+    backend
+}
+
+#[test]
+fn test_transaction_rollback() {
+    let backend = two_tasks_backend();
+    let ops = "TX_BEGIN PUSH_U32 0 T_DELETE \
+               PUSH_STATE 2 PUSH_U32 1 PUSH_TASK_FIELD 1 T_SET_FIELD \
+               PUSH_U32 5 PUSH_U32 0 G_SET \
+               PUSH_STRING C PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+               TX_ROLLBACK S_LEN \
+               PUSH_STRING D PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE";
+    let mut vm = memory_vm(&backend, ops);
+    let stack = vm.run().unwrap();
+    assert_eq!(vm.unbox(&stack).next().unwrap().unwrap(), Return::U32(2));
+    assert_eq!(vm.print_task(0).unwrap().title, "A");
+    assert_eq!(vm.print_task(1).unwrap().state.state, 0);
+    assert_eq!(vm.global(0).unwrap(), Global::Null);
+    // id of rolled back task is reused
+    assert_eq!(vm.print_task(2).unwrap().title, "D");
+}
+
+#[test]
+fn test_nested_transactions() {
+    let backend = two_tasks_backend();
+    // inner commit is undone by outer rollback
+    let ops = "TX_BEGIN TX_BEGIN PUSH_U32 0 T_DELETE TX_COMMIT \
+               TX_BEGIN PUSH_U32 1 T_DELETE TX_ROLLBACK TX_ROLLBACK";
+    let mut vm = memory_vm(&backend, ops);
+    vm.run().unwrap();
+    assert!(vm.print_task(0).is_ok());
+    assert!(vm.print_task(1).is_ok());
+
+    let ops = "TX_BEGIN PUSH_U32 0 T_DELETE TX_BEGIN PUSH_U32 1 T_DELETE TX_ROLLBACK TX_COMMIT";
+    let mut vm = memory_vm(&backend, ops);
+    vm.run().unwrap();
+    assert!(vm.print_task(0).is_err());
+    assert!(vm.print_task(1).is_ok());
+}
+
+#[test]
+fn test_transaction_defers_save() {
+    let backend = two_tasks_backend();
+    // run ending with open transaction is rolled back, nothing is saved
+    let mut vm = memory_vm(&backend, "TX_BEGIN PUSH_U32 0 T_DELETE S_SAVE");
+    assert!(matches!(vm.run(), Err(VMError::TransactionNotCommitted)));
+    assert!(vm.print_task(0).is_ok());
+    assert!(memory_vm(&backend, "S_LEN").print_task(0).is_ok());
+
+    let mut vm = memory_vm(&backend, "TX_BEGIN PUSH_U32 0 T_DELETE S_SAVE TX_COMMIT");
+    vm.run().unwrap();
+    assert!(memory_vm(&backend, "S_LEN").print_task(0).is_err());
+}
+
+#[test]
+fn test_transaction_errors() {
+    let backend = MemoryBackend::new();
+    for ops in ["TX_COMMIT", "TX_ROLLBACK", "TX_BEGIN TX_COMMIT TX_COMMIT"] {
+        let mut vm = memory_vm(&backend, ops);
+        assert!(matches!(vm.run(), Err(VMError::NoTransaction)));
+        let mut vm = memory_vm(&backend, ops);
+        assert!(matches!(vm.run_atomic(), Err(VMError::NoTransaction)));
+    }
+}
+
+#[test]
+fn test_failed_run_rolls_back_transaction() {
+    let backend = two_tasks_backend();
+    let ops = "TX_BEGIN PUSH_U32 0 T_DELETE S_SAVE PUSH_U32 9 T_DELETE TX_COMMIT";
+    let mut vm = memory_vm(&backend, ops);
+    assert!(matches!(vm.run(), Err(VMError::TaskNotFound(9))));
+    assert!(vm.print_task(0).is_ok());
+    assert!(memory_vm(&backend, "S_LEN").print_task(0).is_ok());
+}
+
+#[test]
+fn test_run_atomic() {
+    let backend = two_tasks_backend();
+    // fails halfway: first delete and S_SAVE are discarded
+    let ops = "PUSH_U32 0 T_DELETE S_SAVE PUSH_U32 9 T_DELETE";
+    let mut vm = memory_vm(&backend, ops);
+    assert!(matches!(vm.run_atomic(), Err(VMError::TaskNotFound(9))));
+    assert!(vm.print_task(0).is_ok());
+    assert!(memory_vm(&backend, "S_LEN").print_task(0).is_ok());
+
+    // succeeds: S_SAVE is performed on commit
+    let ops = "PUSH_U32 0 T_DELETE S_SAVE PUSH_U32 1 T_DELETE";
+    let mut vm = memory_vm(&backend, ops);
+    vm.run_atomic().unwrap();
+    let vm = memory_vm(&backend, "S_LEN");
+    // save on commit writes state at the end of run
+    assert!(vm.print_task(0).is_err());
+    assert!(vm.print_task(1).is_err());
+}
+
+fn shared_vm(shared: &SharedStorage, ops: &str) -> VM {
+    VM::builder()
+        .shared(shared.clone())
+        .build(VM::dot2bin(ops).unwrap())
+        .unwrap()
+}
+
+#[test]
+fn test_vm_is_send() {
+    fn assert_send<T: Send>() {}
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send::<VM>();
+    assert_send_sync::<SharedStorage>();
+}
+
+#[test]
+fn test_shared_storage_between_threads() {
+    let backend = MemoryBackend::new();
+    let shared = SharedStorage::new(backend.clone()).unwrap();
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                // different global slots never conflict
+                let ops = format!("PUSH_U32 {i} PUSH_U32 {i} G_SET");
+                shared_vm(&shared, &ops).run().unwrap();
+                // new tasks may take the same id, conflicting run is retried
+                let ops = format!("PUSH_STRING T{i} PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE");
+                let mut vm = shared_vm(&shared, &ops);
+                while let Err(e) = vm.run() {
+                    assert!(matches!(e, VMError::StorageConflict));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut vm = shared_vm(&shared, "S_LEN S_SAVE");
+    let stack = vm.run().unwrap();
+    assert_eq!(vm.unbox(&stack).next().unwrap().unwrap(), Return::U32(4));
+    for i in 0..4 {
+        assert_eq!(vm.global(i).unwrap(), Global::U32(i));
+    }
+    // S_SAVE writes shared state to backend
+    let vm = memory_vm(&backend, "S_LEN");
+    let mut titles: Vec<_> = (0..4).map(|id| vm.print_task(id).unwrap().title).collect();
+    titles.sort();
+    assert_eq!(titles, ["T0", "T1", "T2", "T3"]);
+}
+
+#[test]
+fn test_shared_storage_isolation() {
+    let shared = SharedStorage::new(MemoryBackend::new()).unwrap();
+    shared_vm(&shared, "PUSH_U32 1 PUSH_U32 0 G_SET")
+        .run()
+        .unwrap();
+
+    // suspended run keeps its copy and does not see changes published meanwhile
+    let mut reader = shared_vm(&shared, "YIELD PUSH_U32 1 G_GET");
+    let mut writer = shared_vm(&shared, "PUSH_U32 3 PUSH_U32 0 G_SET YIELD");
+    reader.run().unwrap();
+    writer.run().unwrap();
+    shared_vm(
+        &shared,
+        "PUSH_U32 2 PUSH_U32 0 G_SET PUSH_U32 5 PUSH_U32 1 G_SET",
+    )
+    .run()
+    .unwrap();
+    let stack = reader.run().unwrap();
+    assert_eq!(reader.unbox(&stack).next().unwrap().unwrap(), Return::Null);
+    // slot 0 was published by other vm after writer took its copy
+    assert!(matches!(writer.run(), Err(VMError::StorageConflict)));
+
+    // next run starts from fresh copy
+    reader.run().unwrap();
+    let stack = reader.run().unwrap();
+    assert_eq!(
+        reader.unbox(&stack).next().unwrap().unwrap(),
+        Return::U32(5)
+    );
+    assert_eq!(reader.global(0).unwrap(), Global::U32(2));
+}
+
+#[test]
+fn test_shared_storage_failed_save_publishes_nothing() {
+    let backend = CountingBackend::default();
+    let shared = SharedStorage::new(backend.clone()).unwrap();
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    shared_vm(&shared, ops).run().unwrap();
+    // copy taken before failed publish
+    let mut other = shared_vm(
+        &shared,
+        "PUSH_STATE 1 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD S_SAVE",
+    );
+
+    backend.set_failing(true);
+    let ops = "PUSH_STRING B PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_STATE 2 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD S_SAVE";
+    let mut failed = shared_vm(&shared, ops);
+    assert!(matches!(failed.run(), Err(VMError::StorageWriteError)));
+    backend.set_failing(false);
+
+    let vm = shared_vm(&shared, "S_LEN");
+    assert!(vm.print_task(1).is_err());
+    assert_eq!(vm.print_task(0).unwrap().state.state, 0);
+    // failed publish did not take the slot, so it is no conflict
+    other.run().unwrap();
+    let stored = VM::load_store(&mut backend.clone()).unwrap();
+    assert_eq!(stored.tasks.len(), 1);
+    assert_eq!(stored.tasks[0].state.state, 1);
+    assert_eq!(stored.next_id, 1);
+}
+
+#[test]
+fn test_shared_storage_failed_run_discarded() {
+    let shared = SharedStorage::new(MemoryBackend::new()).unwrap();
+    let mut vm = shared_vm(&shared, "PUSH_U32 1 PUSH_U32 0 G_SET PUSH_U32 9 T_DELETE");
+    assert!(matches!(vm.run(), Err(VMError::TaskNotFound(9))));
+    let vm = shared_vm(&shared, "S_LEN");
+    assert_eq!(vm.global(0).unwrap(), Global::Null);
+}
+
+#[test]
+fn test_file_store_conflict() {
+    let dir = TestDir::new("conflict");
+    let path = &dir.path("tasks.bin");
+    // both loaded the same store, second writer must not overwrite first one
+    let mut first = file_vm(
+        path,
+        "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE",
+    );
+    let mut second = file_vm(
+        path,
+        "PUSH_STRING B PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE",
+    );
+    first.run().unwrap();
+    assert!(matches!(second.run(), Err(VMError::StorageConflict)));
+    // first one keeps saving, journal append is checked too
+    first.run().unwrap();
+    assert!(matches!(second.run(), Err(VMError::StorageConflict)));
+
+    // reloaded store is writable again, unsaved changes of failed run are dropped
+    second.reload().unwrap();
+    assert_eq!(second.print_task(1).unwrap().title, "A");
+    second.run().unwrap();
+    let mut third = file_vm(
+        path,
+        "PUSH_STRING C PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE",
+    );
+    third.run().unwrap();
+    assert!(matches!(first.run(), Err(VMError::StorageConflict)));
+    let titles: Vec<_> = (0..4)
+        .map(|id| file_vm(path, "S_LEN").print_task(id).unwrap().title)
+        .collect();
+    assert_eq!(titles, ["A", "A", "B", "C"]);
+}
+
+#[test]
+fn test_file_store_lock_blocks_writer() {
+    let dir = TestDir::new("lock");
+    let path = &dir.path("tasks.bin");
+    let lock = fs::File::create(dir.path("tasks.bin.lock")).unwrap();
+    lock.lock().unwrap();
+    let writer_path = path.clone();
+    let started = std::sync::Arc::new(std::sync::Barrier::new(2));
+    let released = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let (writer_started, writer_released) = (started.clone(), released.clone());
+    let writer = std::thread::spawn(move || {
+        let bytecode = VM::dot2bin("PUSH_U32 1 PUSH_U32 0 G_SET S_SAVE").unwrap();
+        writer_started.wait();
+        let result = VM::builder()
+            .storage(FileBackend::new(writer_path))
+            .build(bytecode)
+            .and_then(|mut vm| vm.run().map(|_| ()));
+        // writer gets through only after the lock is released
+        (
+            result,
+            writer_released.load(std::sync::atomic::Ordering::SeqCst),
+        )
+    });
+    started.wait();
+    // gives writer time to block on the lock, result does not depend on it
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    released.store(true, std::sync::atomic::Ordering::SeqCst);
+    lock.unlock().unwrap();
+    let (result, after_release) = writer.join().unwrap();
+    result.unwrap();
+    assert!(after_release);
+    assert!(path.exists());
+}
+
+// memory backend counting writes (full saves and journal appends), failing them while set to
+#[derive(Debug, Clone, Default)]
+struct CountingBackend {
+    inner: MemoryBackend,
+    writes: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    failing: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl CountingBackend {
+    fn writes(&self) -> usize {
+        self.writes.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    fn set_failing(&self, failing: bool) {
+        self.failing
+            .store(failing, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn journal_len(&self) -> usize {
+        let journal = self.inner.clone().load_journal().unwrap();
+        journal.map_or(0, |journal| journal.len())
+    }
+
+    fn count(&self) -> VMResult<()> {
+        if self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(VMError::StorageWriteError);
+        }
+        self.writes
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl StorageBackend for CountingBackend {
+    fn load(&mut self) -> VMResult<Option<Vec<u8>>> {
+        self.inner.load()
+    }
+    fn save(&mut self, bytes: &[u8]) -> VMResult<()> {
+        self.count()?;
+        self.inner.save(bytes)
+    }
+    fn load_journal(&mut self) -> VMResult<Option<Vec<u8>>> {
+        self.inner.load_journal()
+    }
+    fn append_journal(&mut self, bytes: &[u8]) -> VMResult<bool> {
+        self.count()?;
+        self.inner.append_journal(bytes)
+    }
+}
+
+fn policy_vm(backend: &CountingBackend, policy: SavePolicy, ops: &str) -> VM {
+    VM::builder()
+        .storage(backend.clone())
+        .save_policy(policy)
+        .build(VM::dot2bin(ops).unwrap())
+        .unwrap()
+}
+
+// long title keeps snapshot bigger than journal of following tests, no compaction
+const THREE_SAVES: &str = "PUSH_STRING AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE \
+                           PUSH_STRING B PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE \
+                           PUSH_U32 7 PUSH_U32 0 G_SET S_SAVE";
+
+#[test]
+fn test_save_policy_immediate() {
+    let backend = CountingBackend::default();
+    policy_vm(&backend, SavePolicy::Immediate, THREE_SAVES)
+        .run()
+        .unwrap();
+    assert_eq!(backend.writes(), 3);
+}
+
+#[test]
+fn test_save_policy_end_of_run_coalesces() {
+    let backend = CountingBackend::default();
+    policy_vm(&backend, SavePolicy::EndOfRun, THREE_SAVES)
+        .run()
+        .unwrap();
+    assert_eq!(backend.writes(), 1);
+    let vm = policy_vm(&backend, SavePolicy::EndOfRun, "S_LEN");
+    assert_eq!(vm.print_task(1).unwrap().title, "B");
+    assert_eq!(vm.global(0).unwrap(), Global::U32(7));
+
+    // only changed records are appended, state set three times is one record
+    let set_once = "PUSH_STATE 1 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD S_SAVE";
+    let set_thrice = "PUSH_STATE 1 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD S_SAVE \
+                      PUSH_STATE 2 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD S_SAVE \
+                      PUSH_STATE 0 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD S_SAVE";
+    // first append writes journal header
+    policy_vm(&backend, SavePolicy::EndOfRun, set_once)
+        .run()
+        .unwrap();
+    let before = backend.journal_len();
+    policy_vm(&backend, SavePolicy::EndOfRun, set_thrice)
+        .run()
+        .unwrap();
+    let thrice = backend.journal_len() - before;
+    policy_vm(&backend, SavePolicy::EndOfRun, set_once)
+        .run()
+        .unwrap();
+    assert_eq!(backend.journal_len() - before - thrice, thrice);
+    assert_eq!(backend.writes(), 4);
+
+    // run without S_SAVE writes nothing
+    policy_vm(
+        &backend,
+        SavePolicy::EndOfRun,
+        "PUSH_U32 1 PUSH_U32 0 G_SET",
+    )
+    .run()
+    .unwrap();
+    assert_eq!(backend.writes(), 4);
+}
+
+#[test]
+fn test_save_policy_end_of_run_failed_run() {
+    let backend = CountingBackend::default();
+    let ops = "PUSH_U32 7 PUSH_U32 0 G_SET S_SAVE PUSH_U32 9 T_DELETE";
+    let mut vm = policy_vm(&backend, SavePolicy::EndOfRun, ops);
+    assert!(matches!(vm.run(), Err(VMError::TaskNotFound(9))));
+    assert_eq!(backend.writes(), 0);
+
+    // save inside transaction happens once, after commit
+    let ops = "TX_BEGIN PUSH_U32 7 PUSH_U32 0 G_SET S_SAVE TX_COMMIT \
+               TX_BEGIN PUSH_U32 8 PUSH_U32 1 G_SET S_SAVE TX_ROLLBACK S_SAVE";
+    policy_vm(&backend, SavePolicy::EndOfRun, ops)
+        .run()
+        .unwrap();
+    assert_eq!(backend.writes(), 1);
+    let vm = policy_vm(&backend, SavePolicy::EndOfRun, "S_LEN");
+    assert_eq!(vm.global(0).unwrap(), Global::U32(7));
+    assert_eq!(vm.global(1).unwrap(), Global::Null);
+}
+
+#[test]
+fn test_save_policy_manual() {
+    let backend = CountingBackend::default();
+    let mut vm = policy_vm(&backend, SavePolicy::Manual, THREE_SAVES);
+    vm.run().unwrap();
+    vm.run().unwrap();
+    assert_eq!(backend.writes(), 0);
+    vm.save().unwrap();
+    assert_eq!(backend.writes(), 1);
+    assert_eq!(
+        policy_vm(&backend, SavePolicy::Manual, "S_LEN")
+            .print_task(3)
+            .unwrap()
+            .title,
+        "B"
+    );
+
+    // suspended run with open transaction can't be saved
+    let mut vm = policy_vm(&backend, SavePolicy::Manual, "TX_BEGIN YIELD TX_COMMIT");
+    vm.run().unwrap();
+    assert!(matches!(vm.save(), Err(VMError::TransactionNotCommitted)));
+    vm.run().unwrap();
+    vm.save().unwrap();
+}
+
+fn corrupt_title(path: &Path, title: u8) {
+    let mut bytes = fs::read(path).unwrap();
+    let at = bytes.iter().rposition(|&b| b == title).unwrap();
+    bytes[at] = b'?';
+    fs::write(path, bytes).unwrap();
+}
+
+#[test]
+fn test_tasks_are_loaded_lazily() {
+    let dir = TestDir::new("lazy_tasks");
+    let path = &dir.path("tasks.bin");
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ PUSH_U32 7 END_CALL ] T_CREATE \
+               PUSH_STRING B PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    // task B is never decoded, so its corrupted record does not matter
+    corrupt_title(path, b'B');
+
+    let ops = "PUSH_U32 0 CALL PUSH_STATE 2 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD \
+               PUSH_U32 0 PUSH_TASK_FIELD 2 T_GET_FIELD S_SAVE";
+    let mut first = file_vm(path, ops);
+    let stack = first.run().unwrap();
+    let unboxed = first.unbox(&stack).collect::<VMResult<Vec<_>>>().unwrap();
+    assert_eq!(unboxed[0].as_u32().unwrap(), 7);
+    assert_eq!(
+        unboxed[1].as_calldata().unwrap(),
+        VM::dot2bin("PUSH_U32 7 END_CALL").unwrap()
+    );
+    assert!(matches!(
+        first.print_task(1),
+        Err(VMError::StorageChecksumMismatch)
+    ));
+
+    let task = file_vm(path, "S_LEN").print_task(0).unwrap();
+    assert_eq!(task.state.state, 2);
+    assert_eq!(
+        task.instructions,
+        VM::dot2bin("PUSH_U32 7 END_CALL").unwrap()
+    );
+}
+
+#[test]
+fn test_image_keeps_tasks_not_loaded() {
+    let dir = TestDir::new("lazy_image");
+    let path = &dir.path("tasks.bin");
+    let image = &dir.path("vm.image");
+    let ops =
+        "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ PUSH_U32 7 END_CALL ] T_CREATE S_SAVE";
+    file_vm(path, ops).run().unwrap();
+
+    // task is not decoded before snapshot, restored vm does not read storage file
+    file_vm(path, "PUSH_U32 0 CALL").snapshot(image).unwrap();
+    fs::remove_file(path).unwrap();
+    let mut restored = VM::builder()
+        .storage(MemoryBackend::new())
+        .restore(image)
+        .unwrap();
+    let stack = restored.run().unwrap();
+    let unboxed = restored
+        .unbox(&stack)
+        .collect::<VMResult<Vec<_>>>()
+        .unwrap();
+    assert_eq!(unboxed[0].as_u32().unwrap(), 7);
+    assert_eq!(restored.print_task(0).unwrap().title, "A");
+}
+
+#[test]
+fn test_storage_v1_is_upgraded() {
+    let dir = TestDir::new("storage_v1");
+    let path = &dir.path("tasks.bin");
+    #[rustfmt::skip]
+    let bytes = vec![
+        0x53, 0x50, 0x44, 0x4F, 0x01, 0x00, 0x00, 0x00, // header, version 1
+        0x01, 0x01, 0x01, 0x00, 0x01, 0x00, 0x41, 0x03, 0x00, // tasks: A
+        0x01, 0x06, 0x01, 0x02, 0x01, 0x00, 0x00, 0x12, // instructions
+        0x01, 0x01, 0x01, 0x00, // next id, globals
+        0x99, 0xA1, 0x0F, 0x66, // crc32
+    ];
+    fs::write(path, bytes).unwrap();
+    assert_eq!(file_vm(path, "S_LEN").print_task(0).unwrap().title, "A");
+
+    // journal is appended to version 1 file, compaction writes current version
+    file_vm(
+        path,
+        "PUSH_STATE 1 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD S_SAVE",
+    )
+    .run()
+    .unwrap();
+    assert_eq!(&fs::read(path).unwrap()[4..6], &[0x01, 0x00]);
+    let ops = "PUSH_STRING BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB PUSH_MAX_STATES 3 \
+               PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    assert_eq!(&fs::read(path).unwrap()[4..6], &[0x03, 0x00]);
+    let reloaded = file_vm(path, "S_LEN");
+    assert_eq!(reloaded.print_task(0).unwrap().state.state, 1);
+    assert_eq!(
+        reloaded.print_task(0).unwrap().instructions,
+        VM::dot2bin("PUSH_U32 258 END_CALL").unwrap()
+    );
+    assert_eq!(reloaded.print_task(1).unwrap().title.len(), 48);
+}
+
+#[test]
+fn test_storage_v2_is_read() {
+    let dir = TestDir::new("storage_v2");
+    let path = &dir.path("tasks.bin");
+    // indexed file without code section, task A: PUSH_U32 258 END_CALL
+    #[rustfmt::skip]
+    let bytes = vec![
+        0x53, 0x50, 0x44, 0x4F, 0x02, 0x00, 0x00, 0x00, 0x01, 0x01, 0x01, 0x00,
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x0F, 0x00, 0x00, 0x00, 0xEA, 0xDF, 0xAC, 0x1A, 0x01, 0x54, 0x93, 0xE8,
+        0x01, 0x00, 0x01, 0x00, 0x41, 0x03, 0x00,
+        0x01, 0x06, 0x01, 0x02, 0x01, 0x00, 0x00, 0x12,
+    ];
+    fs::write(path, bytes).unwrap();
+    let mut called = file_vm(path, "PUSH_U32 0 CALL");
+    let stack = called.run().unwrap();
+    assert_eq!(
+        called
+            .unbox(&stack)
+            .next()
+            .unwrap()
+            .unwrap()
+            .as_u32()
+            .unwrap(),
+        258
+    );
+    let ops = "PUSH_STRING BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB PUSH_MAX_STATES 3 \
+               PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    assert_eq!(&fs::read(path).unwrap()[4..6], &[0x03, 0x00]);
+    assert_eq!(file_vm(path, "S_LEN").print_task(0).unwrap().title, "A");
+}
+
+#[test]
+fn test_identical_instructions_stored_once() {
+    let dir = TestDir::new("code_dedup");
+    let path = &dir.path("tasks.bin");
+    let code = VM::dot2bin("PUSH_U32 123456789 END_CALL").unwrap();
+    let ops = "PUSH_U32 10 PUSH_U32 0 DO \
+               PUSH_STRING gate PUSH_MAX_STATES 2 PUSH_CALLDATA [ PUSH_U32 123456789 END_CALL ] T_CREATE \
+               LOOP S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    let bytes = fs::read(path).unwrap();
+    let copies = bytes.windows(code.len()).filter(|w| *w == code).count();
+    assert_eq!(copies, 1);
+
+    let vm = file_vm(path, "S_LEN");
+    assert_eq!(vm.print_task(9).unwrap().instructions, code);
+}
+
+#[test]
+fn test_calldata_is_interned_once() {
+    let bytecode =
+        VM::dot2bin("PUSH_CALLDATA [ PUSH_U32 1 ] PUSH_CALLDATA [ PUSH_U32 1 ] EQ").unwrap();
+    let mut vm = VM::init_in_memory(bytecode).unwrap();
+    let stack = vm.run().unwrap();
+    assert_eq!(
+        vm.unbox(&stack).next().unwrap().unwrap(),
+        Return::Bool(true)
+    );
+
+    // executed PUSH_CALLDATA does not grow instructions pool
+    let dir = TestDir::new("calldata_interned");
+    let image_len = |iterations: u32| {
+        let ops = format!(
+            "PUSH_U32 {iterations} PUSH_U32 0 DO PUSH_CALLDATA [ PUSH_U32 1 ] DROP LOOP YIELD"
+        );
+        let mut vm = VM::init_in_memory(VM::dot2bin(&ops).unwrap()).unwrap();
+        vm.run().unwrap();
+        let image = dir.path(&format!("{iterations}.image"));
+        vm.snapshot(&image).unwrap();
+        fs::metadata(&image).unwrap().len()
+    };
+    assert_eq!(image_len(1), image_len(1000));
+}
+
+#[test]
+fn test_instructions_are_collected_between_runs() {
+    let backend = MemoryBackend::new();
+    let ops =
+        "PUSH_STRING A PUSH_MAX_STATES 2 PUSH_CALLDATA [ PUSH_U32 1 END_CALL ] T_CREATE S_SAVE";
+    memory_vm(&backend, ops).run().unwrap();
+
+    // calls task, then replaces its instructions
+    let ops = "PUSH_U32 0 CALL DROP \
+               PUSH_CALLDATA [ PUSH_U32 2 END_CALL ] PUSH_U32 0 PUSH_TASK_FIELD 2 T_SET_FIELD \
+               PUSH_CALLDATA [ PUSH_U32 3 ]";
+    let mut vm = memory_vm(&backend, ops);
+    assert_eq!(vm.instructions_count(), 1);
+    let stack = vm.run().unwrap();
+    // program, old and new task instructions, returned calldata
+    assert_eq!(vm.instructions_count(), 4);
+    let unboxed = vm.unbox(&stack).next().unwrap().unwrap();
+    assert_eq!(
+        unboxed.as_calldata().unwrap(),
+        VM::dot2bin("PUSH_U32 3").unwrap()
+    );
+
+    // replaced instructions and calldata of previous run are freed, their slots reused
+    for _ in 0..3 {
+        vm.run().unwrap();
+        assert_eq!(vm.instructions_count(), 3);
+    }
+
+    // freed slots survive image
+    let dir = TestDir::new("instructions_collected");
+    let image = &dir.path("vm.image");
+    vm.snapshot(image).unwrap();
+    let mut restored = VM::builder()
+        .storage(backend.clone())
+        .restore(image)
+        .unwrap();
+    let stack = restored.run().unwrap();
+    let unboxed = restored.unbox(&stack).next().unwrap().unwrap();
+    assert_eq!(
+        unboxed.as_calldata().unwrap(),
+        VM::dot2bin("PUSH_U32 3").unwrap()
+    );
+    assert_eq!(restored.instructions_count(), 3);
+}
+
+#[test]
+fn test_fsck_salvages_corrupted_store() {
+    let dir = TestDir::new("fsck_salvage");
+    let path = &dir.path("tasks.bin");
+    let ops = "PUSH_STRING AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA PUSH_MAX_STATES 3 \
+               PUSH_CALLDATA [ PUSH_U32 1 CALL ] T_CREATE \
+               PUSH_STRING B PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_STRING config PUSH_U32 0 G_SET PUSH_U32 1 T_REF PUSH_U32 1 G_SET S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    // journaled
+    let ops = "PUSH_STRING C PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    assert!(fs::metadata(dir.path("tasks.bin.journal")).is_ok());
+    let report = VM::fsck(&mut FileBackend::new(path)).unwrap();
+    assert!(report.is_clean());
+    assert_eq!(report.tasks, 3);
+
+    // broken header and record of task B
+    corrupt_title(path, b'g');
+    corrupt_title(path, b'B');
+    let loaded = VM::builder()
+        .storage(FileBackend::new(path))
+        .build(VM::dot2bin("S_LEN").unwrap());
+    assert!(matches!(loaded, Err(VMError::StorageChecksumMismatch)));
+    let mut backend = FileBackend::new(path);
+    let report = VM::fsck(&mut backend).unwrap();
+    assert_eq!(report.version, Some(3));
+    assert_eq!(report.tasks, 2);
+    assert!(matches!(
+        report.issues.as_slice(),
+        [
+            FsckIssue::HeaderChecksumMismatch,
+            FsckIssue::CorruptRecord {
+                id: 1,
+                error: VMError::StorageChecksumMismatch
+            },
+            FsckIssue::DanglingTaskRef { id: 0, target: 1 },
+            FsckIssue::DanglingGlobalRef { index: 1, id: 1 },
+        ]
+    ));
+    assert!(report.to_string().contains("task 1: corrupt record"));
+
+    report.repair(&mut backend).unwrap();
+    assert!(fs::metadata(dir.path("tasks.bin.journal")).is_err());
+    let vm = file_vm(path, "S_LEN");
+    assert_eq!(vm.print_task(0).unwrap().title.len(), 48);
+    assert!(matches!(vm.print_task(1), Err(VMError::TaskNotFound(1))));
+    assert_eq!(vm.print_task(2).unwrap().title, "C");
+    assert_eq!(vm.global(0).unwrap(), Global::String("confi?".to_string()));
+}
+
+#[test]
+fn test_fsck_repairs_invalid_tasks() {
+    // legacy file saved by baseline, operands are big-endian
+    #[rustfmt::skip]
+    let bytes = vec![
+        0x01, 0x03, // legacy file without header, 3 tasks
+        0x01, 0x00, 0x01, 0x00, 0x41, 0x02, 0x05, 0x01, 0x05, // 0: state 5 of 2, JUMP_IF_FALSE 32
+        0x1a, 0x00, 0x00, 0x00, 0x20,
+        0x01, 0x00, 0x01, 0x00, 0x42, 0x02, 0x00, 0x01, 0x00, // 0 again
+        0x01, 0x07, 0x01, 0x00, 0x43, 0x02, 0x01, 0x01, 0x0f, // 7: calldata, PUSH_U32 9 CALL
+        0x04, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x01, 0x13,
+        0x01, 0x00, 0x00, 0x00, 0x09, 0x11,
+        0x01, 0x02, // next id
+    ];
+    let backend = MemoryBackend::new();
+    backend.clone().save(&bytes).unwrap();
+    let report = VM::fsck(&mut backend.clone()).unwrap();
+    assert_eq!(report.version, Some(0));
+    assert_eq!(report.tasks, 2);
+    assert!(matches!(
+        report.issues.as_slice(),
+        [
+            FsckIssue::DuplicateId(0),
+            FsckIssue::InvalidState {
+                id: 0,
+                state: 5,
+                len: 2
+            },
+            FsckIssue::InvalidInstructions {
+                id: 0,
+                error: VMError::MalformedIfThen { .. }
+            },
+            FsckIssue::IdBeyondNextId { id: 7, next_id: 2 },
+            FsckIssue::DanglingTaskRef { id: 7, target: 9 },
+        ]
+    ));
+
+    report.repair(&mut backend.clone()).unwrap();
+    let mut vm = memory_vm(
+        &backend,
+        "PUSH_STRING D PUSH_MAX_STATES 2 PUSH_CALLDATA [ ] T_CREATE",
+    );
+    let task = vm.print_task(0).unwrap();
+    assert_eq!((task.title.as_str(), task.state.state), ("A", 0));
+    assert!(task.instructions.is_empty());
+    // valid instructions are converted to little-endian
+    assert_eq!(
+        vm.print_task(7).unwrap().instructions,
+        VM::dot2bin("PUSH_CALLDATA [ PUSH_U32 1 ] DROP PUSH_U32 9 CALL").unwrap()
+    );
+    vm.run().unwrap();
+    assert_eq!(vm.print_task(8).unwrap().title, "D");
+    // references are reported, not repaired
+    let report = VM::fsck(&mut backend.clone()).unwrap();
+    assert!(matches!(
+        report.issues.as_slice(),
+        [FsckIssue::DanglingTaskRef { id: 7, target: 9 }]
+    ));
+}
+
+#[test]
+fn test_fsck_refuses_to_clear_legacy_instructions() {
+    // legacy task 0 with unknown opcode, its instructions can't be converted
+    let bytes = vec![
+        0x01, 0x01, 0x01, 0x00, 0x01, 0x00, 0x41, 0x02, 0x00, 0x01, 0x01, 0xff, 0x01, 0x01,
+    ];
+    let backend = MemoryBackend::new();
+    backend.clone().save(&bytes).unwrap();
+    let report = VM::fsck(&mut backend.clone()).unwrap();
+    assert!(matches!(
+        report.issues.as_slice(),
+        [FsckIssue::UnconvertibleInstructions {
+            id: 0,
+            error: VMError::UnknownOpcode { .. }
+        }]
+    ));
+    assert!(matches!(
+        report.repair(&mut backend.clone()),
+        Err(VMError::UnconvertibleInstructions(0))
+    ));
+    assert_eq!(backend.clone().load().unwrap().unwrap(), bytes);
+}
+
+#[test]
+fn test_fsck_empty_store() {
+    let report = VM::fsck(&mut MemoryBackend::new()).unwrap();
+    assert!(report.is_clean());
+    assert_eq!((report.version, report.tasks), (None, 0));
+}
+
+#[test]
+fn test_store_snapshot_and_restore() {
+    let backend = MemoryBackend::new();
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_U32 7 PUSH_U32 0 G_SET S_SAVE \
+               PUSH_STRING before-migration S_SNAPSHOT";
+    memory_vm(&backend, ops).run().unwrap();
+
+    let ops = "PUSH_STATE 2 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD \
+               PUSH_STRING B PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_U32 8 PUSH_U32 0 G_SET S_SAVE";
+    let mut vm = memory_vm(&backend, ops);
+    vm.run().unwrap();
+    assert_eq!(vm.store_snapshots().unwrap(), ["before-migration"]);
+    vm.restore_store("before-migration").unwrap();
+    assert_eq!(vm.print_task(0).unwrap().state.state, 0);
+    assert!(matches!(vm.print_task(1), Err(VMError::TaskNotFound(1))));
+    assert_eq!(vm.global(0).unwrap(), Global::U32(7));
+
+    // restored store is saved
+    let vm = memory_vm(&backend, "PUSH_U32 0 PUSH_TASK_FIELD 1 T_GET_FIELD");
+    assert_eq!(vm.print_task(0).unwrap().state.state, 0);
+
+    // snapshot keeps unsaved changes, restore drops them
+    let ops = "PUSH_STATE 1 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD \
+               PUSH_STRING one S_SNAPSHOT \
+               PUSH_STATE 2 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD \
+               PUSH_STRING one S_RESTORE \
+               PUSH_U32 0 PUSH_TASK_FIELD 1 T_GET_FIELD";
+    let mut vm = memory_vm(&backend, ops);
+    let stack = vm.run().unwrap();
+    assert_eq!(
+        vm.unbox(&stack).last().unwrap().unwrap().as_u32().unwrap(),
+        1
+    );
+    assert_eq!(
+        memory_vm(&backend, "S_LEN")
+            .print_task(0)
+            .unwrap()
+            .state
+            .state,
+        1
+    );
+}
+
+#[test]
+fn test_store_snapshot_retention_keeps_newest() {
+    let backend = two_tasks_backend();
+    let mut vm = VM::builder()
+        .storage(backend.clone())
+        .snapshot_retention(0)
+        .build(VM::dot2bin("PUSH_STRING second S_SNAPSHOT").unwrap())
+        .unwrap();
+    vm.snapshot_store("first").unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.store_snapshots().unwrap(), ["second"]);
+    vm.restore_store("second").unwrap();
+    assert_eq!(vm.print_task(1).unwrap().title, "B");
+}
+
+#[test]
+fn test_store_snapshot_and_restore_in_atomic_run() {
+    let backend = MemoryBackend::new();
+    let state = |backend: &MemoryBackend| {
+        memory_vm(backend, "S_LEN")
+            .print_task(0)
+            .unwrap()
+            .state
+            .state
+    };
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_STRING s S_SNAPSHOT S_SAVE";
+    memory_vm(&backend, ops).run_atomic().unwrap();
+    let ops = "PUSH_STATE 2 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD S_SAVE \
+               PUSH_STRING t S_SNAPSHOT";
+    let mut vm = memory_vm(&backend, ops);
+    vm.run_atomic().unwrap();
+    assert_eq!(vm.store_snapshots().unwrap(), ["s", "t"]);
+    assert_eq!(state(&backend), 2);
+
+    // failed run neither writes its snapshot nor keeps its restore
+    let ops = "PUSH_STRING u S_SNAPSHOT PUSH_STRING s S_RESTORE PUSH_U32 9 T_DELETE";
+    let mut vm = memory_vm(&backend, ops);
+    assert!(vm.run_atomic().is_err());
+    assert_eq!(vm.store_snapshots().unwrap(), ["s", "t"]);
+    assert_eq!(vm.print_task(0).unwrap().state.state, 2);
+    assert_eq!(state(&backend), 2);
+
+    let mut vm = memory_vm(&backend, "PUSH_STRING s S_RESTORE");
+    vm.run_atomic().unwrap();
+    assert_eq!(state(&backend), 0);
+
+    // restore is saved by save policy
+    vm.restore_store("t").unwrap();
+    let mut vm = VM::builder()
+        .storage(backend.clone())
+        .save_policy(SavePolicy::Manual)
+        .build(VM::dot2bin("PUSH_STRING s S_RESTORE").unwrap())
+        .unwrap();
+    vm.run_atomic().unwrap();
+    assert_eq!(vm.print_task(0).unwrap().state.state, 0);
+    assert_eq!(state(&backend), 2);
+    vm.save().unwrap();
+    assert_eq!(state(&backend), 0);
+
+    vm.restore_store("t").unwrap();
+    assert_eq!(state(&backend), 0);
+    vm.save().unwrap();
+    assert_eq!(state(&backend), 2);
+}
+
+#[test]
+fn test_store_snapshots_retention() {
+    let dir = TestDir::new("store_snapshots");
+    let path = &dir.path("tasks.bin");
+    let vm = |ops: &str| {
+        VM::builder()
+            .storage(FileBackend::new(path))
+            .snapshot_retention(2)
+            .build(VM::dot2bin(ops).unwrap())
+            .unwrap()
+    };
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 3 PUSH_CALLDATA [ ] T_CREATE S_SAVE \
+               PUSH_STRING s1 S_SNAPSHOT PUSH_STRING s2 S_SNAPSHOT PUSH_STRING s3 S_SNAPSHOT";
+    let mut vm = vm(ops);
+    vm.run().unwrap();
+    assert_eq!(vm.store_snapshots().unwrap(), ["s2", "s3"]);
+    // retaken snapshot is the newest one
+    vm.snapshot_store("s2").unwrap();
+    assert_eq!(vm.store_snapshots().unwrap(), ["s3", "s2"]);
+    assert!(vm.delete_store_snapshot("s3").unwrap());
+    assert!(!vm.delete_store_snapshot("s3").unwrap());
+    assert_eq!(vm.store_snapshots().unwrap(), ["s2"]);
+
+    assert!(matches!(
+        vm.restore_store("s3"),
+        Err(VMError::SnapshotNotFound(name)) if name == "s3"
+    ));
+    assert!(matches!(
+        vm.snapshot_store("../tasks"),
+        Err(VMError::InvalidSnapshotName(_))
+    ));
+    // snapshot taken inside transaction is written on commit, dropped on rollback
+    let ops = "TX_BEGIN PUSH_STRING s4 S_SNAPSHOT TX_ROLLBACK \
+               TX_BEGIN PUSH_STRING s5 S_SNAPSHOT TX_COMMIT";
+    let mut vm = file_vm(path, ops);
+    vm.run().unwrap();
+    assert_eq!(vm.store_snapshots().unwrap(), ["s2", "s5"]);
+
+    let shared = SharedStorage::new(MemoryBackend::new()).unwrap();
+    let mut vm = VM::builder()
+        .shared(shared)
+        .build(VM::dot2bin("S_LEN").unwrap())
+        .unwrap();
+    assert!(matches!(
+        vm.snapshot_store("s1"),
+        Err(VMError::SnapshotsNotSupported)
+    ));
+}
+
+// two_tasks_backend with global 0 = 7
+fn fork_backend() -> MemoryBackend {
+    let backend = two_tasks_backend();
+    memory_vm(&backend, "PUSH_U32 7 PUSH_U32 0 G_SET S_SAVE")
+        .run()
+        .unwrap();
+    backend
+}
+
+#[test]
+fn test_fork_diff_and_commit() {
+    let backend = fork_backend();
+    let mut vm = memory_vm(&backend, "S_LEN");
+    vm.run().unwrap();
+    let ops = "PUSH_STATE 2 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD \
+               PUSH_STRING A2 PUSH_U32 0 PUSH_TASK_FIELD 0 T_SET_FIELD \
+               PUSH_U32 1 T_DELETE \
+               PUSH_STRING C PUSH_MAX_STATES 2 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_U32 8 PUSH_U32 0 G_SET S_SAVE";
+    let mut fork = vm.fork_with(VM::dot2bin(ops).unwrap()).unwrap();
+    fork.run().unwrap();
+
+    // parent and its backend don't see changes of fork
+    assert_eq!(vm.print_task(0).unwrap().state.state, 0);
+    assert_eq!(vm.print_task(1).unwrap().title, "B");
+    assert_eq!(vm.global(0).unwrap(), Global::U32(7));
+    assert!(memory_vm(&backend, "S_LEN").print_task(2).is_err());
+
+    let diff = fork.diff().unwrap();
+    assert_eq!(diff.created, [fork.print_task(2).unwrap()]);
+    assert_eq!(diff.deleted, [vm.print_task(1).unwrap()]);
+    assert_eq!(diff.changed.len(), 1);
+    assert_eq!(diff.changed[0].id, 0);
+    assert_eq!(
+        diff.changed[0].fields,
+        [
+            FieldChange::Title {
+                from: "A".to_string(),
+                to: "A2".to_string()
+            },
+            FieldChange::State {
+                from: TaskState { len: 3, state: 0 },
+                to: TaskState { len: 3, state: 2 }
+            },
+        ]
+    );
+    assert_eq!(
+        diff.globals,
+        [GlobalChange {
+            index: 0,
+            from: Global::U32(7),
+            to: Global::U32(8)
+        }]
+    );
+    assert!(matches!(vm.diff(), Err(VMError::NotForked)));
+
+    fork.commit(&mut vm).unwrap();
+    assert_eq!(vm.print_task(0).unwrap().title, "A2");
+    assert!(vm.print_task(1).is_err());
+    assert_eq!(vm.print_task(2).unwrap().title, "C");
+    assert_eq!(vm.global(0).unwrap(), Global::U32(8));
+    // committed changes are saved by parent
+    vm.save().unwrap();
+    let vm = memory_vm(&backend, "S_LEN");
+    assert_eq!(vm.print_task(0).unwrap().state.state, 2);
+    assert_eq!(vm.print_task(2).unwrap().title, "C");
+}
+
+#[test]
+fn test_fork_commit_conflict() {
+    let backend = fork_backend();
+    let set_state = |state: u8, id: u32| {
+        VM::dot2bin(&format!(
+            "PUSH_STATE {state} PUSH_U32 {id} PUSH_TASK_FIELD 1 T_SET_FIELD"
+        ))
+        .unwrap()
+    };
+    let mut vm = memory_vm(&backend, "S_LEN");
+
+    // parent changed task changed by fork
+    let mut fork = vm.fork_with(set_state(1, 0)).unwrap();
+    fork.run().unwrap();
+    let mut parent_run = vm.fork_with(set_state(2, 0)).unwrap();
+    parent_run.run().unwrap();
+    parent_run.commit(&mut vm).unwrap();
+    assert!(matches!(
+        fork.commit(&mut vm),
+        Err(VMError::StorageConflict)
+    ));
+    assert_eq!(vm.print_task(0).unwrap().state.state, 2);
+    // fork survives failed commit
+    assert_eq!(fork.diff().unwrap().changed.len(), 1);
+
+    // changes of other tasks don't conflict
+    let mut fork = vm.fork_with(set_state(1, 0)).unwrap();
+    fork.run().unwrap();
+    let mut parent_run = vm.fork_with(set_state(2, 1)).unwrap();
+    parent_run.run().unwrap();
+    parent_run.commit(&mut vm).unwrap();
+    fork.commit(&mut vm).unwrap();
+    assert_eq!(vm.print_task(0).unwrap().state.state, 1);
+    assert_eq!(vm.print_task(1).unwrap().state.state, 2);
+}
+
+#[test]
+fn test_fork_discard() {
+    let backend = fork_backend();
+    let mut vm = memory_vm(&backend, "PUSH_U32 1 T_DELETE S_SAVE");
+    let mut fork = vm.fork().unwrap();
+    // fork runs program of parent
+    fork.run().unwrap();
+    assert_eq!(fork.diff().unwrap().deleted.len(), 1);
+    fork.discard();
+    assert_eq!(vm.print_task(1).unwrap().title, "B");
+    assert!(memory_vm(&backend, "S_LEN").print_task(1).is_ok());
+    // fork changing nothing has empty diff
+    assert!(vm.fork().unwrap().diff().unwrap().is_empty());
+    vm.run().unwrap();
+    assert!(vm.print_task(1).is_err());
+}
+
+#[test]
+fn test_store_diff_between() {
+    let staging = fork_backend();
+    let production = fork_backend();
+    let ops = "PUSH_CALLDATA [ PUSH_U32 1 ] PUSH_U32 0 PUSH_TASK_FIELD 2 T_SET_FIELD \
+               PUSH_U32 1 T_DELETE PUSH_U32 4 PUSH_U32 0 G_SET PUSH_U32 3 PUSH_U32 1 G_SET S_SAVE";
+    memory_vm(&staging, ops).run().unwrap();
+
+    let from = VM::load_store(&mut production.clone()).unwrap();
+    let to = VM::load_store(&mut staging.clone()).unwrap();
+    assert!(StorageDiff::between(&from, &from).is_empty());
+    let diff = StorageDiff::between(&from, &to);
+    assert!(diff.created.is_empty());
+    assert_eq!(diff.deleted, [from.tasks[1].clone()]);
+    assert_eq!(diff.changed[0].id, 0);
+    assert_eq!(
+        diff.changed[0].fields,
+        [FieldChange::Instructions {
+            from: vec![],
+            to: VM::dot2bin("PUSH_U32 1").unwrap()
+        }]
+    );
+    assert_eq!(diff.globals.len(), 2);
+    assert_eq!(diff.globals[1].from, Global::Null);
+    // instructions are disassembled
+    assert_eq!(
+        diff.to_string(),
+        "- task 1: \"B\"\n\
+         ~ task 0\n    instructions: [ ] -> [ PUSH_U32 1 ]\n\
+         ~ global 0: U32(7) -> U32(4)\n\
+         ~ global 1: Null -> U32(3)\n"
+    );
+}
+
+#[test]
+fn test_store_three_way_merge() {
+    let base = fork_backend();
+    let ours = fork_backend();
+    let theirs = fork_backend();
+    let ops = "PUSH_STATE 1 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD \
+               PUSH_STRING B1 PUSH_U32 1 PUSH_TASK_FIELD 0 T_SET_FIELD \
+               PUSH_STRING C PUSH_MAX_STATES 2 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_U32 8 PUSH_U32 0 G_SET S_SAVE";
+    memory_vm(&ours, ops).run().unwrap();
+    let ops = "PUSH_STATE 2 PUSH_U32 0 PUSH_TASK_FIELD 1 T_SET_FIELD \
+               PUSH_STATE 2 PUSH_U32 1 PUSH_TASK_FIELD 1 T_SET_FIELD \
+               PUSH_STRING D PUSH_MAX_STATES 2 PUSH_CALLDATA [ ] T_CREATE \
+               PUSH_U32 9 PUSH_U32 1 G_SET S_SAVE";
+    memory_vm(&theirs, ops).run().unwrap();
+
+    let [base, ours, theirs] = [base, ours, theirs].map(|mut b| VM::load_store(&mut b).unwrap());
+    let merge = StorageMerge::three_way(&base, &ours, &theirs);
+    assert_eq!(
+        merge.conflicts,
+        [
+            MergeConflict::Field {
+                id: 0,
+                field: TaskField::State
+            },
+            MergeConflict::Created { id: 2 },
+        ]
+    );
+    assert!(!merge.is_clean());
+    let merged = &merge.merged;
+    // conflicting state and created task keep ours
+    assert_eq!(merged.tasks[0].state.state, 1);
+    assert_eq!(merged.tasks[2].title, "C");
+    // changes of different fields of the same task are merged
+    assert_eq!(merged.tasks[1].title, "B1");
+    assert_eq!(merged.tasks[1].state.state, 2);
+    assert_eq!(merged.globals, [Global::U32(8), Global::U32(9)]);
+    assert_eq!(merged.next_id, 3);
+
+    // one side deletes task changed by the other
+    let mut deleted = base.clone();
+    deleted.tasks.remove(1);
+    let merge = StorageMerge::three_way(&base, &deleted, &theirs);
+    assert_eq!(merge.conflicts, [MergeConflict::Deleted { id: 1 }]);
+    assert_eq!(merge.merged.tasks[1], theirs.tasks[1]);
+    // deletion of unchanged task is merged
+    let merge = StorageMerge::three_way(&base, &base, &deleted);
+    assert!(merge.is_clean());
+    assert_eq!(merge.merged, deleted);
+
+    // merged store is saved
+    let mut backend = MemoryBackend::new();
+    VM::save_store(&mut backend, &merge.merged).unwrap();
+    assert_eq!(VM::load_store(&mut backend).unwrap(), merge.merged);
+}
+
+#[test]
+fn test_save_store_validates_data() {
+    let task = |id: u32| Task {
+        id,
+        title: format!("T{id}"),
+        state: TaskState { len: 2, state: 0 },
+        instructions: Vec::new(),
+    };
+    let mut backend = MemoryBackend::new();
+    let data = StorageData {
+        tasks: vec![task(4), task(1)],
+        next_id: 2,
+        globals: Vec::new(),
+    };
+    VM::save_store(&mut backend, &data).unwrap();
+    let saved = VM::load_store(&mut backend).unwrap();
+    assert_eq!(
+        saved.tasks.iter().map(|t| t.id).collect::<Vec<_>>(),
+        vec![1, 4]
+    );
+    assert_eq!(saved.next_id, 5);
+
+    for tasks in [vec![task(3), task(1), task(3)], vec![task(u32::MAX)]] {
+        let invalid = StorageData {
+            tasks,
+            next_id: 0,
+            globals: Vec::new(),
+        };
+        assert!(matches!(
+            VM::save_store(&mut backend, &invalid),
+            Err(VMError::InvalidTaskId(_))
+        ));
+    }
+    // nothing was written
+    assert_eq!(VM::load_store(&mut backend).unwrap(), saved);
+}
+
+#[test]
+fn test_save_store_detects_stale_write() {
+    let dir = TestDir::new("save_store_stale");
+    let path = &dir.path("tasks.bin");
+    let ops = "PUSH_STRING A PUSH_MAX_STATES 2 PUSH_CALLDATA [ ] T_CREATE S_SAVE";
+    file_vm(path, ops).run().unwrap();
+    let mut backend = FileBackend::new(path);
+    let mut data = VM::load_store(&mut backend).unwrap();
+    // store is written by other writer after it was loaded
+    file_vm(path, ops).run().unwrap();
+    data.tasks.clear();
+    assert!(matches!(
+        VM::save_store(&mut backend, &data),
+        Err(VMError::StorageConflict)
+    ));
+    assert_eq!(
+        VM::load_store(&mut FileBackend::new(path))
+            .unwrap()
+            .tasks
+            .len(),
+        2
+    );
+}